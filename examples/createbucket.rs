@@ -2,10 +2,7 @@
  * Copyright 2019 Joyent, Inc.
  */
 
-#[macro_use]
-extern crate serde_json;
-
-use moray::buckets;
+use moray::buckets::{self, BucketConfig, IndexDef, IndexType};
 use moray::client::MorayClient;
 use std::io::Error;
 use std::sync::Mutex;
@@ -24,22 +21,17 @@ fn main() -> Result<(), Error> {
     );
 
     let mut mclient = MorayClient::from_parts(ip_arr, port, log, None)?;
-    let bucket_config = json!({
-        "index": {
-            "aNumber": {
-                "type": "number"
-            }
-        }
-    });
+    let bucket_config = BucketConfig::new()
+        .with_index("aNumber", IndexDef::new(IndexType::Number));
 
-    match mclient.create_bucket("rust_test_bucket", bucket_config, opts) {
+    match mclient.create_bucket("rust_test_bucket", &bucket_config, opts) {
         Ok(()) => {
             println!("Bucket Created Successfully");
             Ok(())
         }
         Err(e) => {
             eprintln!("Error Creating Bucket");
-            Err(e)
+            Err(e.into())
         }
     }
 }
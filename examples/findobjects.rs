@@ -4,6 +4,7 @@
 
 use libmanta::moray as manta;
 use moray::client::MorayClient;
+use moray::filter::Filter;
 use moray::objects;
 
 use slog::{o, Drain, Logger};
@@ -27,7 +28,8 @@ fn main() -> Result<(), Error> {
     let mut mclient = MorayClient::from_parts(ip_arr, port, log, None)?;
 
     opts.set_limit(10);
-    mclient.find_objects("manta", "(type=object)", &opts, |o| {
+    let filter = Filter::eq("type", "object");
+    mclient.find_objects("manta", &filter, &opts, |o| {
         if o.bucket != "manta" {
             return Err(Error::new(
                 ErrorKind::Other,
@@ -48,30 +50,23 @@ fn main() -> Result<(), Error> {
 
     let mut opts = objects::MethodOptions::default();
 
-    mclient.get_object("manta", key.as_str(), &opts, |o| {
-        if o.bucket != "manta" {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Unknown bucket type {}", &o.bucket),
-            ));
-        }
-        let manta_obj: manta::ObjectType =
-            serde_json::from_value(o.value.clone()).unwrap();
-        match manta_obj {
-            manta::ObjectType::Object(mobj) => {
-                println!("Found checksum:     {}", &mobj.content_md5);
-                println!("Expected checksum:  {}", &checksum);
-                assert_eq!(mobj.content_md5, checksum);
-                ()
-            }
-            _ => (),
+    let o = mclient.get_object_as::<manta::ObjectType>(
+        "manta",
+        key.as_str(),
+        &opts,
+    )?;
+    match o.value {
+        manta::ObjectType::Object(mobj) => {
+            println!("Found checksum:     {}", &mobj.content_md5);
+            println!("Expected checksum:  {}", &checksum);
+            assert_eq!(mobj.content_md5, checksum);
         }
-        Ok(())
-    })?;
+        _ => (),
+    }
 
     let mut count = 0;
-    let filter = format!("(objectId={})", oid);
-    mclient.find_objects("manta", filter.as_str(), &opts, |o| {
+    let filter = Filter::eq("objectId", oid.as_str());
+    mclient.find_objects("manta", &filter, &opts, |o| {
         count += 1;
         assert_eq!(count, 1, "should only be one result");
         if o.bucket != "manta" {
@@ -96,7 +91,8 @@ fn main() -> Result<(), Error> {
     })?;
 
     opts.set_limit(10);
-    mclient.find_objects("manta", "(type=directory)", &opts, |o| {
+    let filter = Filter::eq("type", "directory");
+    mclient.find_objects("manta", &filter, &opts, |o| {
         assert_eq!(count, 1, "should only be one result");
         if o.bucket != "manta" {
             return Err(Error::new(
@@ -4,6 +4,7 @@
 
 use moray::buckets;
 use moray::client::MorayClient;
+use moray::error::MorayError;
 
 use slog::{o, Drain, Logger};
 use std::io::{Error, ErrorKind};
@@ -16,7 +17,7 @@ use trust_dns_resolver::Resolver;
 fn client_fromstr(
     addr: &str,
     opts: buckets::MethodOptions,
-) -> Result<(), Error> {
+) -> Result<(), MorayError> {
     let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
     let log = Logger::root(
         Mutex::new(slog_term::FullFormat::new(plain).build()).fuse(),
@@ -35,7 +36,7 @@ fn client_sockaddr(
     sockaddr: SocketAddr,
     opts: buckets::MethodOptions,
     log: Logger,
-) -> Result<(), Error> {
+) -> Result<(), MorayError> {
     let mut mclient = MorayClient::new(sockaddr, log, None).unwrap();
     mclient.list_buckets(opts, |b| {
         dbg!(&b);
@@ -48,7 +49,7 @@ fn client_fromparts(
     port: u16,
     opts: buckets::MethodOptions,
     log: Logger,
-) -> Result<(), Error> {
+) -> Result<(), MorayError> {
     let mut mclient = MorayClient::from_parts(ip, port, log, None).unwrap();
     mclient.list_buckets(opts, |b| {
         dbg!(&b);
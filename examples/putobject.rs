@@ -7,6 +7,7 @@ extern crate serde_json;
 
 use moray::buckets;
 use moray::client::MorayClient;
+use moray::error::MorayError;
 use moray::objects::{self, Etag};
 use slog::{o, Drain, Logger};
 use std::io::{Error, ErrorKind};
@@ -103,13 +104,14 @@ fn main() -> Result<(), Error> {
                 "replacing object with 'Nulled' etag should fail",
             ));
         }
-        Err(e) => {
+        Err(MorayError::EtagConflict(e)) => {
             println!(
                 "Attempt to replace exiting object with 'Nulled' etag failed \
-                 as expected:\n {}\n",
-                e
+                 as expected:\n {}: {}\n",
+                e.name, e.message
             );
         }
+        Err(e) => return Err(e.into()),
     }
 
     /* Object doesn't exist, should pass. */
@@ -137,7 +139,25 @@ fn main() -> Result<(), Error> {
             );
         });
 
-    // TODO: Delete 'viva_la_pi' object so that this test can be run twice
+    /*
+     * Delete 'viva_la_pi' so that this example can be run again.  A specified
+     * etag that does not match the existing object's etag should fail.
+     */
+    println!("\n===delete with wrong etag (should fail)===");
+    opts.etag = Etag::Specified(String::from("not-the-etag"));
+    if mclient
+        .delete_object("rust_test_bucket", "viva_la_pi", &opts)
+        .is_ok()
+    {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "deleting object with the wrong etag should fail",
+        ));
+    }
+
+    println!("\n===delete with undefined etag===");
+    opts.etag = Etag::Undefined;
+    mclient.delete_object("rust_test_bucket", "viva_la_pi", &opts)?;
 
     Ok(())
 }
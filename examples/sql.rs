@@ -2,6 +2,7 @@
  * Copyright 2019 Joyent, Inc.
  */
 use moray::client::MorayClient;
+use moray::error::MorayError;
 use serde_json::{Map, Value};
 use slog::{o, Drain, Logger};
 use std::io::Error;
@@ -16,7 +17,7 @@ fn query_client_string_opts(
     ip: [u8; 4],
     port: u16,
     log: Logger,
-) -> Result<(), Error> {
+) -> Result<(), MorayError> {
     let mut mclient = MorayClient::from_parts(ip, port, log, None)?;
 
     // The sql interface does not take 'limit' in opts
@@ -29,7 +30,7 @@ fn query_client_map_opts(
     ip: [u8; 4],
     port: u16,
     log: Logger,
-) -> Result<(), Error> {
+) -> Result<(), MorayError> {
     let mut mclient = MorayClient::from_parts(ip, port, log, None)?;
 
     // The sql interface does not take 'limit' in opts
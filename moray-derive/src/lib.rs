@@ -0,0 +1,309 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! `#[derive(MorayBucket)]`, which implements `moray::MorayBucket` for a
+//! struct.  Use it through the `derive` feature of the moray crate.
+//!
+//! Struct attributes:
+//!
+//! * `#[moray(bucket = "name")]`: the bucket name.  Defaults to the struct's
+//!   name in lower case.
+//! * `#[moray(version = 2)]`: the bucket version.
+//!
+//! Field attributes:
+//!
+//! * `#[moray(index = "type")]`: index the field.  The type is one of moray's
+//!   index types, e.g. "string", "number" or "[string]".
+//! * `#[moray(unique)]`: make the index unique.
+//! * `#[moray(name = "field")]`: the name of the indexed field.  Defaults to
+//!   the field's `#[serde(rename = "...")]`, or else its name with the
+//!   struct's `#[serde(rename_all = "...")]` applied, as serde names it.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Lit, Meta,
+    NestedMeta,
+};
+
+#[proc_macro_derive(MorayBucket, attributes(moray))]
+pub fn derive_moray_bucket(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields =
+        match &input.data {
+            Data::Struct(data) => match &data.fields {
+                Fields::Named(fields) => &fields.named,
+                _ => return Err(Error::new_spanned(
+                    input,
+                    "MorayBucket can only be derived for structs with named \
+                     fields",
+                )),
+            },
+            _ => {
+                return Err(Error::new_spanned(
+                    input,
+                    "MorayBucket can only be derived for structs",
+                ))
+            }
+        };
+
+    let mut bucket = None;
+    let mut version = None;
+
+    for meta in moray_meta(&input.attrs)? {
+        match &meta {
+            NestedMeta::Meta(Meta::NameValue(nv))
+                if nv.path.is_ident("bucket") =>
+            {
+                bucket = Some(lit_str(&nv.lit)?);
+            }
+            NestedMeta::Meta(Meta::NameValue(nv))
+                if nv.path.is_ident("version") =>
+            {
+                version = Some(match &nv.lit {
+                    Lit::Int(i) => i.base10_parse::<u32>()?,
+                    lit => {
+                        return Err(Error::new_spanned(
+                            lit,
+                            "expected an integer version",
+                        ))
+                    }
+                });
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    &meta,
+                    "unknown moray attribute, expected `bucket` or `version`",
+                ))
+            }
+        }
+    }
+
+    let bucket =
+        bucket.unwrap_or_else(|| input.ident.to_string().to_lowercase());
+
+    let rename_all = serde_name(&input.attrs, "rename_all");
+    if let Some(rule) = &rename_all {
+        if rename_field(rule, "").is_none() {
+            return Err(Error::new_spanned(
+                input,
+                format!("unknown serde rename_all rule \"{}\"", rule),
+            ));
+        }
+    }
+
+    let mut indexes = vec![];
+    for field in fields {
+        let mut index_type = None;
+        let mut unique = false;
+        let mut name = None;
+
+        for meta in moray_meta(&field.attrs)? {
+            match &meta {
+                NestedMeta::Meta(Meta::NameValue(nv))
+                    if nv.path.is_ident("index") =>
+                {
+                    index_type = Some(parse_index_type(&nv.lit)?);
+                }
+                NestedMeta::Meta(Meta::NameValue(nv))
+                    if nv.path.is_ident("name") =>
+                {
+                    name = Some(lit_str(&nv.lit)?);
+                }
+                NestedMeta::Meta(Meta::Path(path))
+                    if path.is_ident("unique") =>
+                {
+                    unique = true;
+                }
+                _ => {
+                    return Err(Error::new_spanned(
+                        &meta,
+                        "unknown moray attribute, expected `index`, `unique` \
+                         or `name`",
+                    ))
+                }
+            }
+        }
+
+        let index_type = match index_type {
+            Some(index_type) => index_type,
+            None if unique || name.is_some() => {
+                return Err(Error::new_spanned(
+                    field,
+                    "`unique` and `name` require `index = \"...\"`",
+                ))
+            }
+            None => continue,
+        };
+
+        let name = name
+            .or_else(|| serde_name(&field.attrs, "rename"))
+            .unwrap_or_else(|| {
+                let ident = field
+                    .ident
+                    .as_ref()
+                    .map(|ident| ident.unraw().to_string())
+                    .unwrap_or_default();
+                match &rename_all {
+                    Some(rule) => rename_field(rule, &ident).unwrap_or(ident),
+                    None => ident,
+                }
+            });
+
+        indexes.push(quote! {
+            .with_index(#name, ::moray::buckets::IndexDef {
+                index_type: #index_type,
+                unique: #unique,
+            })
+        });
+    }
+
+    let version = version.map(|v| quote!(.with_version(#v)));
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::moray::MorayBucket for #ident #ty_generics
+            #where_clause
+        {
+            const BUCKET_NAME: &'static str = #bucket;
+
+            fn bucket_config() -> ::moray::buckets::BucketConfig {
+                ::moray::buckets::BucketConfig::new()
+                    #(#indexes)*
+                    #version
+            }
+        }
+    })
+}
+
+// The contents of every #[moray(...)] attribute.
+fn moray_meta(attrs: &[Attribute]) -> Result<Vec<NestedMeta>, Error> {
+    let mut nested = vec![];
+
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("moray")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => nested.extend(list.nested),
+            meta => {
+                return Err(Error::new_spanned(meta, "expected #[moray(...)]"))
+            }
+        }
+    }
+
+    Ok(nested)
+}
+
+// The value of a #[serde(key = "...")] attribute, e.g. `rename`.  For the
+// #[serde(key(serialize = "..."))] form the serialize name is used, as that is
+// the one moray stores.
+fn serde_name(attrs: &[Attribute], key: &str) -> Option<String> {
+    let str_value = |nested: &NestedMeta, key: &str| match nested {
+        NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident(key) => {
+            match &nv.lit {
+                Lit::Str(s) => Some(s.value()),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("serde"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|nested| match &nested {
+            NestedMeta::Meta(Meta::List(list)) if list.path.is_ident(key) => {
+                list.nested.iter().find_map(|n| str_value(n, "serialize"))
+            }
+            _ => str_value(&nested, key),
+        })
+}
+
+// Rename a snake_case field as serde's `rename_all` rule does, or None if the
+// rule isn't one serde knows.
+fn rename_field(rule: &str, field: &str) -> Option<String> {
+    let pascal = || {
+        let mut pascal = String::new();
+        let mut capitalize = true;
+        for ch in field.chars() {
+            if ch == '_' {
+                capitalize = true;
+            } else if capitalize {
+                pascal.push(ch.to_ascii_uppercase());
+                capitalize = false;
+            } else {
+                pascal.push(ch);
+            }
+        }
+        pascal
+    };
+
+    let renamed = match rule {
+        "lowercase" => field.to_ascii_lowercase(),
+        "snake_case" => field.to_string(),
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" => pascal(),
+        "camelCase" => {
+            let pascal = pascal();
+            match pascal.chars().next() {
+                Some(first) => {
+                    first.to_ascii_lowercase().to_string()
+                        + &pascal[first.len_utf8()..]
+                }
+                None => pascal,
+            }
+        }
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.to_ascii_uppercase().replace('_', "-"),
+        _ => return None,
+    };
+
+    Some(renamed)
+}
+
+fn lit_str(lit: &Lit) -> Result<String, Error> {
+    match lit {
+        Lit::Str(s) => Ok(s.value()),
+        _ => Err(Error::new_spanned(lit, "expected a string")),
+    }
+}
+
+fn parse_index_type(lit: &Lit) -> Result<TokenStream2, Error> {
+    let name = lit_str(lit)?;
+    let variant = match name.as_str() {
+        "string" => quote!(String),
+        "number" => quote!(Number),
+        "boolean" => quote!(Boolean),
+        "ip" => quote!(Ip),
+        "subnet" => quote!(Subnet),
+        "[string]" => quote!(StringArray),
+        "[number]" => quote!(NumberArray),
+        "[boolean]" => quote!(BooleanArray),
+        "[ip]" => quote!(IpArray),
+        "[subnet]" => quote!(SubnetArray),
+        _ => {
+            return Err(Error::new_spanned(
+                lit,
+                format!("unknown moray index type \"{}\"", name),
+            ))
+        }
+    };
+
+    Ok(quote!(::moray::buckets::IndexType::#variant))
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use moray::buckets::{BucketConfig, IndexDef, IndexType};
+use moray::MorayBucket;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, MorayBucket)]
+#[moray(bucket = "manta", version = 2)]
+struct MantaObject {
+    #[moray(index = "string")]
+    owner: String,
+
+    #[serde(rename = "objectId")]
+    #[moray(index = "string", unique)]
+    object_id: String,
+
+    #[moray(index = "number", name = "contentLength")]
+    content_length: u64,
+
+    #[moray(index = "[string]")]
+    roles: Vec<String>,
+
+    #[serde(rename = "contentMD5")]
+    content_md5: String,
+}
+
+#[derive(Serialize, Deserialize, MorayBucket)]
+struct Plain {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, MorayBucket)]
+#[serde(rename_all = "camelCase")]
+struct Renamed {
+    #[moray(index = "number")]
+    content_length: u64,
+
+    #[serde(rename = "contentMD5")]
+    #[moray(index = "string")]
+    content_md5: String,
+}
+
+#[test]
+fn derive_bucket_config_test() {
+    assert_eq!(MantaObject::BUCKET_NAME, "manta");
+
+    let expected = BucketConfig::new()
+        .with_index("owner", IndexDef::new(IndexType::String))
+        .with_index("objectId", IndexDef::unique(IndexType::String))
+        .with_index("contentLength", IndexDef::new(IndexType::Number))
+        .with_index("roles", IndexDef::new(IndexType::StringArray))
+        .with_version(2);
+    assert_eq!(MantaObject::bucket_config(), expected);
+}
+
+#[test]
+fn derive_defaults_test() {
+    assert_eq!(Plain::BUCKET_NAME, "plain");
+    assert_eq!(Plain::bucket_config(), BucketConfig::new());
+}
+
+#[test]
+fn derive_rename_all_test() {
+    let expected = BucketConfig::new()
+        .with_index("contentLength", IndexDef::new(IndexType::Number))
+        .with_index("contentMD5", IndexDef::new(IndexType::String));
+    assert_eq!(Renamed::bucket_config(), expected);
+
+    // The indexes name the fields as serde stores them.
+    let renamed = Renamed {
+        content_length: 1,
+        content_md5: String::new(),
+    };
+    let value = serde_json::to_value(&renamed).unwrap();
+    assert!(value.get("contentLength").is_some());
+    assert!(value.get("contentMD5").is_some());
+}
@@ -0,0 +1,602 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! `MorayApi`, the moray operations as a trait, so that code which talks to
+//! moray can be handed a `MorayClient` in production and something else in
+//! tests:
+//!
+//! ```
+//! use moray::api::MorayApi;
+//! use moray::objects::MethodOptions;
+//!
+//! fn owner_of(api: &mut dyn MorayApi, key: &str) -> Option<String> {
+//!     let mut owner = None;
+//!     api.get_object("manta", key, &MethodOptions::default(), &mut |o| {
+//!         owner = o.value["owner"].as_str().map(String::from);
+//!         Ok(())
+//!     })
+//!     .ok()?;
+//!     owner
+//! }
+//!
+//! # #[cfg(feature = "testing")]
+//! # {
+//! let mut fake = moray::testing::FakeMoray::new();
+//! assert_eq!(owner_of(&mut fake, "/a/b"), None);
+//! # }
+//! ```
+//!
+//! The methods mirror those of `MorayClient`, except that handlers are passed
+//! as `&mut dyn FnMut` and filters as `&str`, which keeps the trait usable as
+//! a `Box<dyn MorayApi>`.
+
+use cueball::resolver::Resolver;
+use futures::stream::StreamExt;
+use serde_json::Value;
+use std::io::Error;
+
+use super::async_client::AsyncMorayClient;
+use super::buckets::{self, Bucket, BucketConfig};
+use super::client::MorayClient;
+use super::error::MorayError;
+use super::objects::{
+    BatchRequest, BatchResult, MethodOptions, MorayObject,
+    ReindexObjectsReturn, UpdateObjectsReturn,
+};
+
+pub trait MorayApi {
+    fn list_buckets(
+        &mut self,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError>;
+
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError>;
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError>;
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError>;
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError>;
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError>;
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError>;
+
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError>;
+}
+
+impl<R> MorayApi for MorayClient<R>
+where
+    R: Resolver,
+{
+    fn list_buckets(
+        &mut self,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::list_buckets(self, opts, bucket_handler)
+    }
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::get_bucket(self, name, opts, bucket_handler)
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        MorayClient::create_bucket(self, name, config, opts)
+    }
+
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        MorayClient::update_bucket(self, name, config, opts)
+    }
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        MorayClient::delete_bucket(self, name, opts)
+    }
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::get_object(self, bucket, key, opts, object_handler)
+    }
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::find_objects(self, bucket, filter, opts, object_handler)
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::put_object(self, bucket, key, value, opts, object_handler)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        MorayClient::delete_object(self, bucket, key, opts)
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError> {
+        MorayClient::update_objects(self, bucket, fields, filter, opts)
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError> {
+        MorayClient::delete_many(self, bucket, filter, opts)
+    }
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        MorayClient::reindex_objects(self, bucket, count, opts)
+    }
+
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::batch(self, requests, opts, batch_handler)
+    }
+
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        MorayClient::sql(self, stmt, vals, opts, query_handler)
+    }
+}
+
+/// Each call is run to completion on a runtime owned by the client, so these
+/// methods must not be called from within another tokio runtime.
+impl MorayApi for AsyncMorayClient {
+    fn list_buckets(
+        &mut self,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        for bucket in
+            self.block_on(AsyncMorayClient::list_buckets(self, &opts))??
+        {
+            bucket_handler(&bucket)?;
+        }
+        Ok(())
+    }
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let bucket =
+            self.block_on(AsyncMorayClient::get_bucket(self, name, &opts))??;
+        Ok(bucket_handler(&bucket)?)
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.block_on(AsyncMorayClient::create_bucket(
+            self, name, config, &opts,
+        ))?
+    }
+
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.block_on(AsyncMorayClient::update_bucket(
+            self, name, config, &opts,
+        ))?
+    }
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.block_on(AsyncMorayClient::delete_bucket(self, name, &opts))?
+    }
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let obj = self.block_on(AsyncMorayClient::get_object(
+            self, bucket, key, opts,
+        ))??;
+        Ok(object_handler(&obj)?)
+    }
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut found =
+            AsyncMorayClient::find_objects(self, bucket, filter, opts);
+
+        // Each object is handled as it arrives, as MorayClient does.
+        while let Some(obj) = self.block_on(found.next())? {
+            object_handler(&obj?)?;
+        }
+        Ok(())
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let etag = self.block_on(AsyncMorayClient::put_object(
+            self, bucket, key, value, opts,
+        ))??;
+        Ok(object_handler(&etag)?)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.block_on(AsyncMorayClient::delete_object(self, bucket, key, opts))?
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError> {
+        self.block_on(AsyncMorayClient::update_objects(
+            self, bucket, fields, filter, opts,
+        ))?
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError> {
+        self.block_on(AsyncMorayClient::delete_many(
+            self, bucket, filter, opts,
+        ))?
+    }
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        self.block_on(AsyncMorayClient::reindex_objects(
+            self, bucket, count, opts,
+        ))?
+    }
+
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let results =
+            self.block_on(AsyncMorayClient::batch(self, requests, opts))??;
+        Ok(batch_handler(results)?)
+    }
+
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        for d in
+            self.block_on(AsyncMorayClient::sql(self, stmt, vals, opts))??
+        {
+            query_handler(&d)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buckets::{IndexDef, IndexType};
+    use crate::testing::{FakeMoray, Fault, MockMoray};
+    use serde_json::json;
+    use slog::{o, Discard, Logger};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // The same calls should behave the same way whatever is behind the trait.
+    fn exercise(api: &mut dyn MorayApi) {
+        let config = BucketConfig::new()
+            .with_index("n", IndexDef::new(IndexType::Number));
+        api.create_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+        let mut names = vec![];
+        api.list_buckets(buckets::MethodOptions::default(), &mut |b| {
+            names.push(b.name().to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(names, vec!["b"]);
+
+        let opts = MethodOptions::default();
+        let mut etags = vec![];
+        for (key, n) in &[("k1", 1), ("k2", 2), ("k3", 3)] {
+            api.put_object("b", key, json!({ "n": n }), &opts, &mut |etag| {
+                etags.push(etag.to_string());
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(etags.len(), 3);
+
+        let mut keys = vec![];
+        api.find_objects("b", "(n>=2)", &opts, &mut |o| {
+            keys.push(o.key.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(keys, vec!["k2", "k3"]);
+
+        let updated = api
+            .update_objects("b", json!({ "n": 5 }), "(n<=1)", &opts)
+            .unwrap();
+        assert_eq!(updated.count, 1);
+
+        // The update changes what filters see, but not the stored value.
+        let mut keys = vec![];
+        api.find_objects("b", "(n=5)", &opts, &mut |o| {
+            keys.push(o.key.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(keys, vec!["k1"]);
+        let mut value = Value::Null;
+        api.get_object("b", "k1", &opts, &mut |o| {
+            value = o.value.clone();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(value, json!({ "n": 1 }));
+
+        assert_eq!(api.delete_many("b", "(n>=3)", &opts).unwrap(), 2);
+        api.delete_object("b", "k2", &opts).unwrap();
+        match api.get_object("b", "k2", &opts, &mut |_| Ok(())) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        api.delete_bucket("b", buckets::MethodOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn fake_moray_test() {
+        let mut api: Box<dyn MorayApi> = Box::new(FakeMoray::new());
+        exercise(api.as_mut());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn local_moray_test() {
+        let moray = crate::local::LocalMoray::open_in_memory().unwrap();
+        let mut api: Box<dyn MorayApi> = Box::new(moray);
+        exercise(api.as_mut());
+    }
+
+    #[test]
+    fn client_test() {
+        let mock = MockMoray::start().unwrap();
+        let log = Logger::root(Discard, o!());
+        let client = MorayClient::new(mock.address(), log, None).unwrap();
+        let mut api: Box<dyn MorayApi> = Box::new(client);
+        exercise(api.as_mut());
+    }
+
+    #[test]
+    fn async_client_test() {
+        let mock = MockMoray::start().unwrap();
+        let log = Logger::root(Discard, o!());
+        let mut api: Box<dyn MorayApi> =
+            Box::new(AsyncMorayClient::new(mock.address(), log));
+        exercise(api.as_mut());
+    }
+
+    #[test]
+    fn async_client_concurrent_test() {
+        let mock = MockMoray::start().unwrap();
+        let log = Logger::root(Discard, o!());
+        let mut client = AsyncMorayClient::new(mock.address(), log);
+        let mut other = client.clone();
+        MorayApi::create_bucket(
+            &mut client,
+            "b",
+            &BucketConfig::new(),
+            buckets::MethodOptions::default(),
+        )
+        .unwrap();
+
+        // A slow call on one thread doesn't hold up calls on another.
+        let delay = Duration::from_secs(2);
+        mock.inject_fault("getObject", Fault::Delay(delay));
+        let slow = thread::spawn(move || {
+            let opts = MethodOptions::default();
+            MorayApi::get_object(&mut other, "b", "k1", &opts, &mut |_| Ok(()))
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        MorayApi::list_buckets(
+            &mut client,
+            buckets::MethodOptions::default(),
+            &mut |_| Ok(()),
+        )
+        .unwrap();
+        assert!(start.elapsed() < delay);
+
+        match slow.join().unwrap() {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
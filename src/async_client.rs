@@ -0,0 +1,695 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use futures::executor;
+use futures::stream::{self, BoxStream, StreamExt};
+use rust_fast::protocol::{FastMessage, FastMessageStatus};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use slog::{debug, Logger};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::Semaphore;
+
+use super::buckets::{self, Bucket};
+use super::error::{MorayError, ServerError};
+use super::fast;
+use super::meta;
+use super::objects::{
+    self, BatchRequest, BatchResult, MethodOptions, MorayObject,
+    ReindexObjectsReturn, UpdateObjectsReturn,
+};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// The number of connections that may be open at once, and the number of
+// those that are kept open between requests.
+const MAX_CONNECTIONS: usize = 16;
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+// A claim on one of the client's MAX_CONNECTIONS, given back when dropped.
+struct Permit(Arc<Semaphore>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.add_permits(1);
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    // Held while the connection is in use, but not while it is idle.
+    permit: Option<Permit>,
+}
+
+impl Connection {
+    async fn send(
+        &mut self,
+        method: String,
+        args: Value,
+    ) -> Result<u32, MorayError> {
+        let msg_id = fast::next_msg_id();
+        let request = fast::encode_request(msg_id, method, args)?;
+        self.stream.write_all(&request).await?;
+        Ok(msg_id)
+    }
+
+    // Read the next message of the response to `msg_id`.  As with
+    // `fast::read_message`, bytes are read straight onto the end of `buf`.
+    async fn receive(
+        &mut self,
+        msg_id: u32,
+    ) -> Result<FastMessage, MorayError> {
+        loop {
+            if let Some(msg) = fast::parse_message(&mut self.buf)? {
+                if msg.id != msg_id {
+                    return Err(MorayError::Protocol(format!(
+                        "Expected response to message {}, got message {}",
+                        msg_id, msg.id
+                    )));
+                }
+                return Ok(msg);
+            }
+
+            let len = self.buf.len();
+            self.buf.resize(len + READ_CHUNK_SIZE, 0);
+            let read = self.stream.read(&mut self.buf[len..]).await;
+            let byte_count = match read {
+                Ok(byte_count) => byte_count,
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(MorayError::Io(e));
+                }
+            };
+            self.buf.truncate(len + byte_count);
+
+            if byte_count == 0 {
+                return Err(MorayError::Io(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed before the end of the response",
+                )));
+            }
+        }
+    }
+}
+
+struct Inner {
+    backends: Vec<SocketAddr>,
+    next_backend: AtomicUsize,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+    // Created on first use by block_on.
+    runtime: Mutex<Option<Runtime>>,
+    log: Logger,
+}
+
+// Where a find_objects stream is in its response.
+enum FindState {
+    Start(String, Value),
+    Streaming(Connection, u32, VecDeque<MorayObject>),
+    Done,
+}
+
+/// A non-blocking moray client for use with tokio.
+///
+/// The requests it sends are the same as those of `MorayClient`, but each
+/// method is an `async fn` and `find_objects` returns a `Stream` of objects
+/// rather than calling a handler.  The client is cheap to clone, and clones
+/// share their connections, of which at most 16 are open at once.  Requests
+/// beyond that wait for a connection to be released.
+#[derive(Clone)]
+pub struct AsyncMorayClient {
+    inner: Arc<Inner>,
+}
+
+impl AsyncMorayClient {
+    pub fn new(address: SocketAddr, log: Logger) -> Self {
+        Self::from_backends(vec![address], log)
+    }
+
+    fn from_backends(backends: Vec<SocketAddr>, log: Logger) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                backends,
+                next_backend: AtomicUsize::new(0),
+                idle: Mutex::new(Vec::new()),
+                permits: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+                runtime: Mutex::new(None),
+                log,
+            }),
+        }
+    }
+
+    /// Create a client that spreads its connections across several moray
+    /// instances.  If a connection can't be made to one of them the next one is
+    /// tried.
+    pub fn with_backends(
+        addresses: Vec<SocketAddr>,
+        log: Logger,
+    ) -> Result<Self, MorayError> {
+        if addresses.is_empty() {
+            return Err(MorayError::InvalidInput(String::from(
+                "At least one backend address is required",
+            )));
+        }
+
+        Ok(Self::from_backends(addresses, log))
+    }
+
+    /// Run `future` to completion on the calling thread, within the context of
+    /// a runtime owned by the client, which is how the client implements the
+    /// blocking `api::MorayApi`.  The runtime's own thread drives the I/O, so
+    /// connections opened here can later be used from other runtimes, and
+    /// several threads can block on the same client at once.  This must not
+    /// be called from within a runtime.
+    pub(crate) fn block_on<F: Future>(
+        &self,
+        future: F,
+    ) -> Result<F::Output, MorayError> {
+        let handle = {
+            let mut runtime =
+                self.inner.runtime.lock().unwrap_or_else(|e| e.into_inner());
+
+            if runtime.is_none() {
+                *runtime = Some(
+                    runtime::Builder::new()
+                        .threaded_scheduler()
+                        .core_threads(1)
+                        .thread_name("moray-async-client")
+                        .enable_all()
+                        .build()?,
+                );
+            }
+
+            runtime.as_ref().expect("runtime").handle().clone()
+        };
+
+        Ok(handle.enter(|| executor::block_on(future)))
+    }
+
+    // Claim an idle connection, or open a new one.  Connections are only
+    // opened when none are idle, so waiting for a permit here caps the number
+    // of open connections at MAX_CONNECTIONS.
+    async fn connect(&self) -> Result<Connection, MorayError> {
+        self.inner.permits.acquire().await.forget();
+        let permit = Permit(self.inner.permits.clone());
+
+        let idle = self.inner.idle.lock().ok().and_then(|mut idle| idle.pop());
+        if let Some(mut conn) = idle {
+            conn.permit = Some(permit);
+            return Ok(conn);
+        }
+
+        let backends = &self.inner.backends;
+        let start = self.inner.next_backend.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+
+        for i in 0..backends.len() {
+            let address = backends[(start + i) % backends.len()];
+            match TcpStream::connect(address).await {
+                Ok(stream) => {
+                    return Ok(Connection {
+                        stream,
+                        buf: Vec::new(),
+                        permit: Some(permit),
+                    });
+                }
+                Err(e) => {
+                    debug!(self.inner.log, "failed to connect";
+                        "address" => address.to_string(),
+                        "error" => e.to_string());
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(MorayError::Io(last_error.unwrap_or_else(|| {
+            Error::new(ErrorKind::NotConnected, "No backends")
+        })))
+    }
+
+    // Return a connection whose response has been read in full to the idle
+    // list, so it can be used for the next request.  Its permit is given back
+    // only once it is on the list, for the next claim to find it there.
+    fn release(&self, mut conn: Connection) {
+        let _permit = conn.permit.take();
+        if !conn.buf.is_empty() {
+            return;
+        }
+
+        if let Ok(mut idle) = self.inner.idle.lock() {
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(conn);
+            }
+        }
+    }
+
+    /// Send an RPC and collect the data of each message in the response.
+    async fn call(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<Vec<Value>, MorayError> {
+        let mut conn = self.connect().await?;
+        let msg_id = conn.send(method, args).await?;
+        let mut data = vec![];
+
+        loop {
+            let msg = conn.receive(msg_id).await?;
+            match msg.status {
+                FastMessageStatus::Data => data.push(msg.data.d),
+                FastMessageStatus::End => {
+                    self.release(conn);
+                    return Ok(data);
+                }
+                FastMessageStatus::Error => {
+                    self.release(conn);
+                    return Err(MorayError::from_server_error(&msg.data.d));
+                }
+            }
+        }
+    }
+
+    /// Send an RPC whose response consists of one value, and return the data
+    /// of its message for the caller to decode.
+    async fn call_single_data(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<Value, MorayError> {
+        let mut data = self.call(method.clone(), args).await?;
+        data.pop().ok_or_else(|| {
+            MorayError::Protocol(format!("No response from {}", method))
+        })
+    }
+
+    /// Like `fast::call_single`.
+    async fn call_single<T>(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<T, MorayError>
+    where
+        T: DeserializeOwned,
+    {
+        let data = self.call_single_data(method.clone(), args).await?;
+        fast::decode_single(&method, &data)
+    }
+
+    async fn get_list_buckets(
+        &self,
+        name: &str,
+        opts: &buckets::MethodOptions,
+        method: buckets::Methods,
+    ) -> Result<Vec<Bucket>, MorayError> {
+        let (method, args) =
+            buckets::get_list_buckets_rpc(name, opts, &method)?;
+        let mut found = vec![];
+
+        for d in self.call(method, args).await? {
+            buckets::decode_bucket(&d, |b| {
+                found.push(b);
+                Ok(())
+            })?;
+        }
+
+        Ok(found)
+    }
+
+    pub async fn list_buckets(
+        &self,
+        opts: &buckets::MethodOptions,
+    ) -> Result<Vec<Bucket>, MorayError> {
+        self.get_list_buckets("", opts, buckets::Methods::List)
+            .await
+    }
+
+    pub async fn get_bucket(
+        &self,
+        name: &str,
+        opts: &buckets::MethodOptions,
+    ) -> Result<Bucket, MorayError> {
+        self.get_list_buckets(name, opts, buckets::Methods::Get)
+            .await?
+            .pop()
+            .ok_or_else(|| {
+                MorayError::BucketNotFound(ServerError::new(
+                    "BucketNotFoundError",
+                    format!("{} does not exist", name),
+                ))
+            })
+    }
+
+    pub async fn create_bucket(
+        &self,
+        name: &str,
+        config: &buckets::BucketConfig,
+        opts: &buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let (method, args) = buckets::create_bucket_rpc(name, config, opts)?;
+        self.call(method, args).await.map(|_| ())
+    }
+
+    /// See `buckets::update_bucket`.
+    pub async fn update_bucket(
+        &self,
+        name: &str,
+        config: &buckets::BucketConfig,
+        opts: &buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let current = self.get_bucket(name, opts).await?;
+        buckets::check_bucket_update(name, Some(&current), config)?;
+
+        let (method, args) = buckets::update_bucket_rpc(name, config, opts)?;
+        self.call(method, args).await.map(|_| ())
+    }
+
+    pub async fn delete_bucket(
+        &self,
+        name: &str,
+        opts: &buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let (method, args) = buckets::delete_bucket_rpc(name, opts);
+        self.call(method, args).await.map(|_| ())
+    }
+
+    pub async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<MorayObject, MorayError> {
+        let (method, args) = objects::get_find_objects_rpc(
+            bucket,
+            key,
+            opts,
+            &objects::Methods::Get,
+        );
+        let mut found = None;
+
+        for d in self.call(method, args).await? {
+            objects::decode_object(bucket, &d, |obj| {
+                found = Some(obj);
+                Ok(())
+            })?;
+        }
+
+        found.ok_or_else(|| {
+            MorayError::Protocol(String::from("No response from getObject"))
+        })
+    }
+
+    /// Find the objects in `bucket` that match `filter`.  Objects are decoded
+    /// as they arrive, and the stream ends after the last one.  If the stream
+    /// is dropped before then its connection is closed rather than reused.
+    pub fn find_objects<Q>(
+        &self,
+        bucket: &str,
+        filter: Q,
+        opts: &MethodOptions,
+    ) -> BoxStream<'static, Result<MorayObject, MorayError>>
+    where
+        Q: Into<String>,
+    {
+        let (method, args) = objects::get_find_objects_rpc(
+            bucket,
+            &filter.into(),
+            opts,
+            &objects::Methods::Find,
+        );
+        let client = self.clone();
+        let bucket = bucket.to_string();
+
+        stream::unfold(FindState::Start(method, args), move |state| {
+            let client = client.clone();
+            let bucket = bucket.clone();
+            async move { client.next_found(&bucket, state).await }
+        })
+        .boxed()
+    }
+
+    async fn next_found(
+        &self,
+        bucket: &str,
+        state: FindState,
+    ) -> Option<(Result<MorayObject, MorayError>, FindState)> {
+        let (mut conn, msg_id, mut pending) = match state {
+            FindState::Done => return None,
+            FindState::Streaming(conn, msg_id, pending) => {
+                (conn, msg_id, pending)
+            }
+            FindState::Start(method, args) => {
+                let mut conn = match self.connect().await {
+                    Ok(conn) => conn,
+                    Err(e) => return Some((Err(e), FindState::Done)),
+                };
+                match conn.send(method, args).await {
+                    Ok(msg_id) => (conn, msg_id, VecDeque::new()),
+                    Err(e) => return Some((Err(e), FindState::Done)),
+                }
+            }
+        };
+
+        loop {
+            if let Some(obj) = pending.pop_front() {
+                let state = FindState::Streaming(conn, msg_id, pending);
+                return Some((Ok(obj), state));
+            }
+
+            let msg = match conn.receive(msg_id).await {
+                Ok(msg) => msg,
+                Err(e) => return Some((Err(e), FindState::Done)),
+            };
+
+            match msg.status {
+                FastMessageStatus::Data => {
+                    let decoded =
+                        objects::decode_object(bucket, &msg.data.d, |obj| {
+                            pending.push_back(obj);
+                            Ok(())
+                        });
+                    if let Err(e) = decoded {
+                        return Some((Err(e), FindState::Done));
+                    }
+                }
+                FastMessageStatus::End => {
+                    self.release(conn);
+                    return None;
+                }
+                FastMessageStatus::Error => {
+                    self.release(conn);
+                    let e = MorayError::from_server_error(&msg.data.d);
+                    return Some((Err(e), FindState::Done));
+                }
+            }
+        }
+    }
+
+    /// Put an object, returning its new etag.
+    pub async fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+    ) -> Result<String, MorayError> {
+        let (method, args) = objects::put_object_rpc(bucket, key, value, opts);
+        objects::decode_put_object(&self.call_single_data(method, args).await?)
+    }
+
+    pub async fn delete_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        let (method, args) = objects::delete_object_rpc(bucket, key, opts);
+        self.call(method, args).await.map(|_| ())
+    }
+
+    pub async fn update_objects<Q>(
+        &self,
+        bucket: &str,
+        fields: Value,
+        filter: Q,
+        opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError>
+    where
+        Q: Into<String>,
+    {
+        let (method, args) =
+            objects::update_objects_rpc(bucket, fields, &filter.into(), opts);
+        self.call_single(method, args).await
+    }
+
+    pub async fn delete_many<Q>(
+        &self,
+        bucket: &str,
+        filter: Q,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError>
+    where
+        Q: Into<String>,
+    {
+        let (method, args) =
+            objects::delete_many_rpc(bucket, &filter.into(), opts);
+        objects::decode_delete_many(&self.call_single_data(method, args).await?)
+    }
+
+    pub async fn reindex_objects(
+        &self,
+        bucket: &str,
+        count: u64,
+        opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        let (method, args) = objects::reindex_objects_rpc(bucket, count, opts);
+        self.call_single(method, args).await
+    }
+
+    /// See `objects::batch`.
+    pub async fn batch(
+        &self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+    ) -> Result<Vec<BatchResult>, MorayError> {
+        let (method, args) = objects::batch_rpc(requests, opts)?;
+        objects::decode_batch(
+            requests,
+            &self.call_single_data(method, args).await?,
+        )
+    }
+
+    /// See `meta::sql`.  The data of each response message is returned.
+    pub async fn sql<V>(
+        &self,
+        stmt: &str,
+        vals: Vec<&str>,
+        opts: V,
+    ) -> Result<Vec<Value>, MorayError>
+    where
+        V: Into<Value>,
+    {
+        let (method, args) = meta::sql_rpc(stmt, vals, opts)?;
+        self.call(method, args).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::test_server::{object, serve};
+    use futures::future::FutureExt;
+    use serde_json::json;
+    use slog::{o, Discard};
+
+    fn handler(
+        method: &str,
+        args: &Value,
+    ) -> Result<Vec<Value>, (&'static str, &'static str)> {
+        match method {
+            "findObjects" => {
+                Ok(vec![json!([object("k1")]), json!([object("k2")])])
+            }
+            "getObject" if args[1] == "missing" => {
+                Err(("ObjectNotFoundError", "missing does not exist"))
+            }
+            "getObject" => Ok(vec![json!([object("k1")])]),
+            "putObject" => Ok(vec![json!([{ "etag": "EFGH" }])]),
+            "delObject" => Ok(vec![]),
+            "deleteMany" => Ok(vec![json!([{ "count": 3 }])]),
+            _ => Err(("NotImplementedError", "not implemented")),
+        }
+    }
+
+    fn client(addr: SocketAddr) -> AsyncMorayClient {
+        AsyncMorayClient::new(addr, Logger::root(Discard, o!()))
+    }
+
+    #[tokio::test]
+    async fn find_objects_stream_test() {
+        let client = client(serve(handler));
+        let opts = MethodOptions::default();
+
+        let keys: Vec<String> = client
+            .find_objects("b", "(key=*)", &opts)
+            .map(|obj| obj.unwrap().key)
+            .collect()
+            .await;
+        assert_eq!(keys, vec!["k1", "k2"]);
+
+        // Dropping a stream part way through is fine, as is reusing the
+        // client afterwards.
+        let mut objects = client.find_objects("b", "(key=*)", &opts);
+        assert!(objects.next().await.unwrap().is_ok());
+        drop(objects);
+
+        let count = client.find_objects("b", "(key=*)", &opts).count().await;
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn max_connections_test() {
+        let client = client(serve(handler));
+        let opts = MethodOptions::default();
+
+        // Each stream part way through its response holds a connection.
+        let mut streams = vec![];
+        for _ in 0..MAX_CONNECTIONS {
+            let mut objects = client.find_objects("b", "(key=*)", &opts);
+            assert!(objects.next().await.unwrap().is_ok());
+            streams.push(objects);
+        }
+
+        // So the next one waits until one of them is dropped.
+        let mut objects = client.find_objects("b", "(key=*)", &opts);
+        assert!(objects.next().now_or_never().is_none());
+        streams.pop();
+        assert!(objects.next().await.unwrap().is_ok());
+
+        drop(objects);
+        drop(streams);
+        let permits = client.inner.permits.available_permits();
+        assert_eq!(permits, MAX_CONNECTIONS);
+    }
+
+    #[tokio::test]
+    async fn object_methods_test() {
+        let client = client(serve(handler));
+        let opts = MethodOptions::default();
+
+        let obj = client.get_object("b", "k1", &opts).await.unwrap();
+        assert_eq!(obj.key, "k1");
+
+        match client.get_object("b", "missing", &opts).await {
+            Err(MorayError::ObjectNotFound(e)) => {
+                assert_eq!(e.message, "missing does not exist")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let etag = client
+            .put_object("b", "k1", json!({}), &opts)
+            .await
+            .unwrap();
+        assert_eq!(etag, "EFGH");
+
+        client.delete_object("b", "k1", &opts).await.unwrap();
+        assert_eq!(client.delete_many("b", "(k=*)", &opts).await.unwrap(), 3);
+
+        match client.reindex_objects("b", 10, &opts).await {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "NotImplementedError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
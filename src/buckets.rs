@@ -2,31 +2,232 @@
  * Copyright 2019 Joyent, Inc.
  */
 
-use rust_fast::{client as fast_client, protocol::FastMessageId};
+use cueball::connection::Connection;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, json, Value};
-use std::io::{Error, ErrorKind};
+use std::collections::BTreeMap;
+use std::io::Error;
 use std::net::TcpStream;
+use std::ops::DerefMut;
 use uuid::Uuid;
 
+use super::error::{MorayError, ServerError};
+use super::fast;
+
+#[cfg(any(
+    test,
+    feature = "testing",
+    feature = "postgres",
+    feature = "sqlite"
+))]
+mod store;
+#[cfg(any(test, feature = "testing", feature = "sqlite"))]
+pub(crate) use store::now_iso;
+
 /*
  * === Buckets ===
  */
 
 // Options that are properties of the bucket itself.  Not the rpc method options for bucket
 // manipulation.
-#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
 pub struct BucketOptions {
     #[serde(default)]
     version: u32,
 
-    #[serde(alias = "guaranteeOrder", default)]
+    #[serde(rename = "guaranteeOrder", alias = "guarantee_order", default)]
     guarantee_order: bool,
 
-    #[serde(alias = "syncUpdates", default)]
+    #[serde(rename = "syncUpdates", alias = "sync_updates", default)]
     sync_updates: bool,
 }
 
+impl BucketOptions {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn guarantee_order(&self) -> bool {
+        self.guarantee_order
+    }
+
+    pub fn set_guarantee_order(&mut self, guarantee_order: bool) {
+        self.guarantee_order = guarantee_order;
+    }
+
+    pub fn sync_updates(&self) -> bool {
+        self.sync_updates
+    }
+
+    pub fn set_sync_updates(&mut self, sync_updates: bool) {
+        self.sync_updates = sync_updates;
+    }
+}
+
+/// The type of an indexed field.  The array types index each element of an
+/// array value, e.g. a `[string]` index on `roles` matches `(roles=admin)`
+/// against an object whose `roles` are `["admin", "user"]`.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[serde(from = "String", into = "String")]
+pub enum IndexType {
+    String,
+    Number,
+    Boolean,
+    Ip,
+    Subnet,
+    StringArray,
+    NumberArray,
+    BooleanArray,
+    IpArray,
+    SubnetArray,
+    /// A type this crate doesn't know, by the name moray gave it.  Buckets
+    /// using it can still be read and written back unchanged, and it is
+    /// indexed as a string.
+    Other(String),
+}
+
+impl IndexType {
+    /// The name of the type in a bucket's schema, e.g. "[string]".
+    pub fn name(&self) -> &str {
+        match self {
+            IndexType::String => "string",
+            IndexType::Number => "number",
+            IndexType::Boolean => "boolean",
+            IndexType::Ip => "ip",
+            IndexType::Subnet => "subnet",
+            IndexType::StringArray => "[string]",
+            IndexType::NumberArray => "[number]",
+            IndexType::BooleanArray => "[boolean]",
+            IndexType::IpArray => "[ip]",
+            IndexType::SubnetArray => "[subnet]",
+            IndexType::Other(name) => name,
+        }
+    }
+
+    pub fn is_array(&self) -> bool {
+        match self {
+            IndexType::StringArray
+            | IndexType::NumberArray
+            | IndexType::BooleanArray
+            | IndexType::IpArray
+            | IndexType::SubnetArray => true,
+            _ => false,
+        }
+    }
+
+    /// The type of each element of an array type, or the type itself.
+    pub fn element_type(&self) -> IndexType {
+        match self {
+            IndexType::StringArray => IndexType::String,
+            IndexType::NumberArray => IndexType::Number,
+            IndexType::BooleanArray => IndexType::Boolean,
+            IndexType::IpArray => IndexType::Ip,
+            IndexType::SubnetArray => IndexType::Subnet,
+            t => t.clone(),
+        }
+    }
+}
+
+impl From<String> for IndexType {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "string" => IndexType::String,
+            "number" => IndexType::Number,
+            "boolean" => IndexType::Boolean,
+            "ip" => IndexType::Ip,
+            "subnet" => IndexType::Subnet,
+            "[string]" => IndexType::StringArray,
+            "[number]" => IndexType::NumberArray,
+            "[boolean]" => IndexType::BooleanArray,
+            "[ip]" => IndexType::IpArray,
+            "[subnet]" => IndexType::SubnetArray,
+            _ => IndexType::Other(name),
+        }
+    }
+}
+
+impl From<IndexType> for String {
+    fn from(index_type: IndexType) -> Self {
+        index_type.name().to_string()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct IndexDef {
+    #[serde(rename = "type")]
+    pub index_type: IndexType,
+
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unique: bool,
+}
+
+impl IndexDef {
+    pub fn new(index_type: IndexType) -> Self {
+        Self {
+            index_type,
+            unique: false,
+        }
+    }
+
+    /// An index whose values must be unique across the bucket.
+    pub fn unique(index_type: IndexType) -> Self {
+        Self {
+            index_type,
+            unique: true,
+        }
+    }
+}
+
+/// The schema of a bucket, as passed to `create_bucket` and `update_bucket`.
+///
+/// ```
+/// use moray::buckets::{BucketConfig, IndexDef, IndexType};
+///
+/// let config = BucketConfig::new()
+///     .with_index("owner", IndexDef::new(IndexType::String))
+///     .with_index("objectId", IndexDef::unique(IndexType::String))
+///     .with_version(2);
+/// assert_eq!(config.options.version(), 2);
+/// ```
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct BucketConfig {
+    #[serde(default)]
+    pub index: BTreeMap<String, IndexDef>,
+
+    #[serde(default)]
+    pub options: BucketOptions,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post: Vec<String>,
+}
+
+impl BucketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_index<S: Into<String>>(
+        mut self,
+        field: S,
+        def: IndexDef,
+    ) -> Self {
+        self.index.insert(field.into(), def);
+        self
+    }
+
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.options.set_version(version);
+        self
+    }
+}
+
 // TODO: We should be able to skip this step with per field deserializers
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct BucketIntermediate {
@@ -40,7 +241,7 @@ struct BucketIntermediate {
 
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
 pub struct Bucket {
-    index: Value,
+    index: BTreeMap<String, IndexDef>,
     mtime: String,
     name: String,
     options: BucketOptions,
@@ -48,18 +249,59 @@ pub struct Bucket {
     pre: Vec<String>,
 }
 
+impl Bucket {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The bucket's index definitions, keyed by field name.
+    pub fn index(&self) -> &BTreeMap<String, IndexDef> {
+        &self.index
+    }
+
+    pub fn mtime(&self) -> &str {
+        &self.mtime
+    }
+
+    pub fn options(&self) -> &BucketOptions {
+        &self.options
+    }
+
+    pub fn pre(&self) -> &[String] {
+        &self.pre
+    }
+
+    pub fn post(&self) -> &[String] {
+        &self.post
+    }
+
+    /// The bucket's current schema, as a starting point for `update_bucket`.
+    pub fn config(&self) -> BucketConfig {
+        BucketConfig {
+            index: self.index.clone(),
+            options: self.options.clone(),
+            pre: self.pre.clone(),
+            post: self.post.clone(),
+        }
+    }
+}
+
 pub enum Methods {
     List,
     Get,
     Create,
+    Update,
+    Delete,
 }
 
 impl Methods {
-    fn method(&self) -> String {
+    pub(crate) fn method(&self) -> String {
         match *self {
             Methods::List => String::from("listBuckets"),
             Methods::Get => String::from("getBucket"),
             Methods::Create => String::from("createBucket"),
+            Methods::Update => String::from("updateBucket"),
+            Methods::Delete => String::from("delBucket"),
         }
     }
 }
@@ -67,95 +309,282 @@ impl Methods {
 #[derive(Clone, Debug, Serialize)]
 pub struct MethodOptions {
     pub req_id: String, // UUID as string,
+
+    // Only used by updateBucket.  When set, moray will not reindex existing
+    // objects in the background after new indexes are added.
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_reindex: bool,
 }
 
 impl Default for MethodOptions {
     fn default() -> Self {
         Self {
             req_id: Uuid::new_v4().to_string(),
+            no_reindex: false,
         }
     }
 }
 
-fn decode_bucket<F>(fm_data: &Value, mut cb: F) -> Result<(), Error>
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn decode_bucket_field<T>(
+    bucket: &str,
+    field: &str,
+    raw: &str,
+) -> Result<T, MorayError>
 where
-    F: FnMut(Bucket) -> Result<(), Error>,
+    T: serde::de::DeserializeOwned,
 {
-    let resp_data: Vec<Value> =
-        serde_json::from_value(fm_data.clone()).unwrap();
-
-    let result = Ok(());
-
-    resp_data.iter().fold(result, |_r, bucket_data| {
-        serde_json::from_value::<BucketIntermediate>(bucket_data.clone())
-            .map_err(|e| Error::new(ErrorKind::Other, e))
-            .and_then(|bi| {
-                cb(Bucket {
-                    name: bi.name,
-                    index: serde_json::from_str(bi.index.as_str()).unwrap(),
-                    mtime: bi.mtime,
-                    options: serde_json::from_str(bi.options.as_str()).unwrap(),
-                    post: serde_json::from_str(bi.post.as_str()).unwrap(),
-                    pre: serde_json::from_str(bi.pre.as_str()).unwrap(),
-                })
-            })
-    })
+    serde_json::from_str(raw)
+        .map_err(|e| MorayError::decode(Some(bucket), field, e))
+}
+
+pub(crate) fn decode_bucket<F>(
+    fm_data: &Value,
+    mut cb: F,
+) -> Result<(), MorayError>
+where
+    F: FnMut(Bucket) -> Result<(), MorayError>,
+{
+    let resp_data = fm_data.as_array().ok_or_else(|| {
+        MorayError::decode(None, "buckets", "expected an Array of buckets")
+    })?;
+
+    for bucket_data in resp_data.iter() {
+        let bi = BucketIntermediate::deserialize(bucket_data).map_err(|e| {
+            let name = bucket_data.get("name").and_then(Value::as_str);
+            MorayError::decode(name, "bucket", e)
+        })?;
+        let index = decode_bucket_field(&bi.name, "index", &bi.index)?;
+        let options = decode_bucket_field(&bi.name, "options", &bi.options)?;
+        let post = decode_bucket_field(&bi.name, "post", &bi.post)?;
+        let pre = decode_bucket_field(&bi.name, "pre", &bi.pre)?;
+
+        cb(Bucket {
+            index,
+            mtime: bi.mtime,
+            name: bi.name,
+            options,
+            post,
+            pre,
+        })?;
+    }
+
+    Ok(())
+}
+
+/*
+ * The method name and arguments of each RPC.  These are shared by the blocking
+ * client and AsyncMorayClient.
+ */
+
+pub(crate) fn create_bucket_rpc(
+    name: &str,
+    config: &BucketConfig,
+    opts: &MethodOptions,
+) -> Result<(String, Value), MorayError> {
+    let arg = serde_json::to_value((name, config, opts))?;
+    Ok((Methods::Create.method(), arg))
+}
+
+pub(crate) fn update_bucket_rpc(
+    name: &str,
+    config: &BucketConfig,
+    opts: &MethodOptions,
+) -> Result<(String, Value), MorayError> {
+    let arg = serde_json::to_value((name, config, opts))?;
+    Ok((Methods::Update.method(), arg))
+}
+
+pub(crate) fn delete_bucket_rpc(
+    name: &str,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (Methods::Delete.method(), json!([name, opts]))
+}
+
+pub(crate) fn get_list_buckets_rpc(
+    name: &str,
+    opts: &MethodOptions,
+    method: &Methods,
+) -> Result<(String, Value), MorayError> {
+    let arg = match method {
+        Methods::Get => json!([opts, name]),
+        Methods::List => json!([opts]),
+        _ => {
+            return Err(MorayError::InvalidInput(String::from(
+                "Unsupported Method",
+            )))
+        }
+    };
+
+    Ok((method.method(), arg))
 }
 
 pub fn create_bucket(
     stream: &mut TcpStream,
     name: &str,
-    config: Value,
+    config: &BucketConfig,
     opts: MethodOptions,
-) -> Result<(), Error> {
-    let arg = json!([name, config, opts]);
-    let mut msg_id = FastMessageId::new();
+) -> Result<(), MorayError> {
+    let (method, arg) = create_bucket_rpc(name, config, &opts)?;
 
     // TODO: ideally we'd try to get the bucket first, and if that fails then
-    // create it.
-    fast_client::send(Methods::Create.method(), arg, &mut msg_id, stream)
-        .and_then(|_| {
-            fast_client::receive(stream, |resp| {
-                dbg!(resp); // createBucket returns empty response
-                Ok(())
-            })
-        })?;
+    // create it.  createBucket returns an empty response.
+    fast::call(stream, method, arg, |_| Ok(()))
+}
+
+// A bucket's version may only move forward.  Unversioned buckets (version 0)
+// can be updated to any version.  This fails with the same BucketVersionError
+// that moray returns, so callers see one error whichever side refuses.
+fn check_bucket_version(
+    name: &str,
+    current: u32,
+    config: &BucketConfig,
+) -> Result<(), MorayError> {
+    let new_version = config.options.version;
+
+    if current != 0 && new_version <= current {
+        return Err(MorayError::Server(ServerError::new(
+            "BucketVersionError",
+            format!(
+                "{} has a newer version than {} ({})",
+                name, new_version, current
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn bucket_not_found(name: &str) -> MorayError {
+    MorayError::BucketNotFound(ServerError::new(
+        "BucketNotFoundError",
+        format!("{} does not exist", name),
+    ))
+}
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub(crate) fn invalid_bucket_name(name: &str) -> MorayError {
+    MorayError::Server(ServerError::new(
+        "InvalidBucketNameError",
+        format!("{} is not a valid bucket name", name),
+    ))
+}
 
+/// Check a bucket name against moray's rules, for the backends that create
+/// tables without going through moray.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub(crate) fn check_bucket_name(name: &str) -> Result<(), MorayError> {
+    let valid_char = |(i, c): (usize, char)| {
+        c.is_ascii_alphabetic()
+            || c == '_'
+            || c == '$'
+            || (i > 0 && c.is_ascii_digit())
+    };
+
+    if name.is_empty()
+        || !name.chars().enumerate().all(valid_char)
+        || name == "buckets_config"
+    {
+        return Err(invalid_bucket_name(name));
+    }
     Ok(())
 }
 
+/// Check that `config` may be applied to the `current` bucket named `name`,
+/// which is None if the bucket doesn't exist.
+pub(crate) fn check_bucket_update(
+    name: &str,
+    current: Option<&Bucket>,
+    config: &BucketConfig,
+) -> Result<(), MorayError> {
+    let current = current.ok_or_else(|| bucket_not_found(name))?;
+
+    check_bucket_version(name, current.options.version, config)
+}
+
+/// Update the configuration of an existing bucket.  The bucket is fetched
+/// first so that an update which would move the bucket's version backwards
+/// (or leave it unchanged) is rejected before anything is sent to moray.
+pub fn update_bucket(
+    stream: &mut TcpStream,
+    name: &str,
+    config: &BucketConfig,
+    opts: MethodOptions,
+) -> Result<(), MorayError> {
+    let mut current = None;
+
+    get_list_buckets(stream, name, opts.clone(), Methods::Get, |b| {
+        current = Some(b.clone());
+        Ok(())
+    })?;
+
+    check_bucket_update(name, current.as_ref(), config)?;
+
+    let (method, arg) = update_bucket_rpc(name, config, &opts)?;
+
+    // updateBucket returns empty response
+    fast::call(stream, method, arg, |_| Ok(()))
+}
+
+pub fn delete_bucket(
+    stream: &mut TcpStream,
+    name: &str,
+    opts: MethodOptions,
+) -> Result<(), MorayError> {
+    let (method, arg) = delete_bucket_rpc(name, &opts);
+
+    // delBucket returns empty response
+    fast::call(stream, method, arg, |_| Ok(()))
+}
+
 pub fn get_list_buckets<F>(
     stream: &mut TcpStream,
     name: &str,
     opts: MethodOptions,
     method: Methods,
     mut bucket_handler: F,
-) -> Result<(), Error>
+) -> Result<(), MorayError>
 where
     F: FnMut(&Bucket) -> Result<(), Error>, //FnOnce?
 {
-    let mut arg = json!([opts]);
-    let mut msg_id = FastMessageId::new();
+    let (method, arg) = get_list_buckets_rpc(name, &opts, &method)?;
 
-    match method {
-        Methods::Get => {
-            arg = json!([opts, name]);
-        }
-        Methods::List => {
-            // Use default
-        }
-        _ => return Err(Error::new(ErrorKind::Other, "Unsupported Method")),
-    }
+    fast::call(stream, method, arg, |resp| {
+        decode_bucket(&resp.data.d, |b| Ok(bucket_handler(&b)?))
+    })
+}
 
-    fast_client::send(method.method(), arg, &mut msg_id, stream).and_then(
-        |_| {
-            fast_client::receive(stream, |resp| {
-                decode_bucket(&resp.data.d, |b| bucket_handler(&b))
+/// Send a listBuckets RPC on `conn`, returning an iterator over the buckets.
+/// See `fast::Response` for how the connection is read.
+pub(crate) fn list_buckets_iter<C>(
+    conn: C,
+    opts: &MethodOptions,
+) -> Result<impl Iterator<Item = Result<Bucket, MorayError>>, MorayError>
+where
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    let (method, arg) = get_list_buckets_rpc("", opts, &Methods::List)?;
+    let response = fast::Response::send(conn, method, arg)?;
+
+    Ok(response.flat_map(|data| {
+        let mut found = vec![];
+        let decoded = data.and_then(|d| {
+            decode_bucket(&d, |b| {
+                found.push(Ok(b));
+                Ok(())
             })
-        },
-    )?;
-
-    Ok(())
+        });
+        if let Err(e) = decoded {
+            found.push(Err(e));
+        }
+        found
+    }))
 }
 
 /*
@@ -191,6 +620,28 @@ mod tests {
         }
     }
 
+    impl Arbitrary for IndexDef {
+        fn arbitrary<G: Gen>(g: &mut G) -> IndexDef {
+            let types = [
+                IndexType::String,
+                IndexType::Number,
+                IndexType::Boolean,
+                IndexType::Ip,
+                IndexType::Subnet,
+                IndexType::StringArray,
+                IndexType::NumberArray,
+                IndexType::BooleanArray,
+                IndexType::IpArray,
+                IndexType::SubnetArray,
+            ];
+
+            IndexDef {
+                index_type: types[g.gen_range(0, types.len())].clone(),
+                unique: g.gen::<bool>(),
+            }
+        }
+    }
+
     impl Arbitrary for Bucket {
         fn arbitrary<G: Gen>(g: &mut G) -> Bucket {
             let index_len = g.gen::<u8>() as usize;
@@ -199,12 +650,9 @@ mod tests {
             let post_len = g.gen::<u8>() as usize;
             let pre_len = g.gen::<u8>() as usize;
 
-            // TODO: further randomize index
-            let index = json!({
-                random_string(g, index_len): random_string(g, index_len),
-                random_string(g, index_len): random_string(g, index_len),
-                random_string(g, index_len): random_string(g, index_len),
-            });
+            let index = (0..3)
+                .map(|_| (random_string(g, index_len), IndexDef::arbitrary(g)))
+                .collect();
 
             let mtime = random_string(g, mtime_len);
             let name = random_string(g, name_len);
@@ -234,6 +682,120 @@ mod tests {
         }
     }
 
+    #[test]
+    fn check_bucket_version_test() {
+        let config = |version| BucketConfig::new().with_version(version);
+
+        // Unversioned buckets can go to any version
+        assert!(check_bucket_version("b", 0, &config(0)).is_ok());
+        assert!(check_bucket_version("b", 0, &config(3)).is_ok());
+        assert!(check_bucket_version("b", 0, &BucketConfig::new()).is_ok());
+
+        assert!(check_bucket_version("b", 2, &config(3)).is_ok());
+        match check_bucket_version("b", 2, &config(2)) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "BucketVersionError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(check_bucket_version("b", 2, &config(1)).is_err());
+        assert!(check_bucket_version("b", 2, &BucketConfig::new()).is_err());
+    }
+
+    #[test]
+    fn bucket_config_serialize_test() {
+        let mut config = BucketConfig::new()
+            .with_index("owner", IndexDef::new(IndexType::String))
+            .with_index("roles", IndexDef::new(IndexType::StringArray))
+            .with_index("objectId", IndexDef::unique(IndexType::String))
+            .with_version(2);
+        config.options.set_guarantee_order(true);
+
+        let expected = json!({
+            "index": {
+                "objectId": { "type": "string", "unique": true },
+                "owner": { "type": "string" },
+                "roles": { "type": "[string]" }
+            },
+            "options": {
+                "version": 2,
+                "guaranteeOrder": true,
+                "syncUpdates": false
+            }
+        });
+        assert_eq!(serde_json::to_value(&config).unwrap(), expected);
+
+        let decoded: BucketConfig = serde_json::from_value(expected).unwrap();
+        assert_eq!(decoded, config);
+
+        // Index types we don't know decode, and are sent back unchanged.
+        let other = json!({ "index": { "a": { "type": "[[string]]" } } });
+        let decoded: BucketConfig = serde_json::from_value(other).unwrap();
+        assert_eq!(
+            decoded.index["a"].index_type,
+            IndexType::Other(String::from("[[string]]"))
+        );
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap()["index"],
+            json!({ "a": { "type": "[[string]]" } })
+        );
+    }
+
+    quickcheck! {
+        // Whatever moray sends us for the JSON encoded fields of a bucket we
+        // should get an error naming the field, rather than a panic.
+        fn decode_bucket_garbage_test(
+            index: String,
+            options: String,
+            pre: String,
+            post: String
+        ) -> bool {
+            let input = json!([{
+                "name": "garbage",
+                "mtime": "2020-01-01T00:00:00.000Z",
+                "index": index,
+                "options": options,
+                "pre": pre,
+                "post": post,
+            }]);
+
+            let fields = [
+                (
+                    "index",
+                    serde_json::from_str::<BTreeMap<String, IndexDef>>(&index)
+                        .is_ok()
+                ),
+                (
+                    "options",
+                    serde_json::from_str::<BucketOptions>(&options).is_ok()
+                ),
+                ("post", serde_json::from_str::<Vec<String>>(&post).is_ok()),
+                ("pre", serde_json::from_str::<Vec<String>>(&pre).is_ok()),
+            ];
+            let bad_field = fields.iter().find(|(_, ok)| !ok);
+
+            match (decode_bucket(&input, |_| Ok(())), bad_field) {
+                (Ok(()), None) => true,
+                (
+                    Err(MorayError::Decode { bucket, field, .. }),
+                    Some((bad_field, _)),
+                ) => {
+                    bucket == Some(String::from("garbage")) && field == *bad_field
+                }
+                _ => false,
+            }
+        }
+
+        fn decode_bucket_payload_garbage_test(payload: String) -> bool {
+            let not_array = decode_bucket(&Value::String(payload.clone()), |_| {
+                Ok(())
+            });
+            let bad_bucket = decode_bucket(&json!([payload]), |_| Ok(()));
+
+            not_array.is_err() && bad_bucket.is_err()
+        }
+    }
+
     // TODO: Create array of multiple buckets
     quickcheck! {
         fn decode_bucket_test(bucket: Bucket) -> bool {
@@ -0,0 +1,75 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! How buckets are made and sent by the stores that stand in for moray: the
+//! in-memory one of the testing module and the database backends.
+
+use serde_json::{json, Value};
+
+use super::{Bucket, BucketConfig};
+use crate::error::MorayError;
+#[cfg(any(test, feature = "testing", feature = "sqlite"))]
+use crate::objects;
+
+impl Bucket {
+    pub(crate) fn from_config(
+        name: &str,
+        config: BucketConfig,
+        mtime: String,
+    ) -> Self {
+        Bucket {
+            index: config.index,
+            mtime,
+            name: name.to_string(),
+            options: config.options,
+            post: config.post,
+            pre: config.pre,
+        }
+    }
+
+    /// The bucket as moray sends it, with each field other than the name and
+    /// mtime encoded as a JSON string.  This is the inverse of
+    /// `decode_bucket`.
+    pub(crate) fn to_wire(&self) -> Result<Value, MorayError> {
+        Ok(json!({
+            "index": serde_json::to_string(&self.index)?,
+            "mtime": self.mtime,
+            "name": self.name,
+            "options": serde_json::to_string(&self.options)?,
+            "post": serde_json::to_string(&self.post)?,
+            "pre": serde_json::to_string(&self.pre)?,
+        }))
+    }
+}
+
+// The current time in the ISO 8601 form moray uses for bucket mtimes.
+#[cfg(any(test, feature = "testing", feature = "sqlite"))]
+pub(crate) fn now_iso() -> String {
+    let millis = objects::now_millis();
+    let secs = millis / 1000;
+    let days = (secs / 86400) as i64;
+
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        millis % 1000
+    )
+}
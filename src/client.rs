@@ -5,30 +5,58 @@
 use cueball::backend::Backend;
 use cueball::connection_pool::types::ConnectionPoolOptions;
 use cueball::connection_pool::ConnectionPool;
+use cueball::resolver::Resolver;
 use cueball_static_resolver::StaticIpResolver;
 use cueball_tcp_stream_connection::TcpStreamWrapper;
 
 use slog::Logger;
+use std::collections::HashSet;
 use std::ops::DerefMut;
 
 use std::str::FromStr;
 
+use serde::de::DeserializeOwned;
 use serde_json::{self, Value};
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 use std::net::{IpAddr, SocketAddr};
 
 use super::buckets;
+use super::error::MorayError;
+use super::filter::Filter;
 use super::meta;
-use super::objects;
+use super::objects::{self, Sort, SortOrder};
+use super::resolver::DnsResolver;
+
+// The page size of `find_all_objects` when no limit is given, which is also
+// moray's default limit for findObjects.
+const DEFAULT_PAGE_SIZE: u64 = 1000;
+
+// How `find_all_objects` picks up each page from where the last one ended.
+#[derive(Clone, Copy)]
+enum Paging {
+    ById,
+    ByOffset,
+}
 
 #[derive(Clone)]
-pub struct MorayClient {
-    connection_pool: ConnectionPool<
-        TcpStreamWrapper,
-        StaticIpResolver,
-        fn(&Backend) -> TcpStreamWrapper,
-    >,
+pub struct MorayClient<R = StaticIpResolver>
+where
+    R: Resolver,
+{
+    connection_pool:
+        ConnectionPool<TcpStreamWrapper, R, fn(&Backend) -> TcpStreamWrapper>,
+}
+
+fn default_pool_opts(log: Logger) -> ConnectionPoolOptions {
+    ConnectionPoolOptions {
+        max_connections: Some(2),
+        claim_timeout: Some(5000),
+        log: Some(log),
+        rebalancer_action_delay: None, // Default 100ms
+        decoherence_interval: None,    // Default 300s
+        connection_check_interval: None, // Default 30s
+    }
 }
 
 ///
@@ -39,25 +67,88 @@ impl MorayClient {
         address: SocketAddr,
         log: Logger,
         opts: Option<ConnectionPoolOptions>,
-    ) -> Result<MorayClient, Error> {
-        let primary_backend = (address.ip(), address.port());
-        let resolver = StaticIpResolver::new(vec![primary_backend]);
+    ) -> Result<MorayClient, MorayError> {
+        Self::with_backends(vec![address], log, opts)
+    }
+
+    /// Create a client that balances its connections across several moray
+    /// instances, so that the loss of any one of them doesn't take the client
+    /// down with it.
+    pub fn with_backends(
+        addresses: Vec<SocketAddr>,
+        log: Logger,
+        opts: Option<ConnectionPoolOptions>,
+    ) -> Result<MorayClient, MorayError> {
+        if addresses.is_empty() {
+            return Err(MorayError::InvalidInput(String::from(
+                "At least one backend address is required",
+            )));
+        }
+
+        let backends = addresses
+            .iter()
+            .map(|address| (address.ip(), address.port()))
+            .collect();
+        let resolver = StaticIpResolver::new(backends);
+
+        Self::with_resolver(resolver, log, opts)
+    }
+
+    pub fn from_parts<I: Into<IpAddr>>(
+        ip: I,
+        port: u16,
+        log: Logger,
+        opts: Option<ConnectionPoolOptions>,
+    ) -> Result<MorayClient, MorayError> {
+        Self::new(SocketAddr::new(ip.into(), port), log, opts)
+    }
+
+    pub fn from_str(
+        s: &str,
+        log: Logger,
+        opts: Option<ConnectionPoolOptions>,
+    ) -> Result<MorayClient, MorayError> {
+        let addr = SocketAddr::from_str(s).map_err(|e| {
+            MorayError::InvalidInput(format!("Error parsing address: {}", e))
+        })?;
+        Self::new(addr, log, opts)
+    }
+}
+
+impl MorayClient<DnsResolver> {
+    /// Create a client for the moray service at `domain`, e.g.
+    /// "1.moray.us-east.joyent.us".  The backends are found through DNS (see
+    /// `DnsResolver`) and are periodically re-resolved, so moray instances
+    /// that come and go are picked up by the connection pool.
+    pub fn from_domain(
+        domain: &str,
+        log: Logger,
+        opts: Option<ConnectionPoolOptions>,
+    ) -> Result<MorayClient<DnsResolver>, MorayError> {
+        let resolver = DnsResolver::new(domain, log.clone());
+        Self::with_resolver(resolver, log, opts)
+    }
+}
 
+impl<R> MorayClient<R>
+where
+    R: Resolver,
+{
+    /// Create a client whose connection pool gets its backends from
+    /// `resolver`.
+    pub fn with_resolver(
+        resolver: R,
+        log: Logger,
+        opts: Option<ConnectionPoolOptions>,
+    ) -> Result<MorayClient<R>, MorayError> {
         let pool_opts = match opts {
-            None => ConnectionPoolOptions {
-                max_connections: Some(2),
-                claim_timeout: Some(5000),
-                log: Some(log),
-                rebalancer_action_delay: None, // Default 100ms
-                decoherence_interval: None,    // Default 300s
-                connection_check_interval: None, // Default 30s
-            },
+            None => default_pool_opts(log),
             Some(opts) => opts,
         };
 
         let pool = ConnectionPool::<
             TcpStreamWrapper,
-            StaticIpResolver,
+            R,
             fn(&Backend) -> TcpStreamWrapper,
         >::new(pool_opts, resolver, TcpStreamWrapper::new);
 
@@ -66,27 +157,18 @@ impl MorayClient {
         })
     }
 
-    pub fn from_parts<I: Into<IpAddr>>(
-        ip: I,
-        port: u16,
-        log: Logger,
-        opts: Option<ConnectionPoolOptions>,
-    ) -> Result<MorayClient, Error> {
-        Self::new(SocketAddr::new(ip.into(), port), log, opts)
-    }
-
     pub fn list_buckets<F>(
         &mut self,
         opts: buckets::MethodOptions,
         bucket_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&buckets::Bucket) -> Result<(), Error>,
     {
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
 
         buckets::get_list_buckets(
             &mut (*conn).deref_mut(),
@@ -97,19 +179,38 @@ impl MorayClient {
         )
     }
 
+    /// Like `list_buckets`, but returns an iterator over the buckets instead of
+    /// calling a handler.  Buckets are read off the connection as the
+    /// iterator is advanced, and the connection goes back to the pool once the
+    /// iterator is exhausted or dropped.
+    pub fn list_buckets_iter(
+        &mut self,
+        opts: buckets::MethodOptions,
+    ) -> Result<
+        impl Iterator<Item = Result<buckets::Bucket, MorayError>>,
+        MorayError,
+    > {
+        let conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+
+        buckets::list_buckets_iter(conn, &opts)
+    }
+
     pub fn get_bucket<F>(
         &mut self,
         name: &str,
         opts: buckets::MethodOptions,
         bucket_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&buckets::Bucket) -> Result<(), Error>,
     {
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
 
         buckets::get_list_buckets(
             &mut (*conn).deref_mut(),
@@ -126,14 +227,14 @@ impl MorayClient {
         key: &str,
         opts: &objects::MethodOptions,
         object_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&objects::MorayObject) -> Result<(), Error>,
     {
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
 
         objects::get_find_objects(
             &mut (*conn).deref_mut(),
@@ -145,30 +246,339 @@ impl MorayClient {
         )
     }
 
-    pub fn find_objects<F>(
+    /// Find the objects in `bucket` that match `filter`, which may be a
+    /// `filter::Filter` or a filter string.
+    pub fn find_objects<F, Q>(
         &mut self,
         bucket: &str,
-        filter: &str,
+        filter: Q,
         opts: &objects::MethodOptions,
         object_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&objects::MorayObject) -> Result<(), Error>,
+        Q: Into<String>,
     {
+        let filter: String = filter.into();
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
         objects::get_find_objects(
             &mut (*conn).deref_mut(),
             bucket,
-            filter,
+            &filter,
             opts,
             objects::Methods::Find,
             object_handler,
         )
     }
 
+    /// Like `find_objects`, but pages through the results so that every
+    /// matching object is passed to the handler exactly once, however many
+    /// there are.
+    ///
+    /// Moray caps the objects returned by a single findObjects, so this makes
+    /// a series of them, sorted by `_id`, with each one picking up from the
+    /// last `_id` seen by adding `(_id>=N)` to the filter.  The `limit` in
+    /// `opts` sets the size of each page, and any `sort` or `offset` is
+    /// replaced.
+    ///
+    /// Objects may be created or deleted while the pages are read.  Moray
+    /// assigns `_id` when an object is inserted, not when its transaction
+    /// commits, so an object can turn up behind the pages already read.  To
+    /// find these the results are read again from the start, until a pass
+    /// turns up nothing new below the `_id` the pass before it reached, and
+    /// the `_id` of each object passed to the handler is kept so that none is
+    /// passed twice.  Every matching object committed before the last pass
+    /// starts, and not deleted since, is passed to the handler once.  This
+    /// takes at least two passes over the results.
+    pub fn find_all_objects<F, Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        opts: &objects::MethodOptions,
+        object_handler: F,
+    ) -> Result<(), MorayError>
+    where
+        F: FnMut(&objects::MorayObject) -> Result<(), Error>,
+        Q: Into<String>,
+    {
+        let filter: Filter = filter.into().parse()?;
+        self.find_all(bucket, &filter, opts, Paging::ById, object_handler)
+    }
+
+    /// Like `find_all_objects`, but each page is found with `filter` as it
+    /// is, at the next `offset`, instead of by adding `(_id>=N)` to it.
+    /// Pages are still sorted by `_id`, and an object that moves between
+    /// pages as others are created or deleted is caught by the next pass, or
+    /// passed over if it has already been seen.
+    pub fn find_all_objects_by_offset<F, Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        opts: &objects::MethodOptions,
+        object_handler: F,
+    ) -> Result<(), MorayError>
+    where
+        F: FnMut(&objects::MorayObject) -> Result<(), Error>,
+        Q: Into<String>,
+    {
+        let filter: Filter = filter.into().parse()?;
+        self.find_all(bucket, &filter, opts, Paging::ByOffset, object_handler)
+    }
+
+    fn find_all<F>(
+        &mut self,
+        bucket: &str,
+        filter: &Filter,
+        opts: &objects::MethodOptions,
+        paging: Paging,
+        mut object_handler: F,
+    ) -> Result<(), MorayError>
+    where
+        F: FnMut(&objects::MorayObject) -> Result<(), Error>,
+    {
+        let page_size = opts
+            .limit()
+            .filter(|&limit| limit > 0)
+            .unwrap_or(DEFAULT_PAGE_SIZE);
+        let mut page_opts = opts.clone();
+        page_opts.set_limit(page_size);
+        page_opts.unset_offset();
+        page_opts.set_sort(Sort::new("_id", SortOrder::Asc));
+
+        let mut seen = HashSet::new();
+        // The `_id` past those read by the previous pass.
+        let mut high_water: Option<u64> = None;
+        loop {
+            let mut next_id = 0;
+            let mut offset = 0;
+            let mut late = false;
+            loop {
+                let page_filter = match paging {
+                    Paging::ById => Filter::and(vec![
+                        filter.clone(),
+                        Filter::ge("_id", next_id.to_string()),
+                    ]),
+                    Paging::ByOffset => {
+                        page_opts.set_offset(offset);
+                        filter.clone()
+                    }
+                };
+                let mut found = 0;
+                self.find_objects(bucket, page_filter, &page_opts, |object| {
+                    found += 1;
+                    next_id = next_id.max(object._id + 1);
+                    if !seen.insert(object._id) {
+                        return Ok(());
+                    }
+                    late |= high_water.map_or(false, |h| object._id < h);
+                    object_handler(object)
+                })?;
+
+                offset += found;
+                if found < page_size {
+                    break;
+                }
+            }
+
+            if high_water.is_some() && !late {
+                return Ok(());
+            }
+            high_water = Some(next_id);
+        }
+    }
+
+    /// Like `find_objects`, but returns an iterator over the objects instead
+    /// of calling a handler, so that results can be collected, zipped, or
+    /// abandoned part way through with `?` or `break`.
+    ///
+    /// Objects are read off the connection as the iterator is advanced, and
+    /// the connection goes back to the pool once the iterator is exhausted or
+    /// dropped.  Dropping the iterator early doesn't read the rest of the
+    /// response: the connection is reconnected instead, so abandoning a very
+    /// large result set is cheap.
+    pub fn find_objects_iter<Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        opts: &objects::MethodOptions,
+    ) -> Result<
+        impl Iterator<Item = Result<objects::MorayObject, MorayError>>,
+        MorayError,
+    >
+    where
+        Q: Into<String>,
+    {
+        self.find_objects_as(bucket, filter, opts)
+    }
+
+    /// Like `get_object`, but deserializes the object's value into a `T`
+    /// straight from the response, rather than going through a `Value`.
+    pub fn get_object_as<T>(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &objects::MethodOptions,
+    ) -> Result<objects::MorayObject<T>, MorayError>
+    where
+        T: DeserializeOwned,
+    {
+        let mut conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+
+        objects::get_object_as(&mut (*conn).deref_mut(), bucket, key, opts)
+    }
+
+    /// Like `find_objects_iter`, but deserializes each object's value into a
+    /// `T`.  An object whose value doesn't fit is returned as a decode error
+    /// naming its key, and the iterator carries on with the next object.
+    pub fn find_objects_as<T, Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        opts: &objects::MethodOptions,
+    ) -> Result<
+        impl Iterator<Item = Result<objects::MorayObject<T>, MorayError>>,
+        MorayError,
+    >
+    where
+        T: DeserializeOwned,
+        Q: Into<String>,
+    {
+        let conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+
+        objects::find_objects_iter(conn, bucket, &filter.into(), opts)
+    }
+
+    pub fn update_objects<Q>(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: Q,
+        opts: &objects::MethodOptions,
+    ) -> Result<objects::UpdateObjectsReturn, MorayError>
+    where
+        Q: Into<String>,
+    {
+        let mut conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+        objects::update_objects(
+            &mut (*conn).deref_mut(),
+            bucket,
+            fields,
+            filter,
+            opts,
+        )
+    }
+
+    pub fn delete_many<Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        opts: &objects::MethodOptions,
+    ) -> Result<u64, MorayError>
+    where
+        Q: Into<String>,
+    {
+        let mut conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+        objects::delete_many(&mut (*conn).deref_mut(), bucket, filter, opts)
+    }
+
+    /// Repeatedly call deleteMany with the given `limit` until no more objects
+    /// match `filter`, returning the total number of objects deleted.  Each
+    /// call is made on a freshly claimed connection and is its own transaction,
+    /// so large cleanups don't hold a single long running transaction open.
+    pub fn delete_many_all<Q>(
+        &mut self,
+        bucket: &str,
+        filter: Q,
+        limit: u64,
+        opts: &objects::MethodOptions,
+    ) -> Result<u64, MorayError>
+    where
+        Q: Into<String>,
+    {
+        if limit == 0 {
+            return Err(MorayError::InvalidInput(String::from(
+                "limit must be greater than zero",
+            )));
+        }
+
+        let filter: String = filter.into();
+        let mut limited_opts = opts.clone();
+        limited_opts.set_limit(limit);
+
+        let mut total = 0;
+        loop {
+            let count =
+                self.delete_many(bucket, filter.as_str(), &limited_opts)?;
+            if count == 0 {
+                return Ok(total);
+            }
+            total += count;
+        }
+    }
+
+    pub fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &objects::MethodOptions,
+    ) -> Result<objects::ReindexObjectsReturn, MorayError> {
+        let mut conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+        objects::reindex_objects(&mut (*conn).deref_mut(), bucket, count, opts)
+    }
+
+    /// Repeatedly call reindexObjects, `count` objects at a time, until moray
+    /// reports that there is nothing left to reindex.  The total number of
+    /// objects processed is returned.
+    ///
+    /// The `progress_handler` is called after each call to reindexObjects.  If
+    /// it returns an error reindexing stops and that error is returned, which
+    /// allows the caller to cancel a long running reindex.  Reindexing can
+    /// safely be resumed later.
+    pub fn reindex_to_completion<F>(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &objects::MethodOptions,
+        mut progress_handler: F,
+    ) -> Result<u64, MorayError>
+    where
+        F: FnMut(&objects::ReindexObjectsReturn) -> Result<(), Error>,
+    {
+        if count == 0 {
+            return Err(MorayError::InvalidInput(String::from(
+                "count must be greater than zero",
+            )));
+        }
+
+        let mut total = 0;
+        loop {
+            let ret = self.reindex_objects(bucket, count, opts)?;
+            total += ret.processed;
+            progress_handler(&ret)?;
+            if ret.processed == 0 {
+                return Ok(total);
+            }
+        }
+    }
+
     pub fn put_object<F>(
         &mut self,
         bucket: &str,
@@ -176,14 +586,14 @@ impl MorayClient {
         value: Value,
         opts: &objects::MethodOptions,
         object_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&str) -> Result<(), Error>,
     {
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
         objects::put_object(
             &mut (*conn).deref_mut(),
             bucket,
@@ -194,17 +604,48 @@ impl MorayClient {
         )
     }
 
+    pub fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &objects::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let mut conn = self
+            .connection_pool
+            .claim()
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
+        objects::delete_object(&mut (*conn).deref_mut(), bucket, key, opts)
+    }
+
     pub fn create_bucket(
         &mut self,
         name: &str,
-        config: Value,
+        config: &buckets::BucketConfig,
         opts: buckets::MethodOptions,
-    ) -> Result<(), Error> {
+    ) -> Result<(), MorayError> {
         buckets::create_bucket(
             &mut self
                 .connection_pool
                 .claim()
-                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?
+                .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?
+                .deref_mut(),
+            name,
+            config,
+            opts,
+        )
+    }
+
+    pub fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &buckets::BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        buckets::update_bucket(
+            &mut self
+                .connection_pool
+                .claim()
+                .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?
                 .deref_mut(),
             name,
             config,
@@ -212,19 +653,35 @@ impl MorayClient {
         )
     }
 
+    pub fn delete_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        buckets::delete_bucket(
+            &mut self
+                .connection_pool
+                .claim()
+                .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?
+                .deref_mut(),
+            name,
+            opts,
+        )
+    }
+
     pub fn batch<F>(
         &mut self,
         requests: &[objects::BatchRequest],
         opts: &objects::MethodOptions,
         object_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
-        F: FnMut(Vec<Value>) -> Result<(), Error>,
+        F: FnMut(Vec<objects::BatchResult>) -> Result<(), Error>,
     {
         let mut conn = self
             .connection_pool
             .claim()
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?;
         objects::batch(&mut (*conn).deref_mut(), requests, opts, object_handler)
     }
 
@@ -234,7 +691,7 @@ impl MorayClient {
         vals: Vec<&str>,
         opts: V,
         query_handler: F,
-    ) -> Result<(), Error>
+    ) -> Result<(), MorayError>
     where
         F: FnMut(&Value) -> Result<(), Error>,
         V: Into<Value>,
@@ -243,7 +700,7 @@ impl MorayClient {
             &mut self
                 .connection_pool
                 .claim()
-                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?
+                .map_err(|e| MorayError::ClaimTimeout(e.to_string()))?
                 .deref_mut(),
             stmt,
             vals,
@@ -251,22 +708,154 @@ impl MorayClient {
             query_handler,
         )
     }
-
-    pub fn from_str(
-        s: &str,
-        log: Logger,
-        opts: Option<ConnectionPoolOptions>,
-    ) -> Result<MorayClient, Error> {
-        let addr = SocketAddr::from_str(s).expect("Error parsing address");
-        Self::new(addr, log, opts)
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::buckets::{BucketConfig, IndexDef, IndexType};
+    use crate::fast::test_server::{object, serve};
+    use crate::testing::MockMoray;
+    use serde_json::json;
+    use slog::{o, Discard};
+    use std::io::ErrorKind;
+
+    fn handler(
+        method: &str,
+        _args: &Value,
+    ) -> Result<Vec<Value>, (&'static str, &'static str)> {
+        match method {
+            "findObjects" => Ok(vec![
+                json!([object("k1"), object("k2")]),
+                json!([object("k3")]),
+            ]),
+            "listBuckets" => Err(("NoDatabasePeersError", "no peers")),
+            _ => Err(("NotImplementedError", "not implemented")),
+        }
+    }
+
+    #[test]
+    fn find_objects_iter_test() {
+        let log = Logger::root(Discard, o!());
+        let mut opts = default_pool_opts(log.clone());
+        opts.max_connections = Some(1);
+
+        let addr = serve(handler);
+        let mut client = MorayClient::new(addr, log, Some(opts)).unwrap();
+        let opts = objects::MethodOptions::default();
+
+        let keys: Vec<String> = client
+            .find_objects_iter("b", "(key=*)", &opts)
+            .unwrap()
+            .map(|obj| obj.map(|o| o.key))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(keys, vec!["k1", "k2", "k3"]);
+
+        // Abandon the response part way through.  The only connection in the
+        // pool must come back clean for the next request.
+        let first = client
+            .find_objects_iter("b", "(key=*)", &opts)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.key, "k1");
+
+        let count = client
+            .find_objects_iter("b", "(key=*)", &opts)
+            .unwrap()
+            .count();
+        assert_eq!(count, 3);
+
+        let mut buckets = client
+            .list_buckets_iter(buckets::MethodOptions::default())
+            .unwrap();
+        match buckets.next() {
+            Some(Err(MorayError::NoDatabasePeers(_))) => (),
+            _ => panic!("expected NoDatabasePeers error"),
+        }
+        assert!(buckets.next().is_none());
+    }
+
+    #[test]
+    fn find_all_objects_test() {
+        let log = Logger::root(Discard, o!());
+        let mock = MockMoray::start().unwrap();
+        let mut writer =
+            MorayClient::new(mock.address(), log.clone(), None).unwrap();
+        let mut client = MorayClient::new(mock.address(), log, None).unwrap();
+        let config = BucketConfig::new()
+            .with_index("n", IndexDef::new(IndexType::Number));
+        client
+            .create_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+
+        let opts = objects::MethodOptions::default();
+        let put = |client: &mut MorayClient, key: &str, n: u64| {
+            client.put_object("b", key, json!({ "n": n }), &opts, |_| Ok(()))
+        };
+        for n in 0..25 {
+            put(&mut client, &format!("k{:02}", n), n).unwrap();
+        }
+
+        // Objects created part way through are picked up by later pages, and
+        // nothing is passed to the handler twice.
+        let mut found = vec![];
+        let mut page_opts = objects::MethodOptions::default();
+        page_opts.set_limit(10);
+        client
+            .find_all_objects("b", "(n>=5)", &page_opts, |o| {
+                found.push(o.key.clone());
+                if o.key == "k05" {
+                    put(&mut writer, "new", 50).map_err(|e| {
+                        Error::new(ErrorKind::Other, e.to_string())
+                    })?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        let mut expected: Vec<String> =
+            (5..25).map(|n| format!("k{:02}", n)).collect();
+        expected.push(String::from("new"));
+        assert_eq!(found, expected);
+
+        // Paging by offset, deleting an object that has been seen shifts the
+        // next page past k15, which the second pass picks up.
+        let mut found = vec![];
+        client
+            .find_all_objects_by_offset("b", "(n>=5)", &page_opts, |o| {
+                found.push(o.key.clone());
+                if o.key == "k05" {
+                    writer.delete_object("b", "k05", &opts).map_err(|e| {
+                        Error::new(ErrorKind::Other, e.to_string())
+                    })?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        let mut expected: Vec<String> = (5..25)
+            .filter(|&n| n != 15)
+            .map(|n| format!("k{:02}", n))
+            .collect();
+        expected.push(String::from("new"));
+        expected.push(String::from("k15"));
+        assert_eq!(found, expected);
+
+        match client.find_all_objects("b", "(n>=5", &page_opts, |_| Ok(())) {
+            Err(MorayError::InvalidFilter(_)) => (),
+            _ => panic!("expected InvalidFilter error"),
+        }
+    }
 
     #[test]
-    fn placeholder() {
-        assert_eq!(1, 1);
+    fn with_backends_empty_test() {
+        let log = Logger::root(Discard, o!());
+        match MorayClient::with_backends(vec![], log, None) {
+            Err(MorayError::InvalidInput(_)) => (),
+            _ => panic!("expected InvalidInput error"),
+        }
     }
 }
@@ -0,0 +1,311 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// An error reported by the moray server.  This is decoded from the data of a
+/// Fast ERROR message, and `name` is the moray error name, e.g.
+/// "EtagConflictError".  The `context` is error specific, for example an
+/// EtagConflictError's context includes the `expected` and `actual` etags.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ServerError {
+    pub name: String,
+    pub message: String,
+    #[serde(default)]
+    pub context: Value,
+}
+
+impl ServerError {
+    pub fn new(name: &str, message: String) -> Self {
+        Self {
+            name: name.to_string(),
+            message,
+            context: Value::Null,
+        }
+    }
+
+    /// Look up a string field in the error's context, e.g. the `actual` etag
+    /// of an EtagConflictError.
+    pub fn context_str(&self, field: &str) -> Option<&str> {
+        self.context.get(field).and_then(Value::as_str)
+    }
+}
+
+#[derive(Debug)]
+pub enum MorayError {
+    /// The etag specified in the request did not match the object's etag.
+    EtagConflict(ServerError),
+    ObjectNotFound(ServerError),
+    BucketNotFound(ServerError),
+    /// A unique index constraint would have been violated.
+    UniqueAttribute(ServerError),
+    InvalidQuery(ServerError),
+    /// Moray has no database available to service the request.
+    NoDatabasePeers(ServerError),
+    /// Any other error reported by the moray server.
+    Server(ServerError),
+    /// A connection could not be claimed from the connection pool.
+    ClaimTimeout(String),
+    /// The request was rejected by the client before it was sent to moray.
+    InvalidInput(String),
+    /// A filter could not be parsed, or can't be used with the bucket it was
+    /// validated against.
+    InvalidFilter(String),
+    /// Moray responded with something we could not make sense of.
+    Protocol(String),
+    /// A field of a bucket or object returned by moray could not be decoded.
+    Decode {
+        bucket: Option<String>,
+        field: String,
+        message: String,
+    },
+    Io(Error),
+}
+
+impl MorayError {
+    /// Build the appropriate variant from the data of a Fast ERROR message.
+    pub fn from_server_error(data: &Value) -> Self {
+        let err = match ServerError::deserialize(data) {
+            Ok(err) => err,
+            Err(e) => {
+                return MorayError::Protocol(format!(
+                    "Could not decode error response ({}): {}",
+                    e, data
+                ));
+            }
+        };
+
+        match err.name.as_str() {
+            "EtagConflictError" => MorayError::EtagConflict(err),
+            "ObjectNotFoundError" => MorayError::ObjectNotFound(err),
+            "BucketNotFoundError" => MorayError::BucketNotFound(err),
+            "UniqueAttributeError" => MorayError::UniqueAttribute(err),
+            "InvalidQueryError" => MorayError::InvalidQuery(err),
+            "NoDatabasePeersError" => MorayError::NoDatabasePeers(err),
+            _ => MorayError::Server(err),
+        }
+    }
+
+    pub(crate) fn decode<E: fmt::Display>(
+        bucket: Option<&str>,
+        field: &str,
+        error: E,
+    ) -> Self {
+        MorayError::Decode {
+            bucket: bucket.map(String::from),
+            field: field.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// The error reported by the moray server, if this is a server error.
+    pub fn server_error(&self) -> Option<&ServerError> {
+        match self {
+            MorayError::EtagConflict(e)
+            | MorayError::ObjectNotFound(e)
+            | MorayError::BucketNotFound(e)
+            | MorayError::UniqueAttribute(e)
+            | MorayError::InvalidQuery(e)
+            | MorayError::NoDatabasePeers(e)
+            | MorayError::Server(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MorayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MorayError::ClaimTimeout(msg) => {
+                write!(f, "Failed to claim connection: {}", msg)
+            }
+            MorayError::InvalidInput(msg) => {
+                write!(f, "Invalid input: {}", msg)
+            }
+            MorayError::InvalidFilter(msg) => {
+                write!(f, "Invalid filter: {}", msg)
+            }
+            MorayError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            MorayError::Decode {
+                bucket: Some(bucket),
+                field,
+                message,
+            } => write!(
+                f,
+                "Failed to decode '{}' in bucket {}: {}",
+                field, bucket, message
+            ),
+            MorayError::Decode {
+                bucket: None,
+                field,
+                message,
+            } => write!(f, "Failed to decode '{}': {}", field, message),
+            MorayError::Io(e) => write!(f, "{}", e),
+            _ => {
+                let e = self.server_error().expect("server error");
+                write!(f, "{}: {}", e.name, e.message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MorayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MorayError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Handlers passed to the client return std::io::Error.  If a handler returns
+// an io::Error that wraps a MorayError (see below) we unwrap it here so that
+// the original error makes it back to the caller intact.
+impl From<Error> for MorayError {
+    fn from(error: Error) -> Self {
+        let is_moray_error = error
+            .get_ref()
+            .map(|inner| inner.is::<MorayError>())
+            .unwrap_or(false);
+
+        if is_moray_error {
+            let inner = error.into_inner().expect("inner error");
+            return *inner.downcast::<MorayError>().expect("MorayError");
+        }
+
+        MorayError::Io(error)
+    }
+}
+
+impl From<MorayError> for Error {
+    fn from(error: MorayError) -> Self {
+        let kind = match error {
+            MorayError::Io(e) => return e,
+            MorayError::ObjectNotFound(_) | MorayError::BucketNotFound(_) => {
+                ErrorKind::NotFound
+            }
+            MorayError::InvalidInput(_) | MorayError::InvalidFilter(_) => {
+                ErrorKind::InvalidInput
+            }
+            MorayError::ClaimTimeout(_) => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        };
+
+        Error::new(kind, error)
+    }
+}
+
+impl From<serde_json::Error> for MorayError {
+    fn from(error: serde_json::Error) -> Self {
+        MorayError::Protocol(error.to_string())
+    }
+}
+
+// Errors from the SQLite database behind a `local::LocalMoray`, which stands in
+// for the moray server.
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for MorayError {
+    fn from(error: rusqlite::Error) -> Self {
+        match &error {
+            rusqlite::Error::SqliteFailure(e, _)
+                if e.extended_code
+                    == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                MorayError::UniqueAttribute(ServerError::new(
+                    "UniqueAttributeError",
+                    error.to_string(),
+                ))
+            }
+            _ => MorayError::Server(ServerError::new(
+                "SqliteError",
+                error.to_string(),
+            )),
+        }
+    }
+}
+
+// Errors from the Postgres database behind a `postgres::PgMoray`.
+#[cfg(feature = "postgres")]
+impl From<pg::Error> for MorayError {
+    fn from(error: pg::Error) -> Self {
+        match error.code() {
+            Some(code) if *code == pg::error::SqlState::UNIQUE_VIOLATION => {
+                MorayError::UniqueAttribute(ServerError::new(
+                    "UniqueAttributeError",
+                    error.to_string(),
+                ))
+            }
+            _ => MorayError::Server(ServerError::new(
+                "PostgresError",
+                error.to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_server_error_test() {
+        let data = json!({
+            "name": "EtagConflictError",
+            "message": "wanted to put etag \"ABCD\" but object has \"EFGH\"",
+            "context": {
+                "bucket": "foo",
+                "key": "bar",
+                "expected": "ABCD",
+                "actual": "EFGH"
+            }
+        });
+
+        match MorayError::from_server_error(&data) {
+            MorayError::EtagConflict(e) => {
+                assert_eq!(e.context_str("actual"), Some("EFGH"));
+                assert_eq!(e.context_str("missing"), None);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let data = json!({ "name": "BucketNotFoundError", "message": "nope" });
+        match MorayError::from_server_error(&data) {
+            MorayError::BucketNotFound(e) => assert_eq!(e.context, Value::Null),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let data = json!({ "name": "SomeNewError", "message": "new" });
+        match MorayError::from_server_error(&data) {
+            MorayError::Server(e) => assert_eq!(e.name, "SomeNewError"),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match MorayError::from_server_error(&json!(["garbage"])) {
+            MorayError::Protocol(_) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn io_error_round_trip_test() {
+        let data = json!({ "name": "ObjectNotFoundError", "message": "gone" });
+        let io_err: Error = MorayError::from_server_error(&data).into();
+        assert_eq!(io_err.kind(), ErrorKind::NotFound);
+
+        match MorayError::from(io_err) {
+            MorayError::ObjectNotFound(e) => assert_eq!(e.message, "gone"),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let io_err = Error::new(ErrorKind::Other, "plain io error");
+        match MorayError::from(io_err) {
+            MorayError::Io(e) => assert_eq!(e.kind(), ErrorKind::Other),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
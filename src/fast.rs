@@ -0,0 +1,373 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use bytes::BytesMut;
+use cueball::connection::Connection;
+use rust_fast::client as fast_client;
+use rust_fast::protocol::{
+    self, FastMessage, FastMessageData, FastMessageId, FastMessageStatus,
+    FastParseError,
+};
+use serde_json::Value;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::error::MorayError;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Fast message ids are positive 31 bit integers.
+const FAST_MAX_MSG_ID: usize = 0x7fff_ffff;
+
+static NEXT_MSG_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate a message id for a request that may share its connection with
+/// other outstanding requests.
+pub(crate) fn next_msg_id() -> u32 {
+    (NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed) % FAST_MAX_MSG_ID + 1) as u32
+}
+
+/// Send a single RPC and call `response_handler` for each DATA message
+/// received in response, until the END message arrives.
+///
+/// Unlike `rust_fast::client::receive`, a Fast ERROR message is decoded into
+/// the corresponding `MorayError` so that callers can tell moray errors apart.
+/// If the `response_handler` returns an error the remainder of the response is
+/// still read off the stream before the error is returned, so that the
+/// connection can safely be used for the next request.
+pub(crate) fn call<F>(
+    stream: &mut TcpStream,
+    method: String,
+    args: Value,
+    mut response_handler: F,
+) -> Result<(), MorayError>
+where
+    F: FnMut(&FastMessage) -> Result<(), MorayError>,
+{
+    let mut msg_id = FastMessageId::new();
+    fast_client::send(method, args, &mut msg_id, stream)?;
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut handler_error = None;
+
+    loop {
+        let msg = read_message(stream, &mut buf)?;
+        match msg.status {
+            FastMessageStatus::Data => {
+                if handler_error.is_none() {
+                    handler_error = response_handler(&msg).err();
+                }
+            }
+            FastMessageStatus::End => break,
+            FastMessageStatus::Error => {
+                return Err(MorayError::from_server_error(&msg.data.d));
+            }
+        }
+    }
+
+    match handler_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Send a single RPC whose response consists of one value, and decode it.
+pub(crate) fn call_single<T>(
+    stream: &mut TcpStream,
+    method: String,
+    args: Value,
+) -> Result<T, MorayError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut ret = None;
+
+    call(stream, method.clone(), args, |resp| {
+        ret = Some(decode_single(&method, &resp.data.d)?);
+        Ok(())
+    })?;
+
+    ret.ok_or_else(|| {
+        MorayError::Protocol(format!("No response from {}", method))
+    })
+}
+
+/// The response to an RPC, read off the connection one message at a time as
+/// the iterator is advanced.  Each item is the data of a DATA message, and a
+/// Fast ERROR is returned as the final item.
+///
+/// `C` is a connection claimed from a cueball pool, which dereferences to a
+/// `TcpStream` through one level of wrapping.  If the response is dropped
+/// before the END message has been read, the rest of it is not read: draining
+/// a large response could take a long time.  Instead the socket is shut down,
+/// which tells moray to stop sending, and the connection is reconnected so
+/// that it can be reused for the next request.  If the connection fails part
+/// way through it is shut down.
+pub(crate) struct Response<C>
+where
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    conn: C,
+    msg_id: u32,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<C> Response<C>
+where
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    /// Send an RPC on `conn`, returning its response.
+    pub(crate) fn send(
+        mut conn: C,
+        method: String,
+        args: Value,
+    ) -> Result<Self, MorayError> {
+        let msg_id = next_msg_id();
+        let request = encode_request(msg_id, method, args)?;
+        let stream: &mut TcpStream = &mut conn;
+        stream.write_all(&request)?;
+
+        Ok(Response {
+            conn,
+            msg_id,
+            buf: Vec::new(),
+            done: false,
+        })
+    }
+
+    fn fail(&mut self, error: MorayError) -> Option<Result<Value, MorayError>> {
+        self.done = true;
+        let stream: &mut TcpStream = &mut self.conn;
+        let _ = stream.shutdown(Shutdown::Both);
+        Some(Err(error))
+    }
+}
+
+impl<C> Iterator for Response<C>
+where
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    type Item = Result<Value, MorayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let msg = match read_message(&mut self.conn, &mut self.buf) {
+            Ok(msg) => msg,
+            Err(e) => return self.fail(e),
+        };
+
+        if msg.id != self.msg_id {
+            return self.fail(MorayError::Protocol(format!(
+                "Expected response to message {}, got message {}",
+                self.msg_id, msg.id
+            )));
+        }
+
+        match msg.status {
+            FastMessageStatus::Data => Some(Ok(msg.data.d)),
+            FastMessageStatus::End => {
+                self.done = true;
+                None
+            }
+            FastMessageStatus::Error => {
+                self.done = true;
+                Some(Err(MorayError::from_server_error(&msg.data.d)))
+            }
+        }
+    }
+}
+
+impl<C> Drop for Response<C>
+where
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let stream: &mut TcpStream = &mut self.conn;
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = Connection::connect(&mut *self.conn);
+    }
+}
+
+/// Read the next complete Fast message off of `stream`.  `buf` holds any bytes
+/// that have been read but not yet consumed, and must be passed to each call
+/// for the same response.  Bytes are read straight onto the end of `buf`, so
+/// once it has grown to fit a message no further allocation is needed.
+pub(crate) fn read_message(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> Result<FastMessage, MorayError> {
+    loop {
+        if let Some(msg) = parse_message(buf)? {
+            return Ok(msg);
+        }
+
+        let len = buf.len();
+        buf.resize(len + READ_CHUNK_SIZE, 0);
+        let byte_count = match stream.read(&mut buf[len..]) {
+            Ok(byte_count) => byte_count,
+            Err(e) => {
+                buf.truncate(len);
+                return Err(MorayError::Io(e));
+            }
+        };
+        buf.truncate(len + byte_count);
+
+        if byte_count == 0 {
+            return Err(MorayError::Io(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the end of the response",
+            )));
+        }
+    }
+}
+
+/// Parse a single Fast message from the front of `buf`, removing its bytes.
+/// Returns None if `buf` does not yet contain a complete message.
+pub(crate) fn parse_message(
+    buf: &mut Vec<u8>,
+) -> Result<Option<FastMessage>, MorayError> {
+    match FastMessage::parse(buf) {
+        Ok(msg) => {
+            let msg_size = msg.msg_size.unwrap_or_else(|| buf.len());
+            buf.drain(..msg_size);
+            Ok(Some(msg))
+        }
+        Err(FastParseError::NotEnoughBytes(_)) => Ok(None),
+        Err(FastParseError::IOError(e)) => Err(MorayError::Io(e)),
+    }
+}
+
+/// Encode a Fast message into the bytes that are sent over the wire.
+pub(crate) fn encode_message(msg: &FastMessage) -> Result<Vec<u8>, MorayError> {
+    let mut buf = BytesMut::new();
+    protocol::encode_msg(msg, &mut buf)
+        .map_err(|e| MorayError::Protocol(e.to_string()))?;
+    Ok(buf.to_vec())
+}
+
+/// Encode the request message for an RPC.
+pub(crate) fn encode_request(
+    msg_id: u32,
+    method: String,
+    args: Value,
+) -> Result<Vec<u8>, MorayError> {
+    encode_message(&FastMessage::data(
+        msg_id,
+        FastMessageData::new(method, args),
+    ))
+}
+
+/// Decode the data of a response that is expected to consist of exactly one
+/// value, as is the case for most moray RPCs that don't stream results.
+pub(crate) fn decode_single<T>(
+    method: &str,
+    fm_data: &Value,
+) -> Result<T, MorayError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    match fm_data.as_array() {
+        Some(arr) if arr.len() == 1 => {
+            Ok(serde_json::from_value(arr[0].clone())?)
+        }
+        _ => Err(MorayError::Protocol(format!(
+            "Expected {} response to be a single element Array, got: {}",
+            method, fm_data
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_server {
+    use super::*;
+    use serde_json::json;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    pub(crate) type Handler =
+        fn(&str, &Value) -> Result<Vec<Value>, (&'static str, &'static str)>;
+
+    /// A minimal Fast server that answers each request with the DATA messages
+    /// returned by `handler`, or a Fast ERROR.
+    pub(crate) fn serve(handler: Handler) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = vec![];
+                    while let Ok(req) = read_message(&mut stream, &mut buf) {
+                        let method = req.data.m.name.clone();
+                        let mut resp = vec![];
+                        let msgs = match handler(&method, &req.data.d) {
+                            Ok(data) => {
+                                let mut msgs: Vec<FastMessage> = data
+                                    .into_iter()
+                                    .map(|d| {
+                                        FastMessage::data(
+                                            req.id,
+                                            FastMessageData::new(
+                                                method.clone(),
+                                                d,
+                                            ),
+                                        )
+                                    })
+                                    .collect();
+                                msgs.push(FastMessage::end(
+                                    req.id,
+                                    method.clone(),
+                                ));
+                                msgs
+                            }
+                            Err((name, message)) => vec![FastMessage::error(
+                                req.id,
+                                FastMessageData::new(
+                                    method.clone(),
+                                    json!({ "name": name, "message": message }),
+                                ),
+                            )],
+                        };
+                        for msg in msgs {
+                            resp.extend(encode_message(&msg).unwrap());
+                        }
+                        if stream.write_all(&resp).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    pub(crate) fn object(key: &str) -> Value {
+        json!({
+            "bucket": "b",
+            "key": key,
+            "value": { "key": key },
+            "_id": 1,
+            "_etag": "ABCD",
+            "_mtime": 1,
+            "_txn_snap": null,
+            "_count": 2
+        })
+    }
+}
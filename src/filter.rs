@@ -0,0 +1,837 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! LDAP search filters, as used by findObjects, updateObjects and deleteMany.
+//!
+//! A `Filter` is built up from the constructors below and rendered to its
+//! RFC 4515 string form with `to_string()`, escaping any special characters in
+//! the values:
+//!
+//! ```
+//! use moray::filter::Filter;
+//!
+//! let filter = Filter::and(vec![
+//!     Filter::eq("type", "object"),
+//!     Filter::eq("name", "a(b)*"),
+//! ]);
+//! assert_eq!(filter.to_string(), r"(&(type=object)(name=a\28b\29\2a))");
+//! ```
+//!
+//! Anything that takes a filter accepts either a `Filter` or a `&str`.
+//!
+//! Filter strings can also be parsed into a `Filter`, and a filter can be
+//! checked against a bucket's indexes with `validate` before it is sent to
+//! moray:
+//!
+//! ```ignore
+//! let filter: Filter = "(&(owner=abc)(size>=10))".parse()?;
+//! filter.validate(&bucket)?;
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::buckets::{Bucket, IndexType};
+use super::error::MorayError;
+
+#[cfg(any(test, feature = "testing"))]
+mod matches;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+mod sql;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub(crate) use sql::{order_by, postgres_type, quote_ident, Dialect};
+
+// Fields that moray maintains for every object, and their types.  These can be
+// used in a filter whether or not the bucket indexes them.
+const INTERNAL_FIELDS: &[(&str, IndexType)] = &[
+    ("_id", IndexType::Number),
+    ("_key", IndexType::String),
+    ("_etag", IndexType::String),
+    ("_mtime", IndexType::Number),
+    ("_txn_snap", IndexType::Number),
+    ("_vnode", IndexType::Number),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equal {
+        attr: String,
+        value: String,
+    },
+    GreaterOrEqual {
+        attr: String,
+        value: String,
+    },
+    LessOrEqual {
+        attr: String,
+        value: String,
+    },
+    Present {
+        attr: String,
+    },
+    /// `(attr=initial*any*...*last)`
+    Substring {
+        attr: String,
+        initial: Option<String>,
+        any: Vec<String>,
+        last: Option<String>,
+    },
+    /// An extensible match, `(attr:dn:rule:=value)`.  At least one of `attr`
+    /// and `rule` must be given.
+    Extensible {
+        attr: Option<String>,
+        rule: Option<String>,
+        dn: bool,
+        value: String,
+    },
+}
+
+impl Filter {
+    pub fn eq<A: Into<String>, V: Into<String>>(attr: A, value: V) -> Self {
+        Filter::Equal {
+            attr: attr.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn ge<A: Into<String>, V: Into<String>>(attr: A, value: V) -> Self {
+        Filter::GreaterOrEqual {
+            attr: attr.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn le<A: Into<String>, V: Into<String>>(attr: A, value: V) -> Self {
+        Filter::LessOrEqual {
+            attr: attr.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn and<I: IntoIterator<Item = Filter>>(filters: I) -> Self {
+        Filter::And(filters.into_iter().collect())
+    }
+
+    pub fn or<I: IntoIterator<Item = Filter>>(filters: I) -> Self {
+        Filter::Or(filters.into_iter().collect())
+    }
+
+    pub fn not(filter: Filter) -> Self {
+        Filter::Not(Box::new(filter))
+    }
+
+    pub fn present<A: Into<String>>(attr: A) -> Self {
+        Filter::Present { attr: attr.into() }
+    }
+
+    /// Match values that start with `initial`, contain each of `any` in
+    /// order, and end with `last`.
+    pub fn substring<A: Into<String>>(
+        attr: A,
+        initial: Option<&str>,
+        any: &[&str],
+        last: Option<&str>,
+    ) -> Self {
+        Filter::Substring {
+            attr: attr.into(),
+            initial: initial.map(String::from),
+            any: any.iter().map(|s| s.to_string()).collect(),
+            last: last.map(String::from),
+        }
+    }
+
+    /// Match `attr` against `value` with the given matching rule, e.g.
+    /// `Filter::ext("name", "caseIgnoreMatch", "Foo")`.
+    pub fn ext<A: Into<String>, R: Into<String>, V: Into<String>>(
+        attr: A,
+        rule: R,
+        value: V,
+    ) -> Self {
+        Filter::Extensible {
+            attr: Some(attr.into()),
+            rule: Some(rule.into()),
+            dn: false,
+            value: value.into(),
+        }
+    }
+
+    /// Check that every field this filter references is indexed in `bucket`
+    /// (or is one of moray's internal fields such as `_mtime`), and that the
+    /// values compared against `number` fields are numbers.  Moray rejects
+    /// such filters anyway, but this saves the round trip.
+    pub fn validate(&self, bucket: &Bucket) -> Result<(), MorayError> {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().try_for_each(|f| f.validate(bucket))
+            }
+            Filter::Not(filter) => filter.validate(bucket),
+            Filter::Present { attr } => index_type(bucket, attr).map(|_| ()),
+            Filter::Equal { attr, value }
+            | Filter::GreaterOrEqual { attr, value }
+            | Filter::LessOrEqual { attr, value }
+            | Filter::Extensible {
+                attr: Some(attr),
+                value,
+                ..
+            } => {
+                let index_type = index_type(bucket, attr)?;
+                if is_number_type(&index_type) && !is_number(value) {
+                    return Err(MorayError::InvalidFilter(format!(
+                        "{} is a number field in bucket {}, but \"{}\" is \
+                         not a number",
+                        attr,
+                        bucket.name(),
+                        value
+                    )));
+                }
+                Ok(())
+            }
+            Filter::Substring { attr, .. } => {
+                if is_number_type(&index_type(bucket, attr)?) {
+                    return Err(MorayError::InvalidFilter(format!(
+                        "substring filters can't be used on number field {} \
+                         in bucket {}",
+                        attr,
+                        bucket.name()
+                    )));
+                }
+                Ok(())
+            }
+            Filter::Extensible { attr: None, .. } => Ok(()),
+        }
+    }
+}
+
+fn index_type(bucket: &Bucket, attr: &str) -> Result<IndexType, MorayError> {
+    if let Some((_, index_type)) =
+        INTERNAL_FIELDS.iter().find(|(name, _)| *name == attr)
+    {
+        return Ok(index_type.clone());
+    }
+
+    bucket
+        .index()
+        .get(attr)
+        .map(|index| index.index_type.clone())
+        .ok_or_else(|| {
+            MorayError::InvalidFilter(format!(
+                "{} is not indexed in bucket {}",
+                attr,
+                bucket.name()
+            ))
+        })
+}
+
+fn is_number_type(index_type: &IndexType) -> bool {
+    index_type.element_type() == IndexType::Number
+}
+
+fn is_number(value: &str) -> bool {
+    value.parse::<f64>().map(f64::is_finite).unwrap_or(false)
+}
+
+/// Escape a value for use in a filter string, as described in RFC 4515.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str(r"\2a"),
+            '(' => escaped.push_str(r"\28"),
+            ')' => escaped.push_str(r"\29"),
+            '\\' => escaped.push_str(r"\5c"),
+            '\0' => escaped.push_str(r"\00"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::And(filters) => {
+                write!(f, "(&")?;
+                for filter in filters {
+                    write!(f, "{}", filter)?;
+                }
+                write!(f, ")")
+            }
+            Filter::Or(filters) => {
+                write!(f, "(|")?;
+                for filter in filters {
+                    write!(f, "{}", filter)?;
+                }
+                write!(f, ")")
+            }
+            Filter::Not(filter) => write!(f, "(!{})", filter),
+            Filter::Equal { attr, value } => {
+                write!(f, "({}={})", attr, escape(value))
+            }
+            Filter::GreaterOrEqual { attr, value } => {
+                write!(f, "({}>={})", attr, escape(value))
+            }
+            Filter::LessOrEqual { attr, value } => {
+                write!(f, "({}<={})", attr, escape(value))
+            }
+            Filter::Present { attr } => write!(f, "({}=*)", attr),
+            Filter::Substring {
+                attr,
+                initial,
+                any,
+                last,
+            } => {
+                write!(f, "({}=", attr)?;
+                if let Some(initial) = initial {
+                    write!(f, "{}", escape(initial))?;
+                }
+                write!(f, "*")?;
+                for value in any {
+                    write!(f, "{}*", escape(value))?;
+                }
+                if let Some(last) = last {
+                    write!(f, "{}", escape(last))?;
+                }
+                write!(f, ")")
+            }
+            Filter::Extensible {
+                attr,
+                rule,
+                dn,
+                value,
+            } => {
+                write!(f, "(")?;
+                if let Some(attr) = attr {
+                    write!(f, "{}", attr)?;
+                }
+                if *dn {
+                    write!(f, ":dn")?;
+                }
+                if let Some(rule) = rule {
+                    write!(f, ":{}", rule)?;
+                }
+                write!(f, ":={})", escape(value))
+            }
+        }
+    }
+}
+
+impl From<Filter> for String {
+    fn from(filter: Filter) -> Self {
+        filter.to_string()
+    }
+}
+
+impl From<&Filter> for String {
+    fn from(filter: &Filter) -> Self {
+        filter.to_string()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = MorayError;
+
+    /// Parse an RFC 4515 filter string.  A filter consisting of a single item
+    /// may leave off the enclosing parentheses, e.g. "type=object".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let input = if s.starts_with('(') {
+            s.to_string()
+        } else {
+            format!("({})", s)
+        };
+
+        let mut parser = Parser {
+            input: &input,
+            pos: 0,
+        };
+        let filter = parser.parse_filter()?;
+        if parser.pos != input.len() {
+            return parser.error("unexpected characters after the filter");
+        }
+
+        Ok(filter)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, MorayError> {
+        Err(MorayError::InvalidFilter(format!(
+            "{} at offset {} of \"{}\"",
+            message, self.pos, self.input
+        )))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), MorayError> {
+        if self.peek() != Some(c) {
+            return self.error(&format!("expected '{}'", c as char));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_filter(&mut self) -> Result<Filter, MorayError> {
+        self.expect(b'(')?;
+
+        let filter = match self.peek() {
+            Some(b'&') => {
+                self.pos += 1;
+                Filter::And(self.parse_list()?)
+            }
+            Some(b'|') => {
+                self.pos += 1;
+                Filter::Or(self.parse_list()?)
+            }
+            Some(b'!') => {
+                self.pos += 1;
+                Filter::not(self.parse_filter()?)
+            }
+            _ => self.parse_item()?,
+        };
+
+        self.expect(b')')?;
+        Ok(filter)
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<Filter>, MorayError> {
+        let mut filters = vec![];
+        while self.peek() == Some(b'(') {
+            filters.push(self.parse_filter()?);
+        }
+        Ok(filters)
+    }
+
+    // Values can't contain an unescaped ')', so an item runs up to the next
+    // one.
+    fn parse_item(&mut self) -> Result<Filter, MorayError> {
+        let len = match self.input[self.pos..].find(')') {
+            Some(len) => len,
+            None => return self.error("expected ')'"),
+        };
+
+        let item = &self.input[self.pos..self.pos + len];
+        match parse_item(item) {
+            Ok(filter) => {
+                self.pos += len;
+                Ok(filter)
+            }
+            Err(message) => self.error(&message),
+        }
+    }
+}
+
+fn parse_item(item: &str) -> Result<Filter, String> {
+    let op = item
+        .find(|c| c == '=' || c == '~' || c == '>' || c == '<' || c == ':')
+        .ok_or_else(|| format!("no operator in \"{}\"", item))?;
+    let (attr, rest) = item.split_at(op);
+
+    if rest.starts_with(':') {
+        return parse_extensible(attr, rest);
+    }
+
+    check_attr(attr)?;
+
+    if rest.starts_with(">=") {
+        Ok(Filter::ge(attr, unescape(&rest[2..])?))
+    } else if rest.starts_with("<=") {
+        Ok(Filter::le(attr, unescape(&rest[2..])?))
+    } else if rest.starts_with("~=") {
+        Err(String::from("approximate matches (~=) are not supported"))
+    } else if rest.starts_with('=') {
+        let value = &rest[1..];
+        if value == "*" {
+            Ok(Filter::present(attr))
+        } else if value.contains('*') {
+            parse_substring(attr, value)
+        } else {
+            Ok(Filter::eq(attr, unescape(value)?))
+        }
+    } else {
+        Err(format!("invalid operator in \"{}\"", item))
+    }
+}
+
+fn parse_substring(attr: &str, value: &str) -> Result<Filter, String> {
+    let parts: Vec<&str> = value.split('*').collect();
+    let optional = |part: &str| -> Result<Option<String>, String> {
+        if part.is_empty() {
+            Ok(None)
+        } else {
+            unescape(part).map(Some)
+        }
+    };
+
+    let mut any = vec![];
+    for part in &parts[1..parts.len() - 1] {
+        if part.is_empty() {
+            return Err(format!("empty substring in \"{}\"", value));
+        }
+        any.push(unescape(part)?);
+    }
+
+    Ok(Filter::Substring {
+        attr: attr.to_string(),
+        initial: optional(parts[0])?,
+        any,
+        last: optional(parts[parts.len() - 1])?,
+    })
+}
+
+// `rest` is everything after the attribute, e.g. ":dn:caseIgnoreMatch:=foo".
+fn parse_extensible(attr: &str, rest: &str) -> Result<Filter, String> {
+    let assign = rest
+        .find(":=")
+        .ok_or_else(|| format!("expected ':=' in \"{}{}\"", attr, rest))?;
+    let value = unescape(&rest[assign + 2..])?;
+
+    let mut parts: Vec<&str> = if assign == 0 {
+        vec![]
+    } else {
+        rest[1..assign].split(':').collect()
+    };
+
+    let dn = parts
+        .first()
+        .map(|part| part.eq_ignore_ascii_case("dn"))
+        .unwrap_or(false);
+    if dn {
+        parts.remove(0);
+    }
+
+    let rule = match parts.as_slice() {
+        [] => None,
+        [rule] if !rule.is_empty() => Some(rule.to_string()),
+        _ => return Err(format!("invalid matching rule in \"{}\"", rest)),
+    };
+
+    let attr = if attr.is_empty() {
+        if rule.is_none() {
+            return Err(String::from(
+                "an extensible match needs an attribute or a matching rule",
+            ));
+        }
+        None
+    } else {
+        check_attr(attr)?;
+        Some(attr.to_string())
+    };
+
+    Ok(Filter::Extensible {
+        attr,
+        rule,
+        dn,
+        value,
+    })
+}
+
+fn check_attr(attr: &str) -> Result<(), String> {
+    let valid = !attr.is_empty()
+        && attr
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.;".contains(c));
+
+    if !valid {
+        return Err(format!("invalid attribute \"{}\"", attr));
+    }
+    Ok(())
+}
+
+/// Reverse `escape`, decoding each `\XX` hex escape.
+fn unescape(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                let hex = bytes.get(i + 1..i + 3).unwrap_or(&[]);
+                if hex.len() != 2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(format!("invalid escape in \"{}\"", value));
+                }
+                let hex =
+                    std::str::from_utf8(hex).map_err(|e| e.to_string())?;
+                unescaped.push(
+                    u8::from_str_radix(hex, 16).map_err(|e| e.to_string())?,
+                );
+                i += 3;
+            }
+            c @ b'(' | c @ b'*' => {
+                return Err(format!(
+                    "unescaped '{}' in \"{}\"",
+                    c as char, value
+                ));
+            }
+            c => {
+                unescaped.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(unescaped)
+        .map_err(|_| format!("\"{}\" is not valid UTF-8 once unescaped", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use rand::distributions::Alphanumeric;
+    use rand::Rng;
+    use serde_json::json;
+    use std::iter;
+
+    fn random_name<G: Gen>(g: &mut G) -> String {
+        let len = g.gen_range(1, 10);
+        iter::repeat(())
+            .map(|()| g.sample(Alphanumeric))
+            .take(len)
+            .collect()
+    }
+
+    fn random_value<G: Gen>(g: &mut G) -> String {
+        let value = String::arbitrary(g);
+        if value.is_empty() {
+            random_name(g)
+        } else {
+            value
+        }
+    }
+
+    fn random_filter<G: Gen>(g: &mut G, depth: u32) -> Filter {
+        let max = if depth < 3 { 9 } else { 6 };
+        match g.gen_range(0, max) {
+            0 => Filter::eq(random_name(g), String::arbitrary(g)),
+            1 => Filter::ge(random_name(g), String::arbitrary(g)),
+            2 => Filter::le(random_name(g), String::arbitrary(g)),
+            3 => Filter::present(random_name(g)),
+            4 => Filter::Substring {
+                attr: random_name(g),
+                initial: Some(random_value(g)),
+                any: (0..g.gen_range(0, 3)).map(|_| random_value(g)).collect(),
+                last: if g.gen() { Some(random_value(g)) } else { None },
+            },
+            5 => Filter::Extensible {
+                attr: if g.gen() { Some(random_name(g)) } else { None },
+                rule: Some(format!("{}Match", random_name(g))),
+                dn: g.gen(),
+                value: String::arbitrary(g),
+            },
+            6 => Filter::not(random_filter(g, depth + 1)),
+            7 => Filter::and(
+                (0..g.gen_range(0, 4)).map(|_| random_filter(g, depth + 1)),
+            ),
+            _ => Filter::or(
+                (0..g.gen_range(0, 4)).map(|_| random_filter(g, depth + 1)),
+            ),
+        }
+    }
+
+    impl Arbitrary for Filter {
+        fn arbitrary<G: Gen>(g: &mut G) -> Filter {
+            random_filter(g, 0)
+        }
+    }
+
+    fn bucket() -> Bucket {
+        serde_json::from_value(json!({
+            "name": "manta",
+            "index": {
+                "owner": { "type": "string" },
+                "size": { "type": "number" },
+                "sizes": { "type": "[number]" },
+            },
+            "mtime": "2020-01-01T00:00:00.000Z",
+            "options": { "version": 1 },
+            "post": [],
+            "pre": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn render_test() {
+        let cases = vec![
+            (Filter::eq("type", "object"), "(type=object)"),
+            (Filter::ge("_mtime", "100"), "(_mtime>=100)"),
+            (Filter::le("size", "5"), "(size<=5)"),
+            (Filter::present("owner"), "(owner=*)"),
+            (Filter::not(Filter::eq("a", "b")), "(!(a=b))"),
+            (
+                Filter::or(vec![Filter::eq("a", "1"), Filter::eq("b", "2")]),
+                "(|(a=1)(b=2))",
+            ),
+            (
+                Filter::and(vec![
+                    Filter::eq("a", "1"),
+                    Filter::not(Filter::present("b")),
+                ]),
+                "(&(a=1)(!(b=*)))",
+            ),
+            (
+                Filter::substring("name", Some("a"), &["b", "c"], Some("d")),
+                "(name=a*b*c*d)",
+            ),
+            (Filter::substring("name", None, &[], Some("x")), "(name=*x)"),
+            (
+                Filter::ext("name", "caseIgnoreMatch", "Foo"),
+                "(name:caseIgnoreMatch:=Foo)",
+            ),
+            (
+                Filter::Extensible {
+                    attr: None,
+                    rule: Some(String::from("2.4.8.10")),
+                    dn: true,
+                    value: String::from("x"),
+                },
+                "(:dn:2.4.8.10:=x)",
+            ),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(filter.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape(r"a*(b)\c"), r"a\2a\28b\29\5cc");
+        assert_eq!(escape("nul\0"), r"nul\00");
+        assert_eq!(escape("ünïcode"), "ünïcode");
+
+        let filter = Filter::substring("name", Some("*"), &[")"], None);
+        assert_eq!(filter.to_string(), r"(name=\2a*\29*)");
+    }
+
+    #[test]
+    fn parse_test() {
+        let cases = vec![
+            ("(type=object)", Filter::eq("type", "object")),
+            ("type=object", Filter::eq("type", "object")),
+            ("(a=)", Filter::eq("a", "")),
+            (r"(name=a\28b\29\2a\5c)", Filter::eq("name", r"a(b)*\")),
+            (r"(name=\c3\bc)", Filter::eq("name", "ü")),
+            ("(_mtime>=100)", Filter::ge("_mtime", "100")),
+            ("(size<=5)", Filter::le("size", "5")),
+            ("(owner=*)", Filter::present("owner")),
+            ("(name=*x)", Filter::substring("name", None, &[], Some("x"))),
+            (
+                "(name=a*b*c*)",
+                Filter::substring("name", Some("a"), &["b", "c"], None),
+            ),
+            (
+                "(name:caseIgnoreMatch:=Foo)",
+                Filter::ext("name", "caseIgnoreMatch", "Foo"),
+            ),
+            (
+                "(:dn:2.4.8.10:=x)",
+                Filter::Extensible {
+                    attr: None,
+                    rule: Some(String::from("2.4.8.10")),
+                    dn: true,
+                    value: String::from("x"),
+                },
+            ),
+            ("(&)", Filter::and(vec![])),
+            (
+                " (&(a=1)(|(b=2)(!(c=*)))) ",
+                Filter::and(vec![
+                    Filter::eq("a", "1"),
+                    Filter::or(vec![
+                        Filter::eq("b", "2"),
+                        Filter::not(Filter::present("c")),
+                    ]),
+                ]),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<Filter>().unwrap(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_test() {
+        let cases = vec![
+            "",
+            "()",
+            "(a=b",
+            "(a=b))",
+            "(a=b)(c=d)",
+            "(=b)",
+            "(a b=c)",
+            "(a~=b)",
+            "(a=b(c)",
+            "(a=b**c)",
+            r"(a=\2)",
+            r"(a=\zz)",
+            r"(a=\ff)",
+            "(a:=)x",
+            "(:=x)",
+            "(a:b:c:=x)",
+            "(&(a=b)",
+            "(!(a=b)(c=d))",
+        ];
+
+        for input in cases {
+            match input.parse::<Filter>() {
+                Err(MorayError::InvalidFilter(_)) => (),
+                r => panic!("expected {} to be invalid, got {:?}", input, r),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_parse_round_trip(filter: Filter) -> bool {
+            filter.to_string().parse::<Filter>().ok() == Some(filter)
+        }
+    }
+
+    #[test]
+    fn validate_test() {
+        let bucket = bucket();
+        let valid = vec![
+            "(owner=abc)",
+            "(&(owner=a*)(size>=10)(sizes=-1.5))",
+            "(|(_mtime>=1)(_key=/a/b)(!(owner=*)))",
+            "(:caseIgnoreMatch:=whatever)",
+        ];
+        for input in valid {
+            let filter: Filter = input.parse().unwrap();
+            assert!(filter.validate(&bucket).is_ok(), "{}", input);
+        }
+
+        let invalid = vec![
+            "(name=abc)",
+            "(&(owner=abc)(name=abc))",
+            "(size=abc)",
+            "(sizes<=inf)",
+            "(_id=x)",
+            "(size=1*)",
+        ];
+        for input in invalid {
+            let filter: Filter = input.parse().unwrap();
+            match filter.validate(&bucket) {
+                Err(MorayError::InvalidFilter(_)) => (),
+                r => panic!("expected {} to be invalid, got {:?}", input, r),
+            }
+        }
+    }
+}
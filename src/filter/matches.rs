@@ -0,0 +1,185 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Evaluation of filters against objects, for the in-memory store of the
+//! testing module.
+
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use super::{Filter, INTERNAL_FIELDS};
+
+impl Filter {
+    /// Evaluate the filter against a moray object, as moray does once it has
+    /// fetched candidate rows.  Values are compared according to their JSON
+    /// type, and an array field matches if any of its elements does.
+    pub(crate) fn matches(&self, object: &Value) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(object)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(object)),
+            Filter::Not(filter) => !filter.matches(object),
+            Filter::Present { attr } => field(object, attr).is_some(),
+            Filter::Equal { attr, value } => any_element(object, attr, |v| {
+                compare(v, value) == Some(Ordering::Equal)
+            }),
+            Filter::GreaterOrEqual { attr, value } => {
+                any_element(object, attr, |v| {
+                    compare(v, value).map_or(false, |o| o != Ordering::Less)
+                })
+            }
+            Filter::LessOrEqual { attr, value } => {
+                any_element(object, attr, |v| {
+                    compare(v, value).map_or(false, |o| o != Ordering::Greater)
+                })
+            }
+            Filter::Substring {
+                attr,
+                initial,
+                any,
+                last,
+            } => any_element(object, attr, |v| {
+                v.as_str().map_or(false, |s| {
+                    substring_matches(s, initial.as_ref(), any, last.as_ref())
+                })
+            }),
+            Filter::Extensible {
+                attr: Some(attr),
+                rule,
+                value,
+                ..
+            } => any_element(object, attr, |v| match rule.as_ref() {
+                Some(rule) if rule == "caseIgnoreMatch" => {
+                    v.as_str().map_or(false, |s| s.eq_ignore_ascii_case(value))
+                }
+                _ => compare(v, value) == Some(Ordering::Equal),
+            }),
+            Filter::Extensible { attr: None, .. } => false,
+        }
+    }
+}
+
+// The value of `attr` in a moray object.  Internal fields are properties of
+// the object itself, everything else is part of its value.
+fn field<'a>(object: &'a Value, attr: &str) -> Option<&'a Value> {
+    let value = match attr {
+        "_key" => object.get("key"),
+        _ if INTERNAL_FIELDS.iter().any(|(name, _)| *name == attr) => {
+            object.get(attr)
+        }
+        _ => object.get("value").and_then(|value| value.get(attr)),
+    };
+
+    value.filter(|v| !v.is_null())
+}
+
+fn any_element<F>(object: &Value, attr: &str, f: F) -> bool
+where
+    F: Fn(&Value) -> bool,
+{
+    match field(object, attr) {
+        Some(Value::Array(elements)) => elements.iter().any(f),
+        Some(value) => f(value),
+        None => false,
+    }
+}
+
+// Compare a field's value with the value in a filter, which is interpreted
+// according to the type of the field.
+fn compare(field: &Value, value: &str) -> Option<Ordering> {
+    match field {
+        Value::String(s) => Some(s.as_str().cmp(value)),
+        Value::Number(n) => {
+            n.as_f64()?.partial_cmp(&value.parse::<f64>().ok()?)
+        }
+        Value::Bool(b) => Some(b.cmp(&value.parse::<bool>().ok()?)),
+        _ => None,
+    }
+}
+
+fn substring_matches(
+    s: &str,
+    initial: Option<&String>,
+    any: &[String],
+    last: Option<&String>,
+) -> bool {
+    let mut rest = s;
+
+    if let Some(initial) = initial {
+        if !rest.starts_with(initial.as_str()) {
+            return false;
+        }
+        rest = &rest[initial.len()..];
+    }
+
+    for part in any {
+        match rest.find(part.as_str()) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    last.map_or(true, |last| rest.ends_with(last.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn matches_test() {
+        let object = json!({
+            "bucket": "manta",
+            "key": "/a/b",
+            "value": {
+                "owner": "Abc",
+                "size": 10,
+                "sizes": [1, 20],
+                "deleted": false,
+                "nothing": null
+            },
+            "_id": 3,
+            "_etag": "ABCD",
+            "_mtime": 1000
+        });
+
+        let matching = vec![
+            "(owner=Abc)",
+            "(size=10.0)",
+            "(&(size>=10)(size<=10))",
+            "(sizes=20)",
+            "(sizes>=15)",
+            "(deleted=false)",
+            "(owner=*)",
+            "(owner=A*c)",
+            "(owner=*b*)",
+            "(owner:caseIgnoreMatch:=abc)",
+            "(_key=/a/b)",
+            "(_id>=3)",
+            "(|(owner=x)(_etag=ABCD))",
+            "(!(nothing=*))",
+        ];
+        for input in matching {
+            let filter: Filter = input.parse().unwrap();
+            assert!(filter.matches(&object), "{}", input);
+        }
+
+        let not_matching = vec![
+            "(owner=abc)",
+            "(size>=11)",
+            "(size=abc)",
+            "(sizes=2)",
+            "(name=*)",
+            "(nothing=*)",
+            "(owner=*bc*c)",
+            "(_mtime<=999)",
+            "(!(owner=Abc))",
+            "(:caseIgnoreMatch:=abc)",
+        ];
+        for input in not_matching {
+            let filter: Filter = input.parse().unwrap();
+            assert!(!filter.matches(&object), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Translation of filters to the SQL moray runs for them, for the backends
+//! that stand in for moray.
+
+use serde_json::Value;
+
+use super::{index_type, Filter};
+use crate::buckets::{Bucket, IndexType};
+use crate::error::MorayError;
+use crate::objects::{Sort, SortOrder};
+
+impl Filter {
+    /// Translate the filter into a SQL condition on a bucket's table, in which
+    /// moray's internal fields and each indexed field are columns.  The values
+    /// in the filter become numbered parameters, following those already in
+    /// `params`, and are appended to it as JSON values of the field's type.
+    /// The filter should have been validated against `bucket`.
+    pub(crate) fn to_sql(
+        &self,
+        bucket: &Bucket,
+        dialect: Dialect,
+        params: &mut Vec<Value>,
+    ) -> Result<String, MorayError> {
+        let sql = match self {
+            Filter::And(filters) => {
+                join_sql(filters, " AND ", "1 = 1", bucket, dialect, params)?
+            }
+            Filter::Or(filters) => {
+                join_sql(filters, " OR ", "1 = 0", bucket, dialect, params)?
+            }
+            Filter::Not(filter) => {
+                format!("NOT {}", filter.to_sql(bucket, dialect, params)?)
+            }
+            Filter::Present { attr } => {
+                index_type(bucket, attr)?;
+                format!("{} IS NOT NULL", quote_ident(attr))
+            }
+            Filter::Equal { attr, value } => {
+                let param = sql_param(bucket, attr, value, dialect, params)?;
+                element_sql(bucket, attr, dialect, |e| {
+                    format!("{} = {}", e, param)
+                })?
+            }
+            Filter::GreaterOrEqual { attr, value } => {
+                let param = sql_param(bucket, attr, value, dialect, params)?;
+                element_sql(bucket, attr, dialect, |e| {
+                    format!("{} >= {}", e, param)
+                })?
+            }
+            Filter::LessOrEqual { attr, value } => {
+                let param = sql_param(bucket, attr, value, dialect, params)?;
+                element_sql(bucket, attr, dialect, |e| {
+                    format!("{} <= {}", e, param)
+                })?
+            }
+            Filter::Substring {
+                attr,
+                initial,
+                any,
+                last,
+            } => {
+                let mut pattern = initial
+                    .as_ref()
+                    .map_or_else(String::new, |s| like_escape(s));
+                for part in any {
+                    pattern.push('%');
+                    pattern.push_str(&like_escape(part));
+                }
+                pattern.push('%');
+                if let Some(last) = last {
+                    pattern.push_str(&like_escape(last));
+                }
+
+                params.push(Value::String(pattern));
+                let param =
+                    dialect.placeholder(params.len(), &IndexType::String);
+                element_sql(bucket, attr, dialect, |e| {
+                    format!("{} LIKE {} ESCAPE '\\'", e, param)
+                })?
+            }
+            Filter::Extensible {
+                attr: Some(attr),
+                rule,
+                value,
+                ..
+            } => {
+                let param = sql_param(bucket, attr, value, dialect, params)?;
+                match rule.as_ref() {
+                    Some(rule) if rule == "caseIgnoreMatch" => {
+                        element_sql(bucket, attr, dialect, |e| {
+                            format!("lower({}) = lower({})", e, param)
+                        })?
+                    }
+                    _ => element_sql(bucket, attr, dialect, |e| {
+                        format!("{} = {}", e, param)
+                    })?,
+                }
+            }
+            Filter::Extensible { attr: None, .. } => String::from("1 = 0"),
+        };
+
+        Ok(format!("({})", sql))
+    }
+}
+
+fn join_sql(
+    filters: &[Filter],
+    separator: &str,
+    empty: &str,
+    bucket: &Bucket,
+    dialect: Dialect,
+    params: &mut Vec<Value>,
+) -> Result<String, MorayError> {
+    if filters.is_empty() {
+        return Ok(empty.to_string());
+    }
+
+    let conditions = filters
+        .iter()
+        .map(|f| f.to_sql(bucket, dialect, params))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(conditions.join(separator))
+}
+
+// A condition on the column for `attr` or, if it is an array field, on any of
+// the elements of the array.  A missing value never matches, so that negating
+// the condition matches it instead, as `matches` does.
+fn element_sql<F>(
+    bucket: &Bucket,
+    attr: &str,
+    dialect: Dialect,
+    condition: F,
+) -> Result<String, MorayError>
+where
+    F: FnOnce(&str) -> String,
+{
+    let column = quote_ident(attr);
+
+    if !index_type(bucket, attr)?.is_array() {
+        return Ok(format!(
+            "{} IS NOT NULL AND {}",
+            column,
+            condition(&column)
+        ));
+    }
+
+    Ok(match dialect {
+        Dialect::Sqlite => format!(
+            "EXISTS (SELECT 1 FROM json_each({}) WHERE {})",
+            column,
+            condition("json_each.value")
+        ),
+        Dialect::Postgres => format!(
+            "EXISTS (SELECT 1 FROM unnest({}) AS element WHERE {})",
+            column,
+            condition("element")
+        ),
+    })
+}
+
+// Add `value` to `params` as the type of the field it is compared with, and
+// return its placeholder.
+fn sql_param(
+    bucket: &Bucket,
+    attr: &str,
+    value: &str,
+    dialect: Dialect,
+    params: &mut Vec<Value>,
+) -> Result<String, MorayError> {
+    let element_type = index_type(bucket, attr)?.element_type();
+    let param = match element_type {
+        IndexType::Number => value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| {
+                MorayError::InvalidFilter(format!(
+                    "\"{}\" is not a number",
+                    value
+                ))
+            })?,
+        IndexType::Boolean => value
+            .parse::<bool>()
+            .map_or_else(|_| Value::from(value), Value::Bool),
+        _ => Value::from(value),
+    };
+
+    params.push(param);
+    Ok(dialect.placeholder(params.len(), &element_type))
+}
+
+// Escape the LIKE wildcards in `value`, using backslash as the escape
+// character.
+fn like_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The SQL dialects that a filter can be translated to with `to_sql`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Dialect {
+    /// Array fields hold JSON arrays as text.  Parameters are written `?1`,
+    /// `?2`, ..., since SQLite takes `$1` as a name and numbers named
+    /// parameters in the order they first appear.
+    Sqlite,
+    /// Array fields are Postgres arrays.  Parameters are bound as text, and
+    /// cast to the type of the field they are compared with.
+    Postgres,
+}
+
+impl Dialect {
+    fn placeholder(self, n: usize, index_type: &IndexType) -> String {
+        match self {
+            Dialect::Sqlite => format!("?{}", n),
+            Dialect::Postgres => match index_type {
+                IndexType::String => format!("${}::text", n),
+                t => format!("${}::text::{}", n, postgres_type(t)),
+            },
+        }
+    }
+}
+
+/// The Postgres type of the column moray creates for an index of this type.
+pub(crate) fn postgres_type(index_type: &IndexType) -> String {
+    let element_type = match index_type.element_type() {
+        IndexType::Number => "numeric",
+        IndexType::Boolean => "boolean",
+        IndexType::Ip => "inet",
+        IndexType::Subnet => "cidr",
+        _ => "text",
+    };
+
+    if index_type.is_array() {
+        format!("{}[]", element_type)
+    } else {
+        element_type.to_string()
+    }
+}
+
+/// Quote a table or column name for use in SQL.
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The ORDER BY clause for `sort` on the bucket's table, with `_id` breaking
+/// ties.  As in a filter, only indexed and internal fields can be used.
+pub(crate) fn order_by(
+    bucket: &Bucket,
+    sort: Option<&Sort>,
+) -> Result<String, MorayError> {
+    let sort = match sort {
+        Some(sort) => sort,
+        None => return Ok(String::from("_id")),
+    };
+    index_type(bucket, &sort.attribute)?;
+
+    let order = match sort.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    Ok(format!(
+        "{} {}, _id {}",
+        quote_ident(&sort.attribute),
+        order,
+        order
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bucket() -> Bucket {
+        serde_json::from_value(json!({
+            "name": "manta",
+            "index": {
+                "owner": { "type": "string" },
+                "size": { "type": "number" },
+                "sizes": { "type": "[number]" },
+            },
+            "mtime": "2020-01-01T00:00:00.000Z",
+            "options": { "version": 1 },
+            "post": [],
+            "pre": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn to_sql_test() {
+        let cases = vec![
+            (
+                "(&(owner=abc)(size>=10))",
+                concat!(
+                    r#"(("owner" IS NOT NULL AND "owner" = ?1) AND "#,
+                    r#"("size" IS NOT NULL AND "size" >= ?2))"#,
+                ),
+                vec![json!("abc"), json!(10.0)],
+            ),
+            (
+                "(|(sizes<=2)(!(_key=/a)))",
+                concat!(
+                    r#"((EXISTS (SELECT 1 FROM json_each("sizes") "#,
+                    r#"WHERE json_each.value <= ?1)) OR "#,
+                    r#"(NOT ("_key" IS NOT NULL AND "_key" = ?2)))"#,
+                ),
+                vec![json!(2.0), json!("/a")],
+            ),
+            (
+                "(owner=a%*b)",
+                r#"("owner" IS NOT NULL AND "owner" LIKE ?1 ESCAPE '\')"#,
+                vec![json!("a\\%%b")],
+            ),
+            (
+                "(owner:caseIgnoreMatch:=Abc)",
+                r#"("owner" IS NOT NULL AND lower("owner") = lower(?1))"#,
+                vec![json!("Abc")],
+            ),
+            ("(owner=*)", r#"("owner" IS NOT NULL)"#, vec![]),
+        ];
+
+        for (input, sql, expected) in cases {
+            let filter: Filter = input.parse().unwrap();
+            let mut params = vec![];
+            let translated =
+                filter.to_sql(&bucket(), Dialect::Sqlite, &mut params);
+            assert_eq!(translated.unwrap(), sql);
+            assert_eq!(params, expected, "{}", input);
+        }
+
+        let filter: Filter = "(|(sizes<=2)(owner=a*))".parse().unwrap();
+        let mut params = vec![];
+        assert_eq!(
+            filter
+                .to_sql(&bucket(), Dialect::Postgres, &mut params)
+                .unwrap(),
+            concat!(
+                r#"((EXISTS (SELECT 1 FROM unnest("sizes") AS element "#,
+                r#"WHERE element <= $1::text::numeric)) OR "#,
+                r#"("owner" IS NOT NULL AND "owner" LIKE $2::text "#,
+                r#"ESCAPE '\'))"#,
+            )
+        );
+        assert_eq!(params, vec![json!(2.0), json!("a%")]);
+
+        let filter: Filter = "(name=x)".parse().unwrap();
+        assert!(filter
+            .to_sql(&bucket(), Dialect::Sqlite, &mut vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn order_by_test() {
+        assert_eq!(order_by(&bucket(), None).unwrap(), "_id");
+
+        let sort = Sort::new("size", SortOrder::Desc);
+        assert_eq!(
+            order_by(&bucket(), Some(&sort)).unwrap(),
+            r#""size" DESC, _id DESC"#
+        );
+
+        let sort = Sort::new("name", SortOrder::Asc);
+        assert!(order_by(&bucket(), Some(&sort)).is_err());
+    }
+}
@@ -2,7 +2,26 @@
  * Copyright 2019 Joyent, Inc.
  */
 
+pub mod api;
+pub mod async_client;
 pub mod buckets;
 pub mod client;
+pub mod error;
+mod fast;
+pub mod filter;
+#[cfg(feature = "sqlite")]
+pub mod local;
 pub mod meta;
+pub mod multiplex;
 pub mod objects;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod resolver;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod typed;
+
+pub use typed::MorayBucket;
+
+#[cfg(feature = "derive")]
+pub use moray_derive::MorayBucket;
@@ -0,0 +1,1101 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! `LocalMoray`, a `MorayApi` implemented directly on a SQLite database, for
+//! running code that uses moray on a machine without moray or Postgres.
+//!
+//! The database is laid out the way moray lays out Postgres.  The
+//! `buckets_config` table holds the schema of each bucket, and each bucket is
+//! a table with moray's internal columns (`_id`, `_key`, `_value`, `_etag`,
+//! `_mtime`, ...) and a column for each indexed field.  Filters are translated
+//! to SQL on those columns, so as with moray they may only reference indexed
+//! fields.
+//!
+//! ```
+//! use moray::api::MorayApi;
+//! use moray::buckets::{self, BucketConfig, IndexDef, IndexType};
+//! use moray::local::LocalMoray;
+//! use moray::objects::MethodOptions;
+//! use serde_json::json;
+//!
+//! let mut moray = LocalMoray::open_in_memory().unwrap();
+//! let config = BucketConfig::new()
+//!     .with_index("owner", IndexDef::new(IndexType::String));
+//! moray
+//!     .create_bucket("manta", &config, buckets::MethodOptions::default())
+//!     .unwrap();
+//!
+//! let opts = MethodOptions::default();
+//! let value = json!({ "owner": "abc" });
+//! moray
+//!     .put_object("manta", "/abc/stor/a", value, &opts, &mut |_| Ok(()))
+//!     .unwrap();
+//! let mut found = vec![];
+//! moray
+//!     .find_objects("manta", "(owner=abc)", &opts, &mut |o| {
+//!         found.push(o.key.clone());
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//! assert_eq!(found, vec!["/abc/stor/a"]);
+//! ```
+//!
+//! Unlike moray, `update_bucket` populates the columns of any new indexes
+//! straight away, so `reindex_objects` never has anything to do.  Pre and post
+//! triggers are ignored, and `sql` statements are run by SQLite.
+
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, OptionalExtension, Row, NO_PARAMS};
+use serde_json::{json, Value};
+use std::io::Error;
+use std::path::Path;
+
+use super::api::MorayApi;
+use super::buckets::{
+    self, now_iso, Bucket, BucketConfig, IndexDef, IndexType,
+};
+use super::error::{MorayError, ServerError};
+use super::filter::{order_by, quote_ident, Dialect, Filter};
+use super::objects::{
+    self, etag, now_millis, BatchRequest, BatchResult, MethodOptions,
+    MorayObject, ReindexObjectsReturn, UpdateObjectsReturn,
+};
+
+const BUCKET_COLUMNS: &str = "name, \"index\", options, pre, post, mtime";
+const OBJECT_COLUMNS: &str = "_id, _key, _value, _etag, _mtime, _txn_snap";
+
+pub struct LocalMoray {
+    conn: Connection,
+}
+
+impl LocalMoray {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, MorayError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Open a database that only lasts as long as the `LocalMoray`.
+    pub fn open_in_memory() -> Result<Self, MorayError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, MorayError> {
+        // Substring filters become LIKEs, which must be case sensitive.
+        conn.execute_batch(
+            "PRAGMA case_sensitive_like = ON;
+             CREATE TABLE IF NOT EXISTS buckets_config (
+                 name TEXT PRIMARY KEY,
+                 \"index\" TEXT NOT NULL,
+                 options TEXT NOT NULL,
+                 pre TEXT NOT NULL,
+                 post TEXT NOT NULL,
+                 mtime TEXT NOT NULL
+             );",
+        )?;
+        Ok(LocalMoray { conn })
+    }
+}
+
+// Buckets are stored the way moray sends them, with each field other than the
+// name and mtime encoded as a JSON string.
+fn bucket_row(row: &Row) -> rusqlite::Result<Value> {
+    Ok(json!({
+        "name": row.get::<_, String>(0)?,
+        "index": row.get::<_, String>(1)?,
+        "options": row.get::<_, String>(2)?,
+        "pre": row.get::<_, String>(3)?,
+        "post": row.get::<_, String>(4)?,
+        "mtime": row.get::<_, String>(5)?,
+    }))
+}
+
+fn decode_bucket(wire: Value) -> Result<Bucket, MorayError> {
+    let mut bucket = None;
+    buckets::decode_bucket(&json!([wire]), |b| {
+        bucket = Some(b);
+        Ok(())
+    })?;
+    bucket.ok_or_else(|| MorayError::Protocol(String::from("empty bucket")))
+}
+
+fn find_bucket(
+    conn: &Connection,
+    name: &str,
+) -> Result<Option<Bucket>, MorayError> {
+    let sql = format!(
+        "SELECT {} FROM buckets_config WHERE name = ?1",
+        BUCKET_COLUMNS
+    );
+    conn.query_row(&sql, &[name], bucket_row)
+        .optional()?
+        .map(decode_bucket)
+        .transpose()
+}
+
+fn get_bucket(conn: &Connection, name: &str) -> Result<Bucket, MorayError> {
+    find_bucket(conn, name)?.ok_or_else(|| buckets::bucket_not_found(name))
+}
+
+fn save_bucket(conn: &Connection, bucket: &Bucket) -> Result<(), MorayError> {
+    let wire = bucket.to_wire()?;
+    let fields = ["name", "index", "options", "pre", "post", "mtime"];
+    let values: Vec<&str> =
+        fields.iter().filter_map(|f| wire[f].as_str()).collect();
+
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO buckets_config ({}) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            BUCKET_COLUMNS
+        ),
+        &values,
+    )?;
+    Ok(())
+}
+
+// Keep bucket tables clear of the names SQLite reserves.
+fn check_bucket_name(name: &str) -> Result<(), MorayError> {
+    if name.starts_with("sqlite_") {
+        return Err(buckets::invalid_bucket_name(name));
+    }
+    buckets::check_bucket_name(name)
+}
+
+// Indexed fields are stored in columns of these types, with booleans as
+// integers and arrays as JSON text.
+fn column_type(index_type: &IndexType) -> &'static str {
+    match index_type {
+        t if t.is_array() => "TEXT",
+        IndexType::Number => "NUMERIC",
+        IndexType::Boolean => "INTEGER",
+        _ => "TEXT",
+    }
+}
+
+fn index_name(bucket: &str, field: &str) -> String {
+    quote_ident(&format!("{}_{}_idx", bucket, field))
+}
+
+// Add a column for each indexed field that doesn't already have one.  Columns
+// of fields that are no longer indexed are left in place.
+fn add_columns(conn: &Connection, bucket: &Bucket) -> Result<(), MorayError> {
+    let table = quote_ident(bucket.name());
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let existing = stmt
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    for (field, def) in bucket.index() {
+        if !existing.contains(field) {
+            conn.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    table,
+                    quote_ident(field),
+                    column_type(&def.index_type)
+                ),
+                NO_PARAMS,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// SQLite can't index the elements of a JSON array, so array fields are left
+// unindexed, and can't be unique.
+fn create_indexes(
+    conn: &Connection,
+    bucket: &Bucket,
+) -> Result<(), MorayError> {
+    for (field, def) in bucket.index() {
+        if def.index_type.is_array() {
+            continue;
+        }
+
+        let kind = if def.unique { "UNIQUE INDEX" } else { "INDEX" };
+        conn.execute(
+            &format!(
+                "CREATE {} {} ON {} ({})",
+                kind,
+                index_name(bucket.name(), field),
+                quote_ident(bucket.name()),
+                quote_ident(field)
+            ),
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+fn drop_indexes(conn: &Connection, bucket: &Bucket) -> Result<(), MorayError> {
+    for field in bucket.index().keys() {
+        conn.execute(
+            &format!(
+                "DROP INDEX IF EXISTS {}",
+                index_name(bucket.name(), field)
+            ),
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+// The SQLite form of a JSON value.
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        _ => SqlValue::Text(value.to_string()),
+    }
+}
+
+fn json_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => json!(i),
+        SqlValue::Real(f) => json!(f),
+        SqlValue::Text(s) => Value::String(s),
+        SqlValue::Blob(b) => json!(b),
+    }
+}
+
+// The value stored in the column of an indexed field.  A single value in an
+// array field is stored as an array of one element.
+fn index_value(def: &IndexDef, value: &Value) -> Value {
+    if def.index_type.is_array() && !value.is_array() && !value.is_null() {
+        json!([value])
+    } else {
+        value.clone()
+    }
+}
+
+// The value of each of the bucket's indexed fields in an object's value, in
+// the order of `bucket.index()`.
+fn index_values(bucket: &Bucket, value: &Value) -> Vec<SqlValue> {
+    bucket
+        .index()
+        .iter()
+        .map(|(field, def)| {
+            value
+                .get(field)
+                .map_or(SqlValue::Null, |v| sql_value(&index_value(def, v)))
+        })
+        .collect()
+}
+
+// Set the index columns of every object in the bucket from its value.
+fn reindex(conn: &Connection, bucket: &Bucket) -> Result<(), MorayError> {
+    if bucket.index().is_empty() {
+        return Ok(());
+    }
+
+    let table = quote_ident(bucket.name());
+    let mut stmt =
+        conn.prepare(&format!("SELECT _id, _value FROM {}", table))?;
+    let rows = stmt
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    let assignments: Vec<String> = bucket
+        .index()
+        .keys()
+        .enumerate()
+        .map(|(i, field)| format!("{} = ?{}", quote_ident(field), i + 1))
+        .collect();
+    let sql = format!(
+        "UPDATE {} SET {} WHERE _id = ?{}",
+        table,
+        assignments.join(", "),
+        assignments.len() + 1
+    );
+
+    for (id, value) in rows {
+        let value = decode_value(bucket.name(), &value)?;
+        let mut params = index_values(bucket, &value);
+        params.push(SqlValue::Integer(id));
+        conn.execute(&sql, &params)?;
+    }
+    Ok(())
+}
+
+// A row of a bucket's table, as selected by OBJECT_COLUMNS.
+struct ObjectRow {
+    id: i64,
+    key: String,
+    value: String,
+    etag: String,
+    mtime: i64,
+    txn_snap: Option<i64>,
+}
+
+impl ObjectRow {
+    fn read(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ObjectRow {
+            id: row.get(0)?,
+            key: row.get(1)?,
+            value: row.get(2)?,
+            etag: row.get(3)?,
+            mtime: row.get(4)?,
+            txn_snap: row.get(5)?,
+        })
+    }
+
+    fn into_object(
+        self,
+        bucket: &str,
+        count: u64,
+    ) -> Result<MorayObject, MorayError> {
+        Ok(MorayObject {
+            bucket: bucket.to_string(),
+            _count: count,
+            _etag: self.etag,
+            _id: self.id as u64,
+            _mtime: self.mtime as u64,
+            _txn_snap: self.txn_snap.map(|snap| snap as u64),
+            value: decode_value(bucket, &self.value)?,
+            key: self.key,
+        })
+    }
+}
+
+fn decode_value(bucket: &str, value: &str) -> Result<Value, MorayError> {
+    serde_json::from_str(value)
+        .map_err(|e| MorayError::decode(Some(bucket), "value", e))
+}
+
+// Filters and sorts that can't be run on the bucket are reported as moray
+// reports them.
+fn invalid_query(error: MorayError) -> MorayError {
+    MorayError::InvalidQuery(ServerError::new(
+        "InvalidQueryError",
+        error.to_string(),
+    ))
+}
+
+// The SQL condition for `filter` on the bucket's table, and the parameters of
+// the statement, with those of the condition following `params`, which are
+// those numbered before the condition.
+fn where_clause(
+    bucket: &Bucket,
+    filter: &str,
+    mut params: Vec<Value>,
+) -> Result<(String, Vec<SqlValue>), MorayError> {
+    let filter: Filter = filter
+        .parse()
+        .and_then(|f: Filter| f.validate(bucket).map(|_| f))
+        .map_err(invalid_query)?;
+
+    let clause = filter
+        .to_sql(bucket, Dialect::Sqlite, &mut params)
+        .map_err(invalid_query)?;
+    Ok((clause, params.iter().map(sql_value).collect()))
+}
+
+fn find_rows(
+    conn: &Connection,
+    bucket: &Bucket,
+    filter: &str,
+    opts: &MethodOptions,
+) -> Result<Vec<(ObjectRow, u64)>, MorayError> {
+    let (clause, params) = where_clause(bucket, filter, vec![])?;
+    let order = order_by(bucket, opts.sort()).map_err(invalid_query)?;
+    let mut sql = format!(
+        "SELECT {}, COUNT(*) OVER () FROM {} WHERE {} ORDER BY {}",
+        OBJECT_COLUMNS,
+        quote_ident(bucket.name()),
+        clause,
+        order
+    );
+    // SQLite only takes an offset after a limit, where -1 means no limit.
+    match (opts.limit(), opts.offset()) {
+        (Some(limit), None) => sql.push_str(&format!(" LIMIT {}", limit)),
+        (limit, Some(offset)) => sql.push_str(&format!(
+            " LIMIT {} OFFSET {}",
+            limit.map_or(-1, |limit| limit as i64),
+            offset
+        )),
+        (None, None) => (),
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map(&params, |row| {
+            Ok((ObjectRow::read(row)?, row.get::<_, i64>(6)? as u64))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+fn find_row(
+    conn: &Connection,
+    bucket: &Bucket,
+    key: &str,
+) -> Result<Option<ObjectRow>, MorayError> {
+    let sql = format!(
+        "SELECT {} FROM {} WHERE _key = ?1",
+        OBJECT_COLUMNS,
+        quote_ident(bucket.name())
+    );
+    Ok(conn.query_row(&sql, &[key], ObjectRow::read).optional()?)
+}
+
+// Insert the object, or update the row with the given `_id`.
+fn write_object(
+    conn: &Connection,
+    bucket: &Bucket,
+    id: Option<i64>,
+    key: &str,
+    value: &Value,
+    etag: &str,
+) -> Result<(), MorayError> {
+    let columns: Vec<String> = ["_key", "_value", "_etag", "_mtime"]
+        .iter()
+        .map(|column| column.to_string())
+        .chain(bucket.index().keys().map(|field| quote_ident(field)))
+        .collect();
+    let mut params = vec![
+        SqlValue::Text(key.to_string()),
+        SqlValue::Text(value.to_string()),
+        SqlValue::Text(etag.to_string()),
+        SqlValue::Integer(now_millis() as i64),
+    ];
+    params.extend(index_values(bucket, value));
+
+    let table = quote_ident(bucket.name());
+    let sql = match id {
+        Some(id) => {
+            let assignments: Vec<String> = columns
+                .iter()
+                .enumerate()
+                .map(|(i, column)| format!("{} = ?{}", column, i + 1))
+                .collect();
+            params.push(SqlValue::Integer(id));
+            format!(
+                "UPDATE {} SET {} WHERE _id = ?{}",
+                table,
+                assignments.join(", "),
+                params.len()
+            )
+        }
+        None => {
+            let placeholders: Vec<String> =
+                (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+            format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                table,
+                columns.join(", "),
+                placeholders.join(", ")
+            )
+        }
+    };
+
+    conn.execute(&sql, &params)?;
+    Ok(())
+}
+
+fn put_object(
+    conn: &Connection,
+    bucket: &str,
+    key: &str,
+    value: &Value,
+    opts: &MethodOptions,
+) -> Result<String, MorayError> {
+    let bucket = get_bucket(conn, bucket)?;
+    let current = find_row(conn, &bucket, key)?;
+    let actual = current.as_ref().map(|row| row.etag.as_str());
+    objects::check_etag(bucket.name(), key, &opts.etag, actual)?;
+
+    let etag = etag(value);
+    write_object(conn, &bucket, current.map(|row| row.id), key, value, &etag)?;
+    Ok(etag)
+}
+
+fn delete_object(
+    conn: &Connection,
+    bucket: &str,
+    key: &str,
+    opts: &MethodOptions,
+) -> Result<(), MorayError> {
+    let bucket = get_bucket(conn, bucket)?;
+    let current = find_row(conn, &bucket, key)?
+        .ok_or_else(|| objects::object_not_found(bucket.name(), key))?;
+    let actual = Some(current.etag.as_str());
+    objects::check_etag(bucket.name(), key, &opts.etag, actual)?;
+
+    conn.execute(
+        &format!("DELETE FROM {} WHERE _id = ?1", quote_ident(bucket.name())),
+        &[current.id],
+    )?;
+    Ok(())
+}
+
+fn update_objects(
+    conn: &Connection,
+    bucket: &str,
+    fields: &Value,
+    filter: &str,
+) -> Result<UpdateObjectsReturn, MorayError> {
+    let bucket = get_bucket(conn, bucket)?;
+    let fields =
+        objects::update_fields(&bucket, fields).map_err(MorayError::Server)?;
+
+    // As with moray, only the index columns and etag are updated, and every
+    // object updated by the call gets the same etag.
+    let etag = objects::update_etag();
+    let mut assignments = vec![String::from("_etag = ?1")];
+    let mut params = vec![json!(etag)];
+    for (field, value) in fields {
+        params.push(index_value(&bucket.index()[field], value));
+        assignments.push(format!("{} = ?{}", quote_ident(field), params.len()));
+    }
+    let (clause, params) = where_clause(&bucket, filter, params)?;
+
+    let count = conn.execute(
+        &format!(
+            "UPDATE {} SET {} WHERE {}",
+            quote_ident(bucket.name()),
+            assignments.join(", "),
+            clause
+        ),
+        &params,
+    )?;
+    Ok(UpdateObjectsReturn {
+        count: count as u64,
+        etag,
+    })
+}
+
+fn delete_many(
+    conn: &Connection,
+    bucket: &str,
+    filter: &str,
+    opts: &MethodOptions,
+) -> Result<u64, MorayError> {
+    let bucket = get_bucket(conn, bucket)?;
+    let (clause, params) = where_clause(&bucket, filter, vec![])?;
+    let table = quote_ident(bucket.name());
+    let limit = opts.limit().map_or_else(String::new, |limit| {
+        format!(" ORDER BY _id LIMIT {}", limit)
+    });
+
+    let count = conn.execute(
+        &format!(
+            "DELETE FROM {} WHERE _id IN (SELECT _id FROM {} WHERE {}{})",
+            table, table, clause, limit
+        ),
+        &params,
+    )?;
+    Ok(count as u64)
+}
+
+impl MorayApi for LocalMoray {
+    fn list_buckets(
+        &mut self,
+        _opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let sql = format!(
+            "SELECT {} FROM buckets_config ORDER BY name",
+            BUCKET_COLUMNS
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt
+            .query_map(NO_PARAMS, bucket_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for wire in rows {
+            bucket_handler(&decode_bucket(wire)?)?;
+        }
+        Ok(())
+    }
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        _opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let bucket = get_bucket(&self.conn, name)?;
+        Ok(bucket_handler(&bucket)?)
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        check_bucket_name(name)?;
+
+        let txn = self.conn.transaction()?;
+        if find_bucket(&txn, name)?.is_some() {
+            return Err(MorayError::Server(ServerError::new(
+                "BucketConflictError",
+                format!("{} already exists", name),
+            )));
+        }
+
+        let bucket = Bucket::from_config(name, config.clone(), now_iso());
+        txn.execute(
+            &format!(
+                "CREATE TABLE {} (
+                     _id INTEGER PRIMARY KEY AUTOINCREMENT,
+                     _txn_snap INTEGER,
+                     _key TEXT NOT NULL UNIQUE,
+                     _value TEXT NOT NULL,
+                     _etag TEXT NOT NULL,
+                     _mtime INTEGER NOT NULL,
+                     _vnode INTEGER
+                 )",
+                quote_ident(name)
+            ),
+            NO_PARAMS,
+        )?;
+        add_columns(&txn, &bucket)?;
+        create_indexes(&txn, &bucket)?;
+        save_bucket(&txn, &bucket)?;
+
+        Ok(txn.commit()?)
+    }
+
+    /// The bucket's version must increase, as with `buckets::update_bucket`.
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let txn = self.conn.transaction()?;
+        let current = find_bucket(&txn, name)?;
+        buckets::check_bucket_update(name, current.as_ref(), config)?;
+
+        let bucket = Bucket::from_config(name, config.clone(), now_iso());
+        if let Some(current) = current {
+            drop_indexes(&txn, &current)?;
+        }
+        add_columns(&txn, &bucket)?;
+        reindex(&txn, &bucket)?;
+        create_indexes(&txn, &bucket)?;
+        save_bucket(&txn, &bucket)?;
+
+        Ok(txn.commit()?)
+    }
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let txn = self.conn.transaction()?;
+        get_bucket(&txn, name)?;
+
+        txn.execute(&format!("DROP TABLE {}", quote_ident(name)), NO_PARAMS)?;
+        txn.execute("DELETE FROM buckets_config WHERE name = ?1", &[name])?;
+
+        Ok(txn.commit()?)
+    }
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        _opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let bucket = get_bucket(&self.conn, bucket)?;
+        let object = find_row(&self.conn, &bucket, key)?
+            .ok_or_else(|| objects::object_not_found(bucket.name(), key))?
+            .into_object(bucket.name(), 0)?;
+        Ok(object_handler(&object)?)
+    }
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let bucket = get_bucket(&self.conn, bucket)?;
+        for (row, count) in find_rows(&self.conn, &bucket, filter, opts)? {
+            object_handler(&row.into_object(bucket.name(), count)?)?;
+        }
+        Ok(())
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let etag = put_object(&self.conn, bucket, key, &value, opts)?;
+        Ok(object_handler(&etag)?)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        delete_object(&self.conn, bucket, key, opts)
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        _opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError> {
+        let txn = self.conn.transaction()?;
+        let ret = update_objects(&txn, bucket, &fields, filter)?;
+        txn.commit()?;
+        Ok(ret)
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError> {
+        delete_many(&self.conn, bucket, filter, opts)
+    }
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        _count: u64,
+        _opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        get_bucket(&self.conn, bucket)?;
+        Ok(ReindexObjectsReturn {
+            processed: 0,
+            remaining: Some(0),
+        })
+    }
+
+    /// The requests are run in a transaction, so either all of them are
+    /// applied or none are.
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        _opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let txn = self.conn.transaction()?;
+        let mut results = vec![];
+
+        for req in requests {
+            let result = match req {
+                BatchRequest::Put(op) => BatchResult::Put {
+                    etag: put_object(
+                        &txn,
+                        &op.bucket,
+                        &op.key,
+                        &op.value,
+                        &op.options,
+                    )?,
+                },
+                BatchRequest::Update(op) => {
+                    let ret = update_objects(
+                        &txn, &op.bucket, &op.fields, &op.filter,
+                    )?;
+                    BatchResult::Update {
+                        count: ret.count,
+                        etag: Some(ret.etag),
+                    }
+                }
+                BatchRequest::Delete(op) => {
+                    delete_object(&txn, &op.bucket, &op.key, &op.options)?;
+                    BatchResult::Delete
+                }
+                BatchRequest::DeleteMany(op) => BatchResult::DeleteMany {
+                    count: delete_many(
+                        &txn,
+                        &op.bucket,
+                        &op.filter,
+                        &op.options,
+                    )?,
+                },
+            };
+            results.push(result);
+        }
+
+        txn.commit()?;
+        Ok(batch_handler(results)?)
+    }
+
+    /// Run a SQLite statement, passing each row it returns to the handler as
+    /// an object keyed by column name.
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        _opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut statement = self.conn.prepare(stmt)?;
+        let columns: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let mut rows = statement.query(&vals)?;
+
+        while let Some(row) = rows.next()? {
+            let mut object = serde_json::Map::new();
+            for (i, column) in columns.iter().enumerate() {
+                object.insert(column.clone(), json_value(row.get(i)?));
+            }
+            query_handler(&Value::Object(object))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{BatchDeleteOp, BatchPutOp, Etag};
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn keys(moray: &mut LocalMoray, filter: &str) -> Vec<String> {
+        let mut keys = vec![];
+        moray
+            .find_objects("b", filter, &MethodOptions::default(), &mut |o| {
+                keys.push(o.key.clone());
+                Ok(())
+            })
+            .unwrap();
+        keys
+    }
+
+    fn put(moray: &mut LocalMoray, key: &str, value: Value) {
+        moray
+            .put_object("b", key, value, &MethodOptions::default(), &mut |_| {
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn populated() -> LocalMoray {
+        let mut moray = LocalMoray::open_in_memory().unwrap();
+        let config = BucketConfig::new()
+            .with_index("name", IndexDef::unique(IndexType::String))
+            .with_index("size", IndexDef::new(IndexType::Number))
+            .with_index("tags", IndexDef::new(IndexType::StringArray))
+            .with_index("hidden", IndexDef::new(IndexType::Boolean))
+            .with_version(1);
+        moray
+            .create_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+
+        put(&mut moray, "k1", json!({ "name": "Apple", "size": 5 }));
+        put(
+            &mut moray,
+            "k2",
+            json!({ "name": "b%c", "size": 20, "tags": ["x", "y"] }),
+        );
+        put(
+            &mut moray,
+            "k3",
+            json!({ "name": "cherry", "tags": "y", "hidden": true }),
+        );
+        moray
+    }
+
+    #[test]
+    fn filters_test() {
+        let mut moray = populated();
+        let cases = vec![
+            ("(name=Apple)", vec!["k1"]),
+            ("(name=apple)", vec![]),
+            ("(name:caseIgnoreMatch:=apple)", vec!["k1"]),
+            ("(name=b%*)", vec!["k2"]),
+            ("(name=*e*r*)", vec!["k3"]),
+            ("(size>=6)", vec!["k2"]),
+            ("(size<=20.5)", vec!["k1", "k2"]),
+            ("(!(size>=6))", vec!["k1", "k3"]),
+            ("(tags=y)", vec!["k2", "k3"]),
+            ("(&(tags=*)(!(tags=x)))", vec!["k3"]),
+            ("(hidden=true)", vec!["k3"]),
+            ("(|(_key=k1)(_id>=3))", vec!["k1", "k3"]),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(keys(&mut moray, filter), expected, "{}", filter);
+        }
+
+        let opts = MethodOptions::default();
+        match moray.find_objects("b", "(color=red)", &opts, &mut |_| Ok(())) {
+            Err(MorayError::InvalidQuery(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn objects_test() {
+        let mut moray = populated();
+        let mut opts = MethodOptions::default();
+
+        let mut first = None;
+        moray
+            .get_object("b", "k2", &opts, &mut |o| {
+                first = Some(o.clone());
+                Ok(())
+            })
+            .unwrap();
+        let first = first.unwrap();
+        assert_eq!(first._id, 2);
+        assert_eq!(first.value["tags"], json!(["x", "y"]));
+
+        // Overwriting an object keeps its _id but changes its etag.
+        opts.etag = Etag::Specified(first._etag.clone());
+        put(&mut moray, "k2", json!({ "name": "b%c", "size": 21 }));
+        match moray.delete_object("b", "k2", &opts) {
+            Err(MorayError::EtagConflict(e)) => {
+                assert_eq!(e.context_str("expected"), Some(&first._etag[..]));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(keys(&mut moray, "(_id=2)"), vec!["k2"]);
+
+        opts.etag = Etag::Nulled;
+        match moray.put_object("b", "k1", json!({}), &opts, &mut |_| Ok(())) {
+            Err(MorayError::EtagConflict(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let unique = json!({ "name": "Apple" });
+        match moray.put_object("b", "k4", unique, &opts, &mut |_| Ok(())) {
+            Err(MorayError::UniqueAttribute(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let opts = MethodOptions::default();
+        let ret = moray
+            .update_objects("b", json!({ "size": 1 }), "(size<=20)", &opts)
+            .unwrap();
+        assert_eq!(ret.count, 1);
+        assert_eq!(keys(&mut moray, "(size=1)"), vec!["k1"]);
+        let mut found = vec![];
+        moray
+            .get_object("b", "k1", &opts, &mut |o| {
+                found.push(o.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found[0].value, json!({ "name": "Apple", "size": 5 }));
+        assert_eq!(found[0]._etag, ret.etag);
+        let fields = json!({ "color": "red" });
+        match moray.update_objects("b", fields, "(size<=20)", &opts) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "FieldUpdateError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut limited = MethodOptions::default();
+        limited.set_limit(1);
+        assert_eq!(moray.delete_many("b", "(name=*)", &limited).unwrap(), 1);
+        assert_eq!(keys(&mut moray, "(name=*)"), vec!["k2", "k3"]);
+    }
+
+    #[test]
+    fn batch_test() {
+        let mut moray = populated();
+        let opts = MethodOptions::default();
+
+        // The second request fails, so the first must not be applied.
+        let requests = vec![
+            BatchRequest::Put(BatchPutOp {
+                bucket: String::from("b"),
+                options: opts.clone(),
+                key: String::from("k4"),
+                value: json!({ "name": "date" }),
+            }),
+            BatchRequest::Delete(BatchDeleteOp {
+                bucket: String::from("b"),
+                options: opts.clone(),
+                key: String::from("missing"),
+            }),
+        ];
+        match moray.batch(&requests, &opts, &mut |_| Ok(())) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(keys(&mut moray, "(name=date)"), Vec::<String>::new());
+
+        let requests = &requests[..1];
+        let mut results = vec![];
+        moray
+            .batch(requests, &opts, &mut |r| {
+                results = r;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(keys(&mut moray, "(name=date)"), vec!["k4"]);
+    }
+
+    #[test]
+    fn update_bucket_test() {
+        let path = env::temp_dir().join(format!("moray-{}.db", Uuid::new_v4()));
+        let mut moray = LocalMoray::open(&path).unwrap();
+        let config = BucketConfig::new().with_version(1);
+        moray
+            .create_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+        put(&mut moray, "k1", json!({ "owner": "abc" }));
+
+        let config = config
+            .with_index("owner", IndexDef::new(IndexType::String))
+            .with_version(2);
+        moray
+            .update_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+        match moray.update_bucket(
+            "b",
+            &config,
+            buckets::MethodOptions::default(),
+        ) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "BucketVersionError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // The new index covers the existing object, and survives reopening
+        // the database.
+        drop(moray);
+        let mut moray = LocalMoray::open(&path).unwrap();
+        assert_eq!(keys(&mut moray, "(owner=abc)"), vec!["k1"]);
+
+        let mut rows = vec![];
+        moray
+            .sql("SELECT count(*) AS n FROM b", vec![], json!({}), &mut |r| {
+                rows.push(r.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows, vec![json!({ "n": 1 })]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
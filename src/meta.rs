@@ -2,11 +2,39 @@
  * Copyright 2019 Joyent, Inc.
  */
 
-use rust_fast::{client as fast_client, protocol::FastMessageId};
 use serde_json::{self, json, Value};
 use std::io::Error;
 use std::net::TcpStream;
 
+use super::error::MorayError;
+use super::fast;
+
+// The method name and arguments of a sql RPC, shared by the blocking client and
+// AsyncMorayClient.
+pub(crate) fn sql_rpc<V>(
+    stmt: &str,
+    vals: Vec<&str>,
+    opts: V,
+) -> Result<(String, Value), MorayError>
+where
+    V: Into<Value>,
+{
+    let opts_tmp: Value = opts.into();
+
+    let options = match opts_tmp {
+        Value::String(s) => serde_json::from_str(s.as_str()).map_err(|e| {
+            MorayError::InvalidInput(format!(
+                "sql options must be a JSON object: {}",
+                e
+            ))
+        })?,
+        _ => opts_tmp,
+    };
+
+    let values: Value = json!(vals);
+    Ok((String::from("sql"), json!([stmt, values, options])))
+}
+
 /// Make a raw sql query.
 ///
 /// * stmt: The SQL query statement
@@ -29,29 +57,14 @@ pub fn sql<F, V>(
     vals: Vec<&str>,
     opts: V,
     mut query_handler: F,
-) -> Result<(), Error>
+) -> Result<(), MorayError>
 where
     F: FnMut(&Value) -> Result<(), Error>,
     V: Into<Value>,
 {
-    let opts_tmp: Value = opts.into();
-
-    let options = if opts_tmp.is_string() {
-        let s: String = serde_json::from_value(opts_tmp).unwrap();
-        let v: Value = serde_json::from_str(s.as_str()).unwrap();
-        v
-    } else {
-        opts_tmp
-    };
-
-    let values: Value = json!(vals);
-    let args: Value = json!([stmt, values, options]);
-    let mut msg_id = FastMessageId::new();
-
-    fast_client::send(String::from("sql"), args, &mut msg_id, stream)
-        .and_then(|_| {
-            fast_client::receive(stream, |resp| query_handler(&resp.data.d))
-        })?;
+    let (method, args) = sql_rpc(stmt, vals, opts)?;
 
-    Ok(())
+    fast::call(stream, method, args, |resp| {
+        Ok(query_handler(&resp.data.d)?)
+    })
 }
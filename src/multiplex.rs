@@ -0,0 +1,436 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use rust_fast::protocol::{FastMessage, FastMessageStatus};
+use serde_json::Value;
+use slog::{debug, Logger};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use super::error::MorayError;
+use super::fast;
+use super::objects::{
+    self, BatchRequest, BatchResult, MethodOptions, MorayObject,
+};
+
+struct CallState {
+    // The outstanding calls, by Fast message id.
+    calls: HashMap<u32, Sender<FastMessage>>,
+    // Why the connection was closed, once it has been.
+    closed: Option<String>,
+}
+
+struct Shared {
+    state: Mutex<CallState>,
+    call_done: Condvar,
+    max_in_flight: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<CallState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn closed_error(&self) -> MorayError {
+        let reason = self
+            .lock()
+            .closed
+            .clone()
+            .unwrap_or_else(|| String::from("connection closed"));
+        MorayError::Io(Error::new(ErrorKind::ConnectionAborted, reason))
+    }
+
+    fn finish(&self, msg_id: u32) {
+        self.lock().calls.remove(&msg_id);
+        self.call_done.notify_all();
+    }
+}
+
+struct Inner {
+    writer: Mutex<TcpStream>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Inner {
+    // Shutting the socket down stops the reader thread.
+    fn drop(&mut self) {
+        if let Ok(stream) = self.writer.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A single Fast connection to moray that can carry many RPCs at once.
+///
+/// Each RPC is sent with its own message id, and a background thread routes
+/// the messages of each response back to the caller that sent it.  This lets
+/// many threads issue requests, e.g. a high fan-out of `get_object` calls,
+/// without claiming a pooled connection each.  The connection is cheap to
+/// clone and clones share the same socket.
+///
+/// At most `max_in_flight` RPCs are outstanding at once; further calls block
+/// until an earlier one completes.  A call completes when its response has
+/// been read in full or its `PendingCall` is dropped.  If the connection
+/// fails, every outstanding and subsequent call returns an error.
+#[derive(Clone)]
+pub struct MultiplexedConnection {
+    inner: Arc<Inner>,
+}
+
+/// The response to an RPC sent on a `MultiplexedConnection`.  Each item is the
+/// data of a DATA message, and a Fast ERROR is returned as the final item.
+pub struct PendingCall {
+    shared: Arc<Shared>,
+    msg_id: u32,
+    rx: Receiver<FastMessage>,
+    done: bool,
+}
+
+impl Iterator for PendingCall {
+    type Item = Result<Value, MorayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let msg = match self.rx.recv() {
+            Ok(msg) => msg,
+            Err(_) => {
+                self.done = true;
+                return Some(Err(self.shared.closed_error()));
+            }
+        };
+
+        match msg.status {
+            FastMessageStatus::Data => Some(Ok(msg.data.d)),
+            FastMessageStatus::End => {
+                self.done = true;
+                None
+            }
+            FastMessageStatus::Error => {
+                self.done = true;
+                Some(Err(MorayError::from_server_error(&msg.data.d)))
+            }
+        }
+    }
+}
+
+impl Drop for PendingCall {
+    // Messages that arrive for an abandoned call are discarded by the reader.
+    fn drop(&mut self) {
+        if !self.done {
+            self.shared.finish(self.msg_id);
+        }
+    }
+}
+
+impl MultiplexedConnection {
+    pub fn connect(
+        address: SocketAddr,
+        max_in_flight: usize,
+        log: Logger,
+    ) -> Result<MultiplexedConnection, MorayError> {
+        if max_in_flight == 0 {
+            return Err(MorayError::InvalidInput(String::from(
+                "max_in_flight must be greater than zero",
+            )));
+        }
+
+        let stream = TcpStream::connect(address)?;
+        let reader = stream.try_clone()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(CallState {
+                calls: HashMap::new(),
+                closed: None,
+            }),
+            call_done: Condvar::new(),
+            max_in_flight,
+        });
+
+        let reader_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name(String::from("moray-multiplex-reader"))
+            .spawn(move || read_responses(reader, reader_shared, log))?;
+
+        Ok(MultiplexedConnection {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(stream),
+                shared,
+            }),
+        })
+    }
+
+    /// Send an RPC without waiting for its response.
+    pub fn call(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<PendingCall, MorayError> {
+        let shared = &self.inner.shared;
+        let msg_id = fast::next_msg_id();
+        let request = fast::encode_request(msg_id, method, args)?;
+        let (tx, rx) = mpsc::channel();
+
+        // The call is registered before the request is written so that the
+        // reader can't see the response first.
+        {
+            let mut state = shared.lock();
+            while state.calls.len() >= shared.max_in_flight
+                && state.closed.is_none()
+            {
+                state = shared
+                    .call_done
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner());
+            }
+            if state.closed.is_some() {
+                drop(state);
+                return Err(shared.closed_error());
+            }
+            state.calls.insert(msg_id, tx);
+        }
+
+        let written = self
+            .inner
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(&request);
+        if let Err(e) = written {
+            shared.finish(msg_id);
+            return Err(e.into());
+        }
+
+        Ok(PendingCall {
+            shared: Arc::clone(shared),
+            msg_id,
+            rx,
+            done: false,
+        })
+    }
+
+    // Send an RPC and wait for the data of each message in its response.
+    fn call_all(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<Vec<Value>, MorayError> {
+        self.call(method, args)?.collect()
+    }
+
+    fn call_last(
+        &self,
+        method: String,
+        args: Value,
+    ) -> Result<Value, MorayError> {
+        let no_response =
+            MorayError::Protocol(format!("No response from {}", method));
+        self.call_all(method, args)?.pop().ok_or(no_response)
+    }
+
+    pub fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<MorayObject, MorayError> {
+        let (method, args) = objects::get_find_objects_rpc(
+            bucket,
+            key,
+            opts,
+            &objects::Methods::Get,
+        );
+        let mut found = None;
+
+        objects::decode_object(
+            bucket,
+            &self.call_last(method, args)?,
+            |obj| {
+                found = Some(obj);
+                Ok(())
+            },
+        )?;
+
+        found.ok_or_else(|| {
+            MorayError::Protocol(String::from("No response from getObject"))
+        })
+    }
+
+    pub fn find_objects<Q>(
+        &self,
+        bucket: &str,
+        filter: Q,
+        opts: &MethodOptions,
+    ) -> Result<Vec<MorayObject>, MorayError>
+    where
+        Q: Into<String>,
+    {
+        let (method, args) = objects::get_find_objects_rpc(
+            bucket,
+            &filter.into(),
+            opts,
+            &objects::Methods::Find,
+        );
+        let mut found = vec![];
+
+        for d in self.call_all(method, args)? {
+            objects::decode_object(bucket, &d, |obj| {
+                found.push(obj);
+                Ok(())
+            })?;
+        }
+
+        Ok(found)
+    }
+
+    /// Put an object, returning its new etag.
+    pub fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+    ) -> Result<String, MorayError> {
+        let (method, args) = objects::put_object_rpc(bucket, key, value, opts);
+        objects::decode_put_object(&self.call_last(method, args)?)
+    }
+
+    pub fn delete_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        let (method, args) = objects::delete_object_rpc(bucket, key, opts);
+        self.call_all(method, args).map(|_| ())
+    }
+
+    /// See `objects::batch`.
+    pub fn batch(
+        &self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+    ) -> Result<Vec<BatchResult>, MorayError> {
+        let (method, args) = objects::batch_rpc(requests, opts)?;
+        objects::decode_batch(requests, &self.call_last(method, args)?)
+    }
+}
+
+fn read_responses(mut stream: TcpStream, shared: Arc<Shared>, log: Logger) {
+    let mut buf = vec![];
+
+    let error = loop {
+        let msg = match fast::read_message(&mut stream, &mut buf) {
+            Ok(msg) => msg,
+            Err(e) => break e,
+        };
+
+        let mut state = shared.lock();
+        let tx = match msg.status {
+            FastMessageStatus::Data => state.calls.get(&msg.id).cloned(),
+            FastMessageStatus::End | FastMessageStatus::Error => {
+                shared.call_done.notify_all();
+                state.calls.remove(&msg.id)
+            }
+        };
+        drop(state);
+
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(msg);
+            }
+            None => {
+                debug!(log, "discarding message for unknown call";
+                    "msg_id" => msg.id);
+            }
+        }
+    };
+
+    debug!(log, "multiplexed connection closed"; "error" => error.to_string());
+
+    let mut state = shared.lock();
+    state.closed = Some(error.to_string());
+    state.calls.clear();
+    shared.call_done.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::test_server::object;
+    use rust_fast::protocol::FastMessageData;
+    use serde_json::json;
+    use slog::{o, Discard};
+    use std::net::TcpListener;
+
+    fn reply(stream: &mut TcpStream, id: u32, method: &str, d: Value) {
+        let method = method.to_string();
+        let data =
+            FastMessage::data(id, FastMessageData::new(method.clone(), d));
+        let mut bytes = fast::encode_message(&data).unwrap();
+        bytes.extend(
+            fast::encode_message(&FastMessage::end(id, method)).unwrap(),
+        );
+        stream.write_all(&bytes).unwrap();
+    }
+
+    #[test]
+    fn out_of_order_responses_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Read two requests, then answer them in reverse order.
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![];
+            let first = fast::read_message(&mut stream, &mut buf).unwrap();
+            let second = fast::read_message(&mut stream, &mut buf).unwrap();
+
+            for req in &[second, first] {
+                let key = req.data.d[1].as_str().unwrap().to_string();
+                reply(&mut stream, req.id, "getObject", json!([object(&key)]));
+            }
+        });
+
+        let log = Logger::root(Discard, o!());
+        let conn = MultiplexedConnection::connect(addr, 2, log).unwrap();
+        let opts = MethodOptions::default();
+
+        let first = conn.clone();
+        let opts_clone = opts.clone();
+        let handle =
+            thread::spawn(move || first.get_object("b", "k1", &opts_clone));
+
+        // Wait for the first request to be in flight before sending another.
+        while conn.inner.shared.lock().calls.is_empty() {
+            thread::yield_now();
+        }
+
+        let k2 = conn.get_object("b", "k2", &opts).unwrap();
+        let k1 = handle.join().unwrap().unwrap();
+        assert_eq!(k1.key, "k1");
+        assert_eq!(k2.key, "k2");
+
+        // The server has gone away, so the connection is now closed.
+        match conn.get_object("b", "k3", &opts) {
+            Err(MorayError::Io(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connect_max_in_flight_test() {
+        let log = Logger::root(Discard, o!());
+        let addr = "127.0.0.1:2020".parse().unwrap();
+        match MultiplexedConnection::connect(addr, 0, log) {
+            Err(MorayError::InvalidInput(_)) => (),
+            _ => panic!("expected InvalidInput error"),
+        }
+    }
+}
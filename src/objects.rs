@@ -2,16 +2,38 @@
  * Copyright 2020 Joyent, Inc.
  */
 
-use rust_fast::{client as fast_client, protocol::FastMessageId};
+use cueball::connection::Connection;
+use serde::de::DeserializeOwned;
 use serde::ser::Serializer;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::net::TcpStream;
+use std::ops::DerefMut;
 use uuid::Uuid;
 
+use super::error::{MorayError, ServerError};
+use super::fast;
+
+#[cfg(any(
+    test,
+    feature = "testing",
+    feature = "postgres",
+    feature = "sqlite"
+))]
+mod store;
+#[cfg(any(
+    test,
+    feature = "testing",
+    feature = "postgres",
+    feature = "sqlite"
+))]
+pub(crate) use store::{etag, now_millis, update_etag, update_fields};
+
+/// An object in a bucket.  The `value` is a `serde_json::Value` by default,
+/// or any type that it can be deserialized into, e.g. with `get_object_as`.
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
-pub struct MorayObject {
+pub struct MorayObject<T = Value> {
     pub bucket: String,
     #[serde(default, deserialize_with = "null_to_zero")]
     pub _count: u64, // TODO: This should probably be an Option<u64>
@@ -20,7 +42,7 @@ pub struct MorayObject {
     pub _mtime: u64,
     pub _txn_snap: Option<u64>,
     pub key: String,
-    pub value: Value, // Bucket schema dependent
+    pub value: T, // Bucket schema dependent
 }
 
 ///
@@ -68,10 +90,33 @@ impl Serialize for Etag {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum SortOrder {
+    #[serde(rename = "ASC")]
+    Asc,
+    #[serde(rename = "DESC")]
+    Desc,
+}
+
+/// The order of `find_objects` results, by `_id`, `_key`, `_mtime` or an
+/// indexed field.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Sort {
+    pub attribute: String,
+    pub order: SortOrder,
+}
+
+impl Sort {
+    pub fn new<A: Into<String>>(attribute: A, order: SortOrder) -> Self {
+        Sort {
+            attribute: attribute.into(),
+            order,
+        }
+    }
+}
+
 // TODO:
 // * include _value: String = serde_json::to_string(value)
-// * add offset,
-// * add sort
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MethodOptions {
     pub req_id: String, // UUID as String
@@ -84,6 +129,10 @@ pub struct MethodOptions {
     pub no_cache: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort: Option<Sort>,
 }
 
 impl Default for MethodOptions {
@@ -96,6 +145,8 @@ impl Default for MethodOptions {
             sql_only: false,
             no_cache: true,
             limit: None,
+            offset: None,
+            sort: None,
         }
     }
 }
@@ -108,6 +159,35 @@ impl MethodOptions {
     pub fn unset_limit(&mut self) {
         self.limit = None;
     }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Skip the first `offset` matching objects.
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = Some(offset);
+    }
+
+    pub fn unset_offset(&mut self) {
+        self.offset = None;
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn set_sort(&mut self, sort: Sort) {
+        self.sort = Some(sort);
+    }
+
+    pub fn unset_sort(&mut self) {
+        self.sort = None;
+    }
+
+    pub fn sort(&self) -> Option<&Sort> {
+        self.sort.as_ref()
+    }
 }
 
 /*
@@ -118,14 +198,22 @@ pub enum Methods {
     Get,
     Find,
     Put,
+    Delete,
+    Update,
+    DeleteMany,
+    Reindex,
 }
 
 impl Methods {
-    fn method(&self) -> String {
+    pub(crate) fn method(&self) -> String {
         match *self {
             Methods::Get => String::from("getObject"),
             Methods::Find => String::from("findObjects"),
             Methods::Put => String::from("putObject"),
+            Methods::Delete => String::from("delObject"),
+            Methods::Update => String::from("updateObjects"),
+            Methods::DeleteMany => String::from("deleteMany"),
+            Methods::Reindex => String::from("reindexObjects"),
         }
     }
 }
@@ -135,6 +223,30 @@ pub struct PutObjectReturn {
     etag: String,
 }
 
+/// The result of an `updateObjects` call: the number of rows that matched the
+/// filter and were updated, and the etag that was assigned to all of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateObjectsReturn {
+    pub count: u64,
+    pub etag: String,
+}
+
+/// The result of a single `reindexObjects` call.  `processed` is the number of
+/// objects reindexed by this call; once it is zero the bucket is fully
+/// reindexed.  `remaining` is moray's estimate of the objects left to process,
+/// and may be absent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReindexObjectsReturn {
+    pub processed: u64,
+    #[serde(default)]
+    pub remaining: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DeleteManyReturn {
+    count: u64,
+}
+
 fn null_to_zero<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: Deserializer<'de>,
@@ -146,35 +258,214 @@ where
     }
 }
 
-#[allow(clippy::redundant_closure)]
-fn decode_object<F>(fm_data: &Value, mut cb: F) -> Result<(), Error>
-where
-    F: FnMut(MorayObject) -> Result<(), Error>,
-{
-    let result = Ok(());
-
-    if fm_data.is_array() {
-        let resp_data: Vec<Value> =
-            serde_json::from_value(fm_data.clone()).unwrap();
-
-        resp_data.iter().fold(result, |_r, object_data| {
-            serde_json::from_value::<MorayObject>(object_data.clone())
-                .map_err(|e| {
-                    // TODO: this should propagate error up
-                    eprintln!("ERROR: {}", &e);
-                    Error::new(ErrorKind::Other, e)
+// Work out which of the fields moray returns is responsible for an object
+// failing to decode, so that the error can name it.  If they all look right
+// it must be the value that doesn't match the type it's being decoded into.
+fn object_decode_error(
+    bucket: &str,
+    object_data: &Value,
+    error: serde_json::Error,
+) -> MorayError {
+    let required: [(&str, fn(&Value) -> bool); 5] = [
+        ("bucket", Value::is_string),
+        ("key", Value::is_string),
+        ("_etag", Value::is_string),
+        ("_id", Value::is_u64),
+        ("_mtime", Value::is_u64),
+    ];
+    // These may also be missing or null.
+    let optional: [(&str, fn(&Value) -> bool); 2] =
+        [("_count", Value::is_u64), ("_txn_snap", Value::is_u64)];
+
+    let field = if object_data.is_object() {
+        required
+            .iter()
+            .find(|(name, valid)| !object_data.get(name).map_or(false, valid))
+            .or_else(|| {
+                optional.iter().find(|(name, valid)| {
+                    object_data
+                        .get(name)
+                        .map_or(false, |v| !v.is_null() && !valid(v))
                 })
-                .and_then(|obj| cb(obj))
-        })
+            })
+            .map_or("value", |(name, _)| *name)
     } else {
-        assert_eq!(fm_data.is_object(), true);
+        "object"
+    };
 
-        serde_json::from_value::<MorayObject>(fm_data.clone())
-            .map_err(|e| Error::new(ErrorKind::Other, e))
-            .and_then(cb)?;
+    let message = match object_data.get("key").and_then(Value::as_str) {
+        Some(key) => format!("object {}: {}", key, error),
+        None => error.to_string(),
+    };
+
+    MorayError::decode(Some(bucket), field, message)
+}
+
+pub(crate) fn decode_object<F>(
+    bucket: &str,
+    fm_data: &Value,
+    cb: F,
+) -> Result<(), MorayError>
+where
+    F: FnMut(MorayObject) -> Result<(), MorayError>,
+{
+    decode_object_as(bucket, fm_data, cb)
+}
+
+/// Decode the objects in a response, deserializing each object's value
+/// straight from the response data into a `T`.
+pub(crate) fn decode_object_as<T, F>(
+    bucket: &str,
+    fm_data: &Value,
+    mut cb: F,
+) -> Result<(), MorayError>
+where
+    T: DeserializeOwned,
+    F: FnMut(MorayObject<T>) -> Result<(), MorayError>,
+{
+    let objects: Vec<&Value> = match fm_data {
+        Value::Array(arr) => arr.iter().collect(),
+        Value::Object(_) => vec![fm_data],
+        _ => {
+            return Err(MorayError::decode(
+                Some(bucket),
+                "object",
+                format!("expected an Object or Array, got: {}", fm_data),
+            ));
+        }
+    };
 
-        result
+    for object_data in objects {
+        let obj = MorayObject::<T>::deserialize(object_data)
+            .map_err(|e| object_decode_error(bucket, object_data, e))?;
+        cb(obj)?;
     }
+
+    Ok(())
+}
+
+/*
+ * The method name and arguments of each RPC.  These are shared by the blocking
+ * client and AsyncMorayClient.
+ */
+
+pub(crate) fn get_find_objects_rpc(
+    bucket: &str,
+    key_filter: &str,
+    opts: &MethodOptions,
+    method: &Methods,
+) -> (String, Value) {
+    (method.method(), json!([bucket, key_filter, opts]))
+}
+
+pub(crate) fn update_objects_rpc(
+    bucket: &str,
+    fields: Value,
+    filter: &str,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (
+        Methods::Update.method(),
+        json!([bucket, fields, filter, opts]),
+    )
+}
+
+pub(crate) fn delete_many_rpc(
+    bucket: &str,
+    filter: &str,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (Methods::DeleteMany.method(), json!([bucket, filter, opts]))
+}
+
+pub(crate) fn reindex_objects_rpc(
+    bucket: &str,
+    count: u64,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (Methods::Reindex.method(), json!([bucket, count, opts]))
+}
+
+pub(crate) fn put_object_rpc(
+    bucket: &str,
+    key: &str,
+    value: Value,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (Methods::Put.method(), json!([bucket, key, value, opts]))
+}
+
+pub(crate) fn delete_object_rpc(
+    bucket: &str,
+    key: &str,
+    opts: &MethodOptions,
+) -> (String, Value) {
+    (Methods::Delete.method(), json!([bucket, key, opts]))
+}
+
+pub(crate) fn batch_rpc(
+    requests: &[BatchRequest],
+    opts: &MethodOptions,
+) -> Result<(String, Value), MorayError> {
+    let batch_requests = serde_json::to_value(requests)?;
+    Ok((String::from("batch"), json!([batch_requests, opts])))
+}
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub(crate) fn object_not_found(bucket: &str, key: &str) -> MorayError {
+    MorayError::ObjectNotFound(ServerError::new(
+        "ObjectNotFoundError",
+        format!("{}::{} does not exist", bucket, key),
+    ))
+}
+
+/// Check the `etag` option of a request against the `actual` etag of the
+/// object, which is None if it doesn't exist, as moray does.  A Nulled etag
+/// means that the object must not exist.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub(crate) fn check_etag(
+    bucket: &str,
+    key: &str,
+    expected: &Etag,
+    actual: Option<&str>,
+) -> Result<(), MorayError> {
+    let matches = match expected {
+        Etag::Undefined => true,
+        Etag::Nulled => actual.is_none(),
+        Etag::Specified(etag) => actual == Some(etag.as_str()),
+    };
+    if matches {
+        return Ok(());
+    }
+
+    let expected = json!(expected.specified_value());
+    let actual = json!(actual);
+    let mut err = ServerError::new(
+        "EtagConflictError",
+        format!(
+            "{}::{} has etag {}, but expected {}",
+            bucket, key, actual, expected
+        ),
+    );
+    err.context = json!({
+        "bucket": bucket,
+        "key": key,
+        "expected": expected,
+        "actual": actual,
+    });
+    Err(MorayError::EtagConflict(err))
+}
+
+pub(crate) fn decode_put_object(fm_data: &Value) -> Result<String, MorayError> {
+    let ret: PutObjectReturn =
+        fast::decode_single(&Methods::Put.method(), fm_data)?;
+    Ok(ret.etag)
+}
+
+pub(crate) fn decode_delete_many(fm_data: &Value) -> Result<u64, MorayError> {
+    let ret: DeleteManyReturn =
+        fast::decode_single(&Methods::DeleteMany.method(), fm_data)?;
+    Ok(ret.count)
 }
 
 pub fn get_find_objects<F>(
@@ -184,21 +475,121 @@ pub fn get_find_objects<F>(
     opts: &MethodOptions,
     method: Methods,
     mut object_handler: F,
-) -> Result<(), Error>
+) -> Result<(), MorayError>
 where
     F: FnMut(&MorayObject) -> Result<(), Error>,
 {
-    let obj_method = method.method();
-    let arg = json!([bucket, key_filter, opts]);
-    let mut msg_id = FastMessageId::new();
+    let (method, arg) = get_find_objects_rpc(bucket, key_filter, opts, &method);
+
+    fast::call(stream, method, arg, |resp| {
+        decode_object(bucket, &resp.data.d, |obj| Ok(object_handler(&obj)?))
+    })
+}
+
+/// Get a single object, deserializing its value into a `T`.
+pub fn get_object_as<T>(
+    stream: &mut TcpStream,
+    bucket: &str,
+    key: &str,
+    opts: &MethodOptions,
+) -> Result<MorayObject<T>, MorayError>
+where
+    T: DeserializeOwned,
+{
+    let (method, arg) = get_find_objects_rpc(bucket, key, opts, &Methods::Get);
+    let mut found = None;
 
-    fast_client::send(obj_method, arg, &mut msg_id, stream).and_then(|_| {
-        fast_client::receive(stream, |resp| {
-            decode_object(&resp.data.d, |obj| object_handler(&obj))
+    fast::call(stream, method, arg, |resp| {
+        decode_object_as(bucket, &resp.data.d, |obj| {
+            found = Some(obj);
+            Ok(())
         })
     })?;
 
-    Ok(())
+    found.ok_or_else(|| {
+        MorayError::Protocol(String::from("No response from getObject"))
+    })
+}
+
+/// Send a findObjects RPC on `conn`, returning an iterator over the objects
+/// found with their values deserialized into a `T`.  See `fast::Response` for
+/// how the connection is read.
+pub(crate) fn find_objects_iter<T, C>(
+    conn: C,
+    bucket: &str,
+    filter: &str,
+    opts: &MethodOptions,
+) -> Result<impl Iterator<Item = Result<MorayObject<T>, MorayError>>, MorayError>
+where
+    T: DeserializeOwned,
+    C: DerefMut,
+    C::Target: DerefMut<Target = TcpStream> + Connection,
+{
+    let (method, arg) =
+        get_find_objects_rpc(bucket, filter, opts, &Methods::Find);
+    let response = fast::Response::send(conn, method, arg)?;
+    let bucket = bucket.to_string();
+
+    Ok(response.flat_map(move |data| {
+        let mut found = vec![];
+        let decoded = data.and_then(|d| {
+            decode_object_as(&bucket, &d, |obj| {
+                found.push(Ok(obj));
+                Ok(())
+            })
+        });
+        if let Err(e) = decoded {
+            found.push(Err(e));
+        }
+        found
+    }))
+}
+
+/// Set the indexed `fields` on every object in `bucket` that matches
+/// `filter`.  Only fields that are indexed in the bucket's schema may be
+/// updated, and the object's `_value` is left untouched.
+pub fn update_objects<Q>(
+    stream: &mut TcpStream,
+    bucket: &str,
+    fields: Value,
+    filter: Q,
+    opts: &MethodOptions,
+) -> Result<UpdateObjectsReturn, MorayError>
+where
+    Q: Into<String>,
+{
+    let (method, arg) =
+        update_objects_rpc(bucket, fields, &filter.into(), opts);
+    fast::call_single(stream, method, arg)
+}
+
+/// Delete every object in `bucket` that matches `filter`, returning the number
+/// of objects that were deleted.  If a limit is set in `opts` at most that many
+/// objects are deleted.
+pub fn delete_many<Q>(
+    stream: &mut TcpStream,
+    bucket: &str,
+    filter: Q,
+    opts: &MethodOptions,
+) -> Result<u64, MorayError>
+where
+    Q: Into<String>,
+{
+    let (method, arg) = delete_many_rpc(bucket, &filter.into(), opts);
+    let ret: DeleteManyReturn = fast::call_single(stream, method, arg)?;
+    Ok(ret.count)
+}
+
+/// Reindex up to `count` objects in `bucket` whose indexes are out of date,
+/// typically after `update_bucket` has added new indexes.
+pub fn reindex_objects(
+    stream: &mut TcpStream,
+    bucket: &str,
+    count: u64,
+    opts: &MethodOptions,
+) -> Result<ReindexObjectsReturn, MorayError> {
+    let (method, arg) = reindex_objects_rpc(bucket, count, opts);
+    fast::call_single(stream, method, arg)
 }
 
 pub fn put_object<F>(
@@ -208,31 +599,30 @@ pub fn put_object<F>(
     value: Value,
     opts: &MethodOptions,
     mut object_handler: F,
-) -> Result<(), Error>
+) -> Result<(), MorayError>
 where
     F: FnMut(&str) -> Result<(), Error>,
 {
-    let arg = json!([bucket, key, value, opts]);
-    let mut msg_id = FastMessageId::new();
-
-    fast_client::send(Methods::Put.method(), arg, &mut msg_id, stream)
-        .and_then(|_| {
-        fast_client::receive(stream, |resp| {
-            let arr: Vec<PutObjectReturn> =
-                serde_json::from_value(resp.data.d.clone())?;
-            if arr.len() != 1 {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Expected response to be a single element Array, got: {:?}",
-                        arr
-                    ),
-                ));
-            }
-            object_handler(arr[0].etag.as_str())
-        })
-    })?;
+    let (method, arg) = put_object_rpc(bucket, key, value, opts);
+    let ret: PutObjectReturn = fast::call_single(stream, method, arg)?;
+    Ok(object_handler(ret.etag.as_str())?)
+}
 
-    Ok(())
+/// Delete the object at `key` in `bucket`.  As with `put_object`, the etag in
+/// `opts` is honored:
+/// * Undefined: Delete the object regardless of its current etag
+/// * Nulled: Never matches an existing object, so the delete fails
+/// * Specified(String): Only delete the object if its etag matches
+pub fn delete_object(
+    stream: &mut TcpStream,
+    bucket: &str,
+    key: &str,
+    opts: &MethodOptions,
+) -> Result<(), MorayError> {
+    let (method, arg) = delete_object_rpc(bucket, key, opts);
+
+    // delObject does not return any data on success.
+    fast::call(stream, method, arg, |_| Ok(()))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -255,12 +645,10 @@ pub struct BatchPutOp {
     pub value: Value,
 }
 
-/// For now we only support Put operations
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BatchUpdateOp {
     pub bucket: String,
     pub options: MethodOptions,
-    pub key: String,
     pub fields: Value,
     pub filter: String,
 }
@@ -279,90 +667,293 @@ pub struct BatchDeleteManyOp {
     pub filter: String,
 }
 
+/// The result of a single operation in a batch, in the same position as the
+/// `BatchRequest` that produced it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchResult {
+    Put { etag: String },
+    Update { count: u64, etag: Option<String> },
+    Delete,
+    DeleteMany { count: u64 },
+}
+
+// Each entry in the "etags" array of a batch response.  The fields present
+// depend on the operation the entry is associated with.
+#[derive(Deserialize, Debug)]
+struct BatchResponseEntry {
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    count: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    etags: Vec<BatchResponseEntry>,
+}
+
+// The batch response makes no mention of the operation each entry is
+// associated with, but moray processes (and responds to) the requests in
+// order, so we pair them up with the requests here.
+pub(crate) fn decode_batch(
+    requests: &[BatchRequest],
+    fm_data: &Value,
+) -> Result<Vec<BatchResult>, MorayError> {
+    let resp: BatchResponse = fast::decode_single("batch", fm_data)?;
+    let entries = resp.etags;
+
+    if entries.len() != requests.len() {
+        return Err(MorayError::Protocol(format!(
+            "Expected {} batch results, got {}",
+            requests.len(),
+            entries.len()
+        )));
+    }
+
+    let missing = |field: &str, op: &str| {
+        MorayError::Protocol(format!(
+            "Batch {} result is missing '{}'",
+            op, field
+        ))
+    };
+
+    requests
+        .iter()
+        .zip(entries.into_iter())
+        .map(|(req, entry)| match req {
+            BatchRequest::Put(_) => Ok(BatchResult::Put {
+                etag: entry.etag.ok_or_else(|| missing("etag", "put"))?,
+            }),
+            BatchRequest::Update(_) => Ok(BatchResult::Update {
+                count: entry.count.ok_or_else(|| missing("count", "update"))?,
+                etag: entry.etag,
+            }),
+            BatchRequest::Delete(_) => Ok(BatchResult::Delete),
+            BatchRequest::DeleteMany(_) => Ok(BatchResult::DeleteMany {
+                count: entry
+                    .count
+                    .ok_or_else(|| missing("count", "deleteMany"))?,
+            }),
+        })
+        .collect()
+}
+
 /// The moray server treats a batch as a transaction.  If any of the operations
 /// in the batch fail, none of them will be applied.  This includes
 /// EtagConflict's.  If there is an error, this function will return Err()
 /// and the `batch_handler` will not be called.
+///
+/// On success the `batch_handler` is called with one `BatchResult` per
+/// request, in the same order as `requests`.
 pub fn batch<F>(
     stream: &mut TcpStream,
     requests: &[BatchRequest],
     opts: &MethodOptions,
     mut batch_handler: F,
-) -> Result<(), Error>
+) -> Result<(), MorayError>
 where
-    F: FnMut(Vec<Value>) -> Result<(), Error>,
+    F: FnMut(Vec<BatchResult>) -> Result<(), Error>,
 {
-    // We only support Put Operations right now
-    if requests.iter().any(|r| match r {
-        BatchRequest::Put(_) => false,
-        _ => true,
-    }) {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Only Put operations are supported",
-        ));
-    }
-
-    let batch_requests =
-        serde_json::to_value(requests.to_owned()).expect("batch requests");
-    let arg = json!([batch_requests, opts]);
-    let mut msg_id = FastMessageId::new();
-
-    fast_client::send(String::from("batch"), arg, &mut msg_id, stream)
-        .and_then(|_| {
-            fast_client::receive(stream, |resp| {
-                // The response is a Vec<Value>, where each Value can take a
-                // different form depending on the batch operation.  We
-                // assume there are no ordering guarntees, and the Value's
-                // make no mention of the operation they are associated with.
-                // So we really have no choice but to return this opaque Vec of
-                // Value's.
-                batch_handler(serde_json::from_value(resp.data.d.clone())?)
-            })
-        })?;
+    let (method, arg) = batch_rpc(requests, opts)?;
 
-    Ok(())
+    fast::call(stream, method, arg, |resp| {
+        Ok(batch_handler(decode_batch(requests, &resp.data.d)?)?)
+    })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::net::TcpListener;
+    use quickcheck::quickcheck;
+
+    fn batch_requests() -> Vec<BatchRequest> {
+        let bucket = String::from("foo bucket");
+        vec![
+            BatchRequest::Put(BatchPutOp {
+                bucket: bucket.clone(),
+                options: MethodOptions::default(),
+                key: String::from("somekey"),
+                value: json!({"field 1": "value 1", "number": 4}),
+            }),
+            BatchRequest::Update(BatchUpdateOp {
+                bucket: bucket.clone(),
+                options: MethodOptions::default(),
+                fields: json!({"number": 5}),
+                filter: String::from("(number=4)"),
+            }),
+            BatchRequest::Delete(BatchDeleteOp {
+                bucket: bucket.clone(),
+                options: MethodOptions::default(),
+                key: String::from("otherkey"),
+            }),
+            BatchRequest::DeleteMany(BatchDeleteManyOp {
+                bucket,
+                options: MethodOptions::default(),
+                filter: String::from("(mydelete=filter)"),
+            }),
+        ]
+    }
 
     #[test]
-    fn batch_unsupported_test() {
-        let mut requests = vec![];
-        let opts = MethodOptions::default();
-        let value = json!({
-            "field 1": "value 1",
-            "objectID": "someuuid",
-            "number": 4
-        });
+    fn batch_request_serialize_test() {
+        let requests = batch_requests();
+        let serialized = serde_json::to_value(&requests).unwrap();
+        let ops: Vec<&str> = serialized
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["operation"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(ops, vec!["put", "update", "delete", "deleteMany"]);
+        assert!(serialized[1].get("key").is_none());
+    }
 
-        // Spawn a thread keep up the dummy_stream's ruse
-        let listen_handle = std::thread::spawn(|| {
-            let listener = TcpListener::bind("localhost:8000").unwrap();
-            listener.accept().unwrap();
-        });
+    #[test]
+    fn decode_batch_test() {
+        let requests = batch_requests();
+        let response = json!([{
+            "etags": [
+                { "bucket": "foo bucket", "key": "somekey", "etag": "ABCD" },
+                { "bucket": "foo bucket", "count": 2, "etag": "EFGH" },
+                { "bucket": "foo bucket", "key": "otherkey" },
+                { "bucket": "foo bucket", "count": 7 }
+            ]
+        }]);
+
+        let results = decode_batch(&requests, &response).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                BatchResult::Put {
+                    etag: String::from("ABCD")
+                },
+                BatchResult::Update {
+                    count: 2,
+                    etag: Some(String::from("EFGH"))
+                },
+                BatchResult::Delete,
+                BatchResult::DeleteMany { count: 7 },
+            ]
+        );
+
+        // A response that doesn't line up with the requests is an error
+        let short = json!([{ "etags": [{ "etag": "ABCD" }] }]);
+        assert!(decode_batch(&requests, &short).is_err());
+
+        // As is one missing a field required by its operation
+        let missing = json!([{
+            "etags": [{}, { "count": 2 }, {}, { "etag": "IJKL" }]
+        }]);
+        assert!(decode_batch(&requests, &missing).is_err());
+    }
 
-        let mut dummy_stream = TcpStream::connect("localhost:8000").unwrap();
+    fn object_json(bucket: &str, key: &str) -> Value {
+        json!({
+            "bucket": bucket,
+            "key": key,
+            "value": { "aNumber": 6.28 },
+            "_id": 42,
+            "_etag": "ABCD1234",
+            "_mtime": 1_577_836_800_000u64,
+            "_txn_snap": null,
+            "_count": null
+        })
+    }
 
-        requests.push(BatchRequest::Put(BatchPutOp {
-            bucket: String::from("foo bucket"),
-            options: MethodOptions::default(),
-            key: String::from("somekey"),
-            value,
-        }));
+    #[test]
+    fn decode_object_test() {
+        let mut keys = vec![];
+        let input = json!([object_json("b", "k1"), object_json("b", "k2")]);
+
+        decode_object("b", &input, |o| {
+            assert_eq!(o._count, 0);
+            keys.push(o.key);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(keys, vec!["k1", "k2"]);
+
+        // A single object that isn't wrapped in an array
+        let mut count = 0;
+        decode_object("b", &object_json("b", "k1"), |_| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 1);
+    }
 
-        requests.push(BatchRequest::DeleteMany(BatchDeleteManyOp {
-            bucket: String::from("foo bucket"),
-            options: MethodOptions::default(),
-            filter: String::from("(mydelete=filter)"),
-        }));
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Typed {
+        #[serde(rename = "aNumber")]
+        a_number: f64,
+    }
 
-        assert!(batch(&mut dummy_stream, &requests, &opts, |_| Ok(())).is_err());
+    #[test]
+    fn decode_object_as_test() {
+        let input = json!([object_json("b", "k1"), object_json("b", "k2")]);
+        let mut values = vec![];
 
-        listen_handle.join().unwrap();
+        decode_object_as("b", &input, |o: MorayObject<Typed>| {
+            values.push(o.value);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(values, vec![Typed { a_number: 6.28 }; 2]);
+
+        // A value that doesn't match the type names the value and the key.
+        let mut input = object_json("b", "k1");
+        input["value"] = json!({ "aNumber": "not a number" });
+        match decode_object_as("b", &input, |_: MorayObject<Typed>| Ok(())) {
+            Err(MorayError::Decode { field, message, .. }) => {
+                assert_eq!(field, "value");
+                assert!(message.starts_with("object k1:"), "{}", message);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // As does a bad _count, which may otherwise be null or missing.
+        let mut input = object_json("b", "k1");
+        input["_count"] = json!("many");
+        match decode_object_as("b", &input, |_: MorayObject<Typed>| Ok(())) {
+            Err(MorayError::Decode { field, .. }) => {
+                assert_eq!(field, "_count")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    quickcheck! {
+        // Garbage in place of any of the fields moray always returns should
+        // result in an error naming that field, rather than a panic.
+        fn decode_object_garbage_test(field: u8, garbage: String) -> bool {
+            let fields = ["bucket", "key", "_etag", "_id", "_mtime"];
+            let field = fields[field as usize % fields.len()];
+            let mut input = object_json("b", "k");
+
+            // Numeric fields get a string, string fields get an Array
+            input[field] = if field == "_id" || field == "_mtime" {
+                Value::String(garbage)
+            } else {
+                json!([garbage])
+            };
+
+            match decode_object("b", &input, |_| Ok(())) {
+                Err(MorayError::Decode { bucket, field: f, .. }) => {
+                    bucket == Some(String::from("b")) && f == field
+                }
+                _ => false,
+            }
+        }
+
+        fn decode_object_payload_garbage_test(garbage: String) -> bool {
+            let as_string = Value::String(garbage.clone());
+            let as_array = json!([garbage]);
+
+            decode_object("b", &as_string, |_| Ok(())).is_err() &&
+                decode_object("b", &as_array, |_| Ok(())).is_err()
+        }
     }
 
     #[test]
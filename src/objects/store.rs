@@ -0,0 +1,87 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! What moray does to objects as it stores them, for the stores that stand in
+//! for it: the in-memory one of the testing module and the database backends.
+
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::buckets::Bucket;
+use crate::error::ServerError;
+
+/// Check the `fields` of an updateObjects request, which as with moray may
+/// only set the bucket's indexed fields.
+pub(crate) fn update_fields<'a>(
+    bucket: &Bucket,
+    fields: &'a Value,
+) -> Result<&'a Map<String, Value>, ServerError> {
+    let fields = fields.as_object().ok_or_else(|| {
+        ServerError::new(
+            "InvocationError",
+            String::from("fields must be an object"),
+        )
+    })?;
+
+    match fields.keys().find(|f| !bucket.index().contains_key(*f)) {
+        Some(field) => Err(ServerError::new(
+            "FieldUpdateError",
+            format!("{} is not indexed in bucket {}", field, bucket.name()),
+        )),
+        None => Ok(fields),
+    }
+}
+
+/// The etag moray gives an object with this value: the CRC-32 of the JSON it
+/// stores for the value, as eight upper case hex digits.  serde_json writes
+/// the fields of an object in sorted order, so a value whose JSON moray was
+/// sent in another order gets a different etag there.
+pub(crate) fn etag(value: &Value) -> String {
+    format!("{:08X}", crc32(value.to_string().as_bytes()))
+}
+
+// The CRC-32 (IEEE 802.3) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The etag given to every object updated by an updateObjects call, which
+/// unlike a put's isn't derived from the object's value.
+pub(crate) fn update_etag() -> String {
+    Uuid::new_v4().to_simple().to_string()[..8].to_uppercase()
+}
+
+/// The current time in milliseconds since the epoch, as moray stores in
+/// `_mtime`.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn etag_test() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(etag(&json!({})), format!("{:08X}", crc32(b"{}")));
+        assert_eq!(etag(&json!({ "a": 1 })).len(), 8);
+    }
+}
@@ -0,0 +1,1168 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! `PgMoray`, a `MorayApi` that talks straight to the Postgres database of a
+//! moray shard, for bulk offline work such as rebuilding indexes or auditing
+//! buckets without going through moray.
+//!
+//! Bucket schemas are read from (and written to) the shard's `buckets_config`
+//! table, and each bucket is a table with moray's columns: `_id`, `_key`,
+//! `_value` (the object's JSON), `_etag`, `_mtime`, `_txn_snap`, `_vnode` and
+//! `_rver`, plus a column for each indexed field.  Filters are translated to
+//! the SQL moray would run, so they may only reference indexed fields.
+//!
+//! ```ignore
+//! let mut moray = PgMoray::connect("host=shard1 user=moray dbname=moray")?;
+//! loop {
+//!     let ret = moray.reindex_objects("manta", 100, &opts)?;
+//!     if ret.processed == 0 {
+//!         break;
+//!     }
+//! }
+//! ```
+//!
+//! As with moray, adding an index with `update_bucket` only creates the
+//! column, and existing objects are not found by it until `reindex_objects`
+//! has processed them.  Pre and post triggers are not run, and objects keep
+//! whatever `_vnode` they have, since that comes from electric-moray.
+
+use pg::types::{FromSql, IsNull, ToSql, Type};
+use pg::{Client, NoTls, Row, Transaction};
+use pg_bytes::BytesMut;
+use serde_json::{json, Value};
+use std::io::Error;
+
+use super::api::MorayApi;
+use super::buckets::{self, Bucket, BucketConfig, IndexDef};
+use super::error::{MorayError, ServerError};
+use super::filter::{order_by, postgres_type, quote_ident, Dialect, Filter};
+use super::objects::{
+    self, etag, now_millis, BatchRequest, BatchResult, MethodOptions,
+    MorayObject, ReindexObjectsReturn, UpdateObjectsReturn,
+};
+
+const BUCKET_COLUMNS: &str = "name, \"index\", COALESCE(options, '{}'), pre, \
+                              post, to_char(mtime, \
+                              'YYYY-MM-DD\"T\"HH24:MI:SS.MS\"Z\"')";
+const OBJECT_COLUMNS: &str = "_id::bigint, _key, _value, _etag::text, \
+                              _mtime::bigint, _txn_snap::bigint";
+
+// Parameters are all bound as text, and cast in the SQL to the type of the
+// column they are compared with or stored in.
+type Params = Vec<Option<String>>;
+
+fn bind<T: ToSql + Sync>(params: &[T]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|p| p as &(dyn ToSql + Sync)).collect()
+}
+
+// A value in Postgres's binary format for its type, as read from a column of
+// any type, to be bound to a parameter of that same type.
+#[derive(Debug)]
+struct RawValue(Vec<u8>);
+
+impl<'a> FromSql<'a> for RawValue {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(RawValue(raw.to_vec()))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+impl ToSql for RawValue {
+    fn to_sql(
+        &self,
+        _: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        out.extend_from_slice(&self.0);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    fn to_sql_checked(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.to_sql(ty, out)
+    }
+}
+
+// Convert the text values of an `sql` call to the parameter types Postgres
+// inferred for the statement.  The client only binds parameters in binary
+// form, so rather than send the text untyped as moray does, Postgres is asked
+// to cast it and the result is bound as it comes back.
+fn typed_params(
+    txn: &mut Transaction,
+    types: &[Type],
+    vals: &[&str],
+) -> Result<Vec<RawValue>, MorayError> {
+    if types.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let casts: Vec<String> = types
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            format!(
+                "${}::text::{}.{}",
+                i + 1,
+                quote_ident(t.schema()),
+                quote_ident(t.name())
+            )
+        })
+        .collect();
+    let sql = format!("SELECT {}", casts.join(", "));
+    let row = txn.query_one(sql.as_str(), &bind(vals))?;
+    (0..types.len()).map(|i| Ok(row.try_get(i)?)).collect()
+}
+
+pub struct PgMoray {
+    client: Client,
+}
+
+impl PgMoray {
+    /// Connect to a shard's database, e.g. with
+    /// "host=localhost user=moray dbname=moray".
+    pub fn connect(params: &str) -> Result<Self, MorayError> {
+        Ok(PgMoray {
+            client: Client::connect(params, NoTls)?,
+        })
+    }
+
+    /// Create the `buckets_config` table if it doesn't exist, as moray's setup
+    /// does for a new shard.
+    pub fn create_buckets_config(&mut self) -> Result<(), MorayError> {
+        self.client.batch_execute(
+            "CREATE TABLE IF NOT EXISTS buckets_config (
+                 name TEXT PRIMARY KEY,
+                 \"index\" TEXT NOT NULL,
+                 pre TEXT NOT NULL,
+                 post TEXT NOT NULL,
+                 options TEXT,
+                 mtime TIMESTAMP NOT NULL DEFAULT now()
+             )",
+        )?;
+        Ok(())
+    }
+}
+
+// buckets_config holds the buckets the way moray sends them, with each field
+// other than the name and mtime encoded as a JSON string.
+fn decode_bucket(row: &Row) -> Result<Bucket, MorayError> {
+    let wire = json!({
+        "name": row.try_get::<_, String>(0)?,
+        "index": row.try_get::<_, String>(1)?,
+        "options": row.try_get::<_, String>(2)?,
+        "pre": row.try_get::<_, String>(3)?,
+        "post": row.try_get::<_, String>(4)?,
+        "mtime": row.try_get::<_, String>(5)?,
+    });
+
+    let mut bucket = None;
+    buckets::decode_bucket(&json!([wire]), |b| {
+        bucket = Some(b);
+        Ok(())
+    })?;
+    bucket.ok_or_else(|| MorayError::Protocol(String::from("empty bucket")))
+}
+
+fn find_bucket(
+    txn: &mut Transaction,
+    name: &str,
+) -> Result<Option<Bucket>, MorayError> {
+    let sql = format!(
+        "SELECT {} FROM buckets_config WHERE name = $1::text",
+        BUCKET_COLUMNS
+    );
+    txn.query(sql.as_str(), &[&name])?
+        .first()
+        .map(decode_bucket)
+        .transpose()
+}
+
+fn get_bucket(txn: &mut Transaction, name: &str) -> Result<Bucket, MorayError> {
+    find_bucket(txn, name)?.ok_or_else(|| buckets::bucket_not_found(name))
+}
+
+fn save_bucket(
+    txn: &mut Transaction,
+    bucket: &Bucket,
+) -> Result<(), MorayError> {
+    let wire = bucket.to_wire()?;
+    let params: Params = ["name", "index", "pre", "post", "options"]
+        .iter()
+        .map(|field| wire[field].as_str().map(String::from))
+        .collect();
+
+    txn.execute(
+        "INSERT INTO buckets_config (name, \"index\", pre, post, options)
+         VALUES ($1::text, $2::text, $3::text, $4::text, $5::text)
+         ON CONFLICT (name) DO UPDATE SET \"index\" = EXCLUDED.\"index\",
+             pre = EXCLUDED.pre, post = EXCLUDED.post,
+             options = EXCLUDED.options, mtime = now()",
+        &bind(&params),
+    )?;
+    Ok(())
+}
+
+fn index_name(bucket: &str, field: &str) -> String {
+    quote_ident(&format!("{}_{}_idx", bucket, field))
+}
+
+fn add_columns(
+    txn: &mut Transaction,
+    bucket: &Bucket,
+) -> Result<(), MorayError> {
+    for (field, def) in bucket.index() {
+        txn.execute(
+            format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                quote_ident(bucket.name()),
+                quote_ident(field),
+                postgres_type(&def.index_type)
+            )
+            .as_str(),
+            &[],
+        )?;
+    }
+    Ok(())
+}
+
+// Array fields get GIN indexes, which can't be unique.
+fn create_index(
+    txn: &mut Transaction,
+    bucket: &str,
+    field: &str,
+    def: &IndexDef,
+) -> Result<(), MorayError> {
+    let (kind, method) = if def.index_type.is_array() {
+        ("INDEX", "GIN")
+    } else if def.unique {
+        ("UNIQUE INDEX", "BTREE")
+    } else {
+        ("INDEX", "BTREE")
+    };
+
+    txn.execute(
+        format!(
+            "CREATE {} IF NOT EXISTS {} ON {} USING {} ({})",
+            kind,
+            index_name(bucket, field),
+            quote_ident(bucket),
+            method,
+            quote_ident(field)
+        )
+        .as_str(),
+        &[],
+    )?;
+    Ok(())
+}
+
+// The text form of a JSON value, as it is cast to the type of a column.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn array_literal(elements: &[Value]) -> String {
+    let elements: Vec<String> = elements
+        .iter()
+        .map(|e| match e {
+            Value::Null => String::from("NULL"),
+            e => format!(
+                "\"{}\"",
+                text(e).replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        })
+        .collect();
+    format!("{{{}}}", elements.join(","))
+}
+
+// The parameter for an index column, and its placeholder.  A single value in
+// an array field is stored as an array of one element.
+fn index_param(def: &IndexDef, value: Option<&Value>) -> Option<String> {
+    match value {
+        None | Some(Value::Null) => None,
+        Some(Value::Array(elements)) if def.index_type.is_array() => {
+            Some(array_literal(elements))
+        }
+        Some(v) if def.index_type.is_array() => {
+            Some(array_literal(std::slice::from_ref(v)))
+        }
+        Some(v) => Some(text(v)),
+    }
+}
+
+fn index_placeholder(def: &IndexDef, n: usize) -> String {
+    format!("${}::text::{}", n, postgres_type(&def.index_type))
+}
+
+// The bucket's index columns, with the placeholders (starting at `$first`)
+// and parameters to set them from an object's value.
+fn index_columns(
+    bucket: &Bucket,
+    value: &Value,
+    first: usize,
+) -> (Vec<(String, String)>, Params) {
+    bucket
+        .index()
+        .iter()
+        .enumerate()
+        .map(|(i, (field, def))| {
+            let column =
+                (quote_ident(field), index_placeholder(def, first + i));
+            (column, index_param(def, value.get(field)))
+        })
+        .unzip()
+}
+
+// The row version of objects written now, which reindex_objects compares with
+// the bucket's version.
+fn row_version(bucket: &Bucket) -> Option<String> {
+    match bucket.options().version() {
+        0 => None,
+        version => Some(version.to_string()),
+    }
+}
+
+// A row of a bucket's table, as selected by OBJECT_COLUMNS.
+struct ObjectRow {
+    id: i64,
+    key: String,
+    value: String,
+    etag: String,
+    mtime: i64,
+    txn_snap: Option<i64>,
+}
+
+impl ObjectRow {
+    fn read(row: &Row) -> Result<Self, MorayError> {
+        Ok(ObjectRow {
+            id: row.try_get(0)?,
+            key: row.try_get(1)?,
+            value: row.try_get(2)?,
+            etag: row.try_get(3)?,
+            mtime: row.try_get(4)?,
+            txn_snap: row.try_get(5)?,
+        })
+    }
+
+    fn into_object(
+        self,
+        bucket: &str,
+        count: u64,
+    ) -> Result<MorayObject, MorayError> {
+        Ok(MorayObject {
+            bucket: bucket.to_string(),
+            _count: count,
+            _etag: self.etag,
+            _id: self.id as u64,
+            _mtime: self.mtime as u64,
+            _txn_snap: self.txn_snap.map(|snap| snap as u64),
+            value: decode_value(bucket, &self.value)?,
+            key: self.key,
+        })
+    }
+}
+
+fn decode_value(bucket: &str, value: &str) -> Result<Value, MorayError> {
+    serde_json::from_str(value)
+        .map_err(|e| MorayError::decode(Some(bucket), "value", e))
+}
+
+// Filters and sorts that can't be run on the bucket are reported as moray
+// reports them.
+fn invalid_query(error: MorayError) -> MorayError {
+    MorayError::InvalidQuery(ServerError::new(
+        "InvalidQueryError",
+        error.to_string(),
+    ))
+}
+
+// The SQL condition for `filter` on the bucket's table, and its parameters.
+fn where_clause(
+    bucket: &Bucket,
+    filter: &str,
+) -> Result<(String, Params), MorayError> {
+    let filter: Filter = filter
+        .parse()
+        .and_then(|f: Filter| f.validate(bucket).map(|_| f))
+        .map_err(invalid_query)?;
+
+    let mut params = vec![];
+    let clause = filter
+        .to_sql(bucket, Dialect::Postgres, &mut params)
+        .map_err(invalid_query)?;
+    Ok((clause, params.iter().map(|p| Some(text(p))).collect()))
+}
+
+fn find_rows(
+    txn: &mut Transaction,
+    bucket: &Bucket,
+    filter: &str,
+    opts: &MethodOptions,
+) -> Result<Vec<(ObjectRow, u64)>, MorayError> {
+    let (clause, params) = where_clause(bucket, filter)?;
+    let order = order_by(bucket, opts.sort()).map_err(invalid_query)?;
+    let mut sql = format!(
+        "SELECT {}, count(*) OVER () FROM {} WHERE {} ORDER BY {}",
+        OBJECT_COLUMNS,
+        quote_ident(bucket.name()),
+        clause,
+        order
+    );
+    if let Some(limit) = opts.limit() {
+        sql.push_str(&format!(" LIMIT {}", limit));
+    }
+    if let Some(offset) = opts.offset() {
+        sql.push_str(&format!(" OFFSET {}", offset));
+    }
+
+    txn.query(sql.as_str(), &bind(&params))?
+        .iter()
+        .map(|row| {
+            Ok((ObjectRow::read(row)?, row.try_get::<_, i64>(6)? as u64))
+        })
+        .collect()
+}
+
+fn find_row(
+    txn: &mut Transaction,
+    bucket: &Bucket,
+    key: &str,
+) -> Result<Option<ObjectRow>, MorayError> {
+    let sql = format!(
+        "SELECT {} FROM {} WHERE _key = $1::text",
+        OBJECT_COLUMNS,
+        quote_ident(bucket.name())
+    );
+    txn.query(sql.as_str(), &[&key])?
+        .first()
+        .map(ObjectRow::read)
+        .transpose()
+}
+
+// Insert the object, or update it if `exists`.
+//
+// `_vnode` is never written.  moray doesn't derive it from the object: it
+// stores the vnode that electric-moray works out from its hash ring and passes
+// along with the put.  There is no ring here, so a new object's `_vnode` is
+// left NULL, as it is for a put sent straight to moray, and an update leaves
+// the existing `_vnode` in place, so objects written through electric-moray
+// keep theirs.
+fn write_object(
+    txn: &mut Transaction,
+    bucket: &Bucket,
+    exists: bool,
+    key: &str,
+    value: &Value,
+    etag: &str,
+) -> Result<(), MorayError> {
+    let mut columns: Vec<(String, String)> = vec![
+        (String::from("_key"), String::from("$1::text")),
+        (String::from("_value"), String::from("$2::text")),
+        (String::from("_etag"), String::from("$3::text")),
+        (String::from("_mtime"), String::from("$4::text::bigint")),
+        (String::from("_rver"), String::from("$5::text::integer")),
+    ];
+    let mut params: Params = vec![
+        Some(key.to_string()),
+        Some(value.to_string()),
+        Some(etag.to_string()),
+        Some(now_millis().to_string()),
+        row_version(bucket),
+    ];
+    let (index_columns, index_params) =
+        index_columns(bucket, value, params.len() + 1);
+    columns.extend(index_columns);
+    params.extend(index_params);
+
+    let table = quote_ident(bucket.name());
+    let sql = if exists {
+        let assignments: Vec<String> = columns
+            .iter()
+            .map(|(column, placeholder)| {
+                format!("{} = {}", column, placeholder)
+            })
+            .collect();
+        format!(
+            "UPDATE {} SET {} WHERE _key = $1::text",
+            table,
+            assignments.join(", ")
+        )
+    } else {
+        let (names, placeholders): (Vec<String>, Vec<String>) =
+            columns.into_iter().unzip();
+        format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            names.join(", "),
+            placeholders.join(", ")
+        )
+    };
+
+    txn.execute(sql.as_str(), &bind(&params))?;
+    Ok(())
+}
+
+fn put_object(
+    txn: &mut Transaction,
+    bucket: &str,
+    key: &str,
+    value: &Value,
+    opts: &MethodOptions,
+) -> Result<String, MorayError> {
+    let bucket = get_bucket(txn, bucket)?;
+    let current = find_row(txn, &bucket, key)?;
+    let actual = current.as_ref().map(|row| row.etag.as_str());
+    objects::check_etag(bucket.name(), key, &opts.etag, actual)?;
+
+    let etag = etag(value);
+    write_object(txn, &bucket, current.is_some(), key, value, &etag)?;
+    Ok(etag)
+}
+
+fn delete_object(
+    txn: &mut Transaction,
+    bucket: &str,
+    key: &str,
+    opts: &MethodOptions,
+) -> Result<(), MorayError> {
+    let bucket = get_bucket(txn, bucket)?;
+    let current = find_row(txn, &bucket, key)?
+        .ok_or_else(|| objects::object_not_found(bucket.name(), key))?;
+    let actual = Some(current.etag.as_str());
+    objects::check_etag(bucket.name(), key, &opts.etag, actual)?;
+
+    let sql = format!(
+        "DELETE FROM {} WHERE _key = $1::text",
+        quote_ident(bucket.name())
+    );
+    txn.execute(sql.as_str(), &[&key])?;
+    Ok(())
+}
+
+fn update_objects(
+    txn: &mut Transaction,
+    bucket: &str,
+    fields: &Value,
+    filter: &str,
+) -> Result<UpdateObjectsReturn, MorayError> {
+    let bucket = get_bucket(txn, bucket)?;
+    let fields =
+        objects::update_fields(&bucket, fields).map_err(MorayError::Server)?;
+    let (clause, mut params) = where_clause(&bucket, filter)?;
+
+    // As with moray, this is a single UPDATE of the index columns and etag,
+    // which leaves `_value` alone, and every object updated by the call gets
+    // the same etag.
+    let etag = objects::update_etag();
+    params.push(Some(etag.clone()));
+    let mut assignments = vec![format!("_etag = ${}::text", params.len())];
+    for (field, value) in fields {
+        let def = &bucket.index()[field];
+        params.push(index_param(def, Some(value)));
+        assignments.push(format!(
+            "{} = {}",
+            quote_ident(field),
+            index_placeholder(def, params.len())
+        ));
+    }
+
+    let count = txn.execute(
+        format!(
+            "UPDATE {} SET {} WHERE {}",
+            quote_ident(bucket.name()),
+            assignments.join(", "),
+            clause
+        )
+        .as_str(),
+        &bind(&params),
+    )?;
+    Ok(UpdateObjectsReturn { count, etag })
+}
+
+fn delete_many(
+    txn: &mut Transaction,
+    bucket: &str,
+    filter: &str,
+    opts: &MethodOptions,
+) -> Result<u64, MorayError> {
+    let bucket = get_bucket(txn, bucket)?;
+    let (clause, params) = where_clause(&bucket, filter)?;
+    let table = quote_ident(bucket.name());
+    let limit = opts.limit().map_or_else(String::new, |limit| {
+        format!(" ORDER BY _id LIMIT {}", limit)
+    });
+
+    let sql = format!(
+        "DELETE FROM {} WHERE _key IN (SELECT _key FROM {} WHERE {}{})",
+        table, table, clause, limit
+    );
+    Ok(txn.execute(sql.as_str(), &bind(&params))?)
+}
+
+// Set the index columns of up to `count` objects written before the bucket
+// reached its current version, as moray's reindexObjects does.
+fn reindex_objects(
+    txn: &mut Transaction,
+    bucket: &str,
+    count: u64,
+) -> Result<ReindexObjectsReturn, MorayError> {
+    let bucket = get_bucket(txn, bucket)?;
+    let version = match row_version(&bucket) {
+        Some(version) => version,
+        None => {
+            return Ok(ReindexObjectsReturn {
+                processed: 0,
+                remaining: Some(0),
+            })
+        }
+    };
+    let table = quote_ident(bucket.name());
+    let stale = "_rver IS NULL OR _rver < $1::text::integer";
+
+    let rows = txn.query(
+        format!(
+            "SELECT _key, _value FROM {} WHERE {} ORDER BY _id LIMIT {} \
+             FOR UPDATE",
+            table, stale, count
+        )
+        .as_str(),
+        &[&version],
+    )?;
+
+    for row in &rows {
+        let key: String = row.try_get(0)?;
+        let value = decode_value(bucket.name(), &row.try_get::<_, String>(1)?)?;
+
+        let mut params = vec![Some(key), Some(version.clone())];
+        let (columns, index_params) =
+            index_columns(&bucket, &value, params.len() + 1);
+        params.extend(index_params);
+        let assignments: Vec<String> = columns
+            .iter()
+            .map(|(column, placeholder)| {
+                format!("{} = {}", column, placeholder)
+            })
+            .chain(Some(String::from("_rver = $2::text::integer")))
+            .collect();
+
+        txn.execute(
+            format!(
+                "UPDATE {} SET {} WHERE _key = $1::text",
+                table,
+                assignments.join(", ")
+            )
+            .as_str(),
+            &bind(&params),
+        )?;
+    }
+
+    let remaining: i64 = txn
+        .query(
+            format!("SELECT count(*) FROM {} WHERE {}", table, stale).as_str(),
+            &[&version],
+        )?
+        .first()
+        .map_or(Ok(0), |row| row.try_get(0))?;
+
+    Ok(ReindexObjectsReturn {
+        processed: rows.len() as u64,
+        remaining: Some(remaining as u64),
+    })
+}
+
+impl MorayApi for PgMoray {
+    fn list_buckets(
+        &mut self,
+        _opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let sql = format!(
+            "SELECT {} FROM buckets_config ORDER BY name",
+            BUCKET_COLUMNS
+        );
+        for row in self.client.query(sql.as_str(), &[])? {
+            bucket_handler(&decode_bucket(&row)?)?;
+        }
+        Ok(())
+    }
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        _opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let bucket = get_bucket(&mut txn, name)?;
+        txn.commit()?;
+        Ok(bucket_handler(&bucket)?)
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        buckets::check_bucket_name(name)?;
+
+        let mut txn = self.client.transaction()?;
+        if find_bucket(&mut txn, name)?.is_some() {
+            return Err(MorayError::Server(ServerError::new(
+                "BucketConflictError",
+                format!("{} already exists", name),
+            )));
+        }
+
+        let bucket = Bucket::from_config(name, config.clone(), String::new());
+        txn.batch_execute(&format!(
+            "CREATE TABLE {table} (
+                 _id SERIAL UNIQUE,
+                 _txn_snap INTEGER,
+                 _key TEXT PRIMARY KEY,
+                 _value TEXT NOT NULL,
+                 _etag CHAR(8) NOT NULL,
+                 _mtime BIGINT NOT NULL,
+                 _vnode BIGINT,
+                 _rver INTEGER
+             );
+             CREATE INDEX {etag} ON {table} USING BTREE (_etag);
+             CREATE INDEX {mtime} ON {table} USING BTREE (_mtime);
+             CREATE INDEX {vnode} ON {table} USING BTREE (_vnode);",
+            table = quote_ident(name),
+            etag = index_name(name, "_etag"),
+            mtime = index_name(name, "_mtime"),
+            vnode = index_name(name, "_vnode"),
+        ))?;
+        add_columns(&mut txn, &bucket)?;
+        for (field, def) in bucket.index() {
+            create_index(&mut txn, name, field, def)?;
+        }
+        save_bucket(&mut txn, &bucket)?;
+
+        Ok(txn.commit()?)
+    }
+
+    /// The bucket's version must increase, as with `buckets::update_bucket`.
+    /// Indexes that were removed or changed are dropped, and new ones are
+    /// created, but existing objects must be reindexed with `reindex_objects`.
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let current = find_bucket(&mut txn, name)?;
+        buckets::check_bucket_update(name, current.as_ref(), config)?;
+
+        let bucket = Bucket::from_config(name, config.clone(), String::new());
+        if let Some(current) = current {
+            for (field, def) in current.index() {
+                if bucket.index().get(field) != Some(def) {
+                    txn.execute(
+                        format!(
+                            "DROP INDEX IF EXISTS {}",
+                            index_name(name, field)
+                        )
+                        .as_str(),
+                        &[],
+                    )?;
+                }
+            }
+        }
+        add_columns(&mut txn, &bucket)?;
+        for (field, def) in bucket.index() {
+            create_index(&mut txn, name, field, def)?;
+        }
+        save_bucket(&mut txn, &bucket)?;
+
+        Ok(txn.commit()?)
+    }
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        _opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        get_bucket(&mut txn, name)?;
+
+        txn.execute(format!("DROP TABLE {}", quote_ident(name)).as_str(), &[])?;
+        txn.execute(
+            "DELETE FROM buckets_config WHERE name = $1::text",
+            &[&name],
+        )?;
+
+        Ok(txn.commit()?)
+    }
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        _opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let bucket = get_bucket(&mut txn, bucket)?;
+        let row = find_row(&mut txn, &bucket, key)?
+            .ok_or_else(|| objects::object_not_found(bucket.name(), key))?;
+        txn.commit()?;
+
+        Ok(object_handler(&row.into_object(bucket.name(), 0)?)?)
+    }
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let bucket = get_bucket(&mut txn, bucket)?;
+        let rows = find_rows(&mut txn, &bucket, filter, opts)?;
+        txn.commit()?;
+
+        for (row, count) in rows {
+            object_handler(&row.into_object(bucket.name(), count)?)?;
+        }
+        Ok(())
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let etag = put_object(&mut txn, bucket, key, &value, opts)?;
+        txn.commit()?;
+
+        Ok(object_handler(&etag)?)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        delete_object(&mut txn, bucket, key, opts)?;
+        Ok(txn.commit()?)
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        _opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError> {
+        let mut txn = self.client.transaction()?;
+        let ret = update_objects(&mut txn, bucket, &fields, filter)?;
+        txn.commit()?;
+        Ok(ret)
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError> {
+        let mut txn = self.client.transaction()?;
+        let count = delete_many(&mut txn, bucket, filter, opts)?;
+        txn.commit()?;
+        Ok(count)
+    }
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        _opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        let mut txn = self.client.transaction()?;
+        let ret = reindex_objects(&mut txn, bucket, count)?;
+        txn.commit()?;
+        Ok(ret)
+    }
+
+    /// The requests are run in a transaction, so either all of them are
+    /// applied or none are.
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        _opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let mut txn = self.client.transaction()?;
+        let mut results = vec![];
+
+        for req in requests {
+            let result = match req {
+                BatchRequest::Put(op) => BatchResult::Put {
+                    etag: put_object(
+                        &mut txn,
+                        &op.bucket,
+                        &op.key,
+                        &op.value,
+                        &op.options,
+                    )?,
+                },
+                BatchRequest::Update(op) => {
+                    let ret = update_objects(
+                        &mut txn, &op.bucket, &op.fields, &op.filter,
+                    )?;
+                    BatchResult::Update {
+                        count: ret.count,
+                        etag: Some(ret.etag),
+                    }
+                }
+                BatchRequest::Delete(op) => {
+                    delete_object(&mut txn, &op.bucket, &op.key, &op.options)?;
+                    BatchResult::Delete
+                }
+                BatchRequest::DeleteMany(op) => BatchResult::DeleteMany {
+                    count: delete_many(
+                        &mut txn,
+                        &op.bucket,
+                        &op.filter,
+                        &op.options,
+                    )?,
+                },
+            };
+            results.push(result);
+        }
+
+        txn.commit()?;
+        Ok(batch_handler(results)?)
+    }
+
+    /// Run a statement with `vals` as its parameters, passing each row it
+    /// returns to the handler as an object keyed by column name, as moray
+    /// does.  As with moray, the parameters are untyped, and Postgres infers
+    /// their types from the statement.
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        _opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let stmt = stmt.trim_end().trim_end_matches(';');
+
+        let mut txn = self.client.transaction()?;
+        let statement = txn.prepare(stmt)?;
+        if statement.columns().is_empty() {
+            let params = typed_params(&mut txn, statement.params(), &vals)?;
+            txn.execute(&statement, &bind(&params))?;
+            return Ok(txn.commit()?);
+        }
+
+        // Let Postgres convert each row to JSON, whatever its column types.
+        let rows = txn.prepare(&format!(
+            "WITH q AS ({}) SELECT row_to_json(q)::text FROM q",
+            stmt
+        ))?;
+        let params = typed_params(&mut txn, rows.params(), &vals)?;
+        let rows = txn.query(&rows, &bind(&params))?;
+        txn.commit()?;
+
+        for row in rows {
+            let json: String = row.try_get(0)?;
+            query_handler(&serde_json::from_str(&json)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buckets::IndexType;
+    use crate::objects::Etag;
+    use std::env;
+    use uuid::Uuid;
+
+    // These tests need a Postgres database to create tables in, named by
+    // MORAY_TEST_PG_URL, e.g. "host=localhost user=postgres dbname=moray", so
+    // they only run when asked for with `cargo test --features postgres --
+    // --ignored`.
+    fn connect() -> PgMoray {
+        let url = env::var("MORAY_TEST_PG_URL")
+            .expect("MORAY_TEST_PG_URL must name a database to test with");
+        let mut moray = PgMoray::connect(&url).unwrap();
+        moray.create_buckets_config().unwrap();
+        moray
+    }
+
+    // A bucket that won't collide with other tests, or earlier runs.
+    fn create_bucket(moray: &mut PgMoray, config: &BucketConfig) -> String {
+        let name = format!("test_{}", Uuid::new_v4().to_simple());
+        moray
+            .create_bucket(&name, config, buckets::MethodOptions::default())
+            .unwrap();
+        name
+    }
+
+    fn keys(moray: &mut PgMoray, bucket: &str, filter: &str) -> Vec<String> {
+        let mut keys = vec![];
+        let opts = MethodOptions::default();
+        moray
+            .find_objects(bucket, filter, &opts, &mut |o| {
+                keys.push(o.key.clone());
+                Ok(())
+            })
+            .unwrap();
+        keys
+    }
+
+    fn put(moray: &mut PgMoray, bucket: &str, key: &str, value: Value) {
+        let opts = MethodOptions::default();
+        moray
+            .put_object(bucket, key, value, &opts, &mut |_| Ok(()))
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn objects_test() {
+        let mut moray = connect();
+        let config = BucketConfig::new()
+            .with_index("name", IndexDef::unique(IndexType::String))
+            .with_index("size", IndexDef::new(IndexType::Number))
+            .with_index("tags", IndexDef::new(IndexType::StringArray))
+            .with_index("hidden", IndexDef::new(IndexType::Boolean));
+        let b = create_bucket(&mut moray, &config);
+
+        put(&mut moray, &b, "k1", json!({ "name": "Apple", "size": 5 }));
+        put(
+            &mut moray,
+            &b,
+            "k2",
+            json!({ "name": "b\"c", "size": 20, "tags": ["x", "y\"z"] }),
+        );
+        put(&mut moray, &b, "k3", json!({ "tags": "w", "hidden": true }));
+
+        let cases = vec![
+            ("(name=Apple)", vec!["k1"]),
+            ("(name:caseIgnoreMatch:=apple)", vec!["k1"]),
+            ("(name=b*)", vec!["k2"]),
+            ("(size>=6)", vec!["k2"]),
+            ("(!(size>=6))", vec!["k1", "k3"]),
+            ("(tags=y\"z)", vec!["k2"]),
+            ("(tags=w)", vec!["k3"]),
+            ("(hidden=true)", vec!["k3"]),
+            ("(|(_key=k1)(_id>=3))", vec!["k1", "k3"]),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(keys(&mut moray, &b, filter), expected, "{}", filter);
+        }
+
+        let mut opts = MethodOptions::default();
+        opts.etag = Etag::Nulled;
+        let value = json!({ "name": "Apple" });
+        match moray.put_object(&b, "k4", value, &opts, &mut |_| Ok(())) {
+            Err(MorayError::UniqueAttribute(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match moray.delete_object(&b, "k4", &opts) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let opts = MethodOptions::default();
+        let ret = moray
+            .update_objects(&b, json!({ "size": 1 }), "(size<=20)", &opts)
+            .unwrap();
+        assert_eq!(ret.count, 2);
+        assert_eq!(keys(&mut moray, &b, "(size=1)"), vec!["k1", "k2"]);
+        let mut found = vec![];
+        moray
+            .get_object(&b, "k1", &opts, &mut |o| {
+                found.push(o.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found[0].value, json!({ "name": "Apple", "size": 5 }));
+        assert_eq!(found[0]._etag, ret.etag);
+        let fields = json!({ "color": "red" });
+        match moray.update_objects(&b, fields, "(size<=20)", &opts) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "FieldUpdateError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        moray
+            .delete_bucket(&b, buckets::MethodOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn reindex_test() {
+        let mut moray = connect();
+        let config = BucketConfig::new().with_version(1);
+        let b = create_bucket(&mut moray, &config);
+        for i in 0..3 {
+            let key = format!("k{}", i);
+            put(&mut moray, &b, &key, json!({ "owner": "abc" }));
+        }
+
+        let config = config
+            .with_index("owner", IndexDef::new(IndexType::String))
+            .with_version(2);
+        moray
+            .update_bucket(&b, &config, buckets::MethodOptions::default())
+            .unwrap();
+        assert!(keys(&mut moray, &b, "(owner=abc)").is_empty());
+
+        let opts = MethodOptions::default();
+        let ret = moray.reindex_objects(&b, 2, &opts).unwrap();
+        assert_eq!((ret.processed, ret.remaining), (2, Some(1)));
+        let ret = moray.reindex_objects(&b, 2, &opts).unwrap();
+        assert_eq!((ret.processed, ret.remaining), (1, Some(0)));
+        assert_eq!(keys(&mut moray, &b, "(owner=abc)").len(), 3);
+
+        let mut rows = vec![];
+        let stmt = format!(
+            "SELECT count(*) AS n FROM {} WHERE _rver = $1 AND _key > $2",
+            b
+        );
+        moray
+            .sql(&stmt, vec!["2", "k0"], json!({}), &mut |row| {
+                rows.push(row.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows, vec![json!({ "n": 2 })]);
+
+        moray
+            .delete_bucket(&b, buckets::MethodOptions::default())
+            .unwrap();
+    }
+}
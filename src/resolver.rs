@@ -0,0 +1,477 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use cueball::backend::{self, Backend};
+use cueball::resolver::{
+    BackendAddedMsg, BackendMsg, BackendRemovedMsg, Resolver,
+};
+use slog::{debug, error, info, warn, Logger};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::Resolver as TrustResolver;
+
+/// The port moray listens on when a domain has no SRV records.
+pub const DEFAULT_MORAY_PORT: u16 = 2020;
+
+/// How often the DNS records are looked up again by default.
+pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The shortest interval between lookups.  Shorter intervals, including zero,
+/// are raised to this so that the resolver never spins on the DNS server.
+pub const MIN_RESOLVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often the resolver checks that the connection pool is still running.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A cueball resolver that finds the moray instances for a service domain,
+/// e.g. "1.moray.us-east.joyent.us", through DNS.
+///
+/// The SRV records for `_moray._tcp.<domain>` are looked up first, and each
+/// SRV target is resolved to its addresses.  If there are no SRV records the
+/// A records of the domain itself are used, with `DEFAULT_MORAY_PORT`.  The
+/// records are looked up again every `resolve_interval`, and backends are added
+/// to or removed from the connection pool as they appear or disappear.  If a
+/// lookup fails, or returns no records, the existing backends are kept.
+#[derive(Clone)]
+pub struct DnsResolver {
+    domain: String,
+    config: Option<(ResolverConfig, ResolverOpts)>,
+    default_port: u16,
+    resolve_interval: Duration,
+    log: Logger,
+}
+
+impl DnsResolver {
+    /// Create a resolver that uses the system's DNS configuration.
+    pub fn new(domain: &str, log: Logger) -> Self {
+        Self {
+            domain: domain.to_string(),
+            config: None,
+            default_port: DEFAULT_MORAY_PORT,
+            resolve_interval: DEFAULT_RESOLVE_INTERVAL,
+            log,
+        }
+    }
+
+    /// Create a resolver that uses the given DNS configuration rather than the
+    /// system's.
+    pub fn with_config(
+        domain: &str,
+        config: ResolverConfig,
+        opts: ResolverOpts,
+        log: Logger,
+    ) -> Self {
+        Self {
+            config: Some((config, opts)),
+            ..Self::new(domain, log)
+        }
+    }
+
+    pub fn default_port(mut self, port: u16) -> Self {
+        self.default_port = port;
+        self
+    }
+
+    /// Set how often the DNS records are looked up again.  This is at least
+    /// `MIN_RESOLVE_INTERVAL`.
+    pub fn resolve_interval(mut self, interval: Duration) -> Self {
+        self.resolve_interval = std::cmp::max(interval, MIN_RESOLVE_INTERVAL);
+        self
+    }
+
+    fn dns_resolver(&self) -> Result<TrustResolver, String> {
+        match &self.config {
+            Some((config, opts)) => {
+                TrustResolver::new(config.clone(), opts.clone())
+            }
+            None => TrustResolver::from_system_conf(),
+        }
+        .map_err(|e| format!("Failed to create DNS resolver: {}", e))
+    }
+
+    /// Look up the current set of moray backends.
+    fn resolve(
+        &self,
+        resolver: &TrustResolver,
+    ) -> Result<HashSet<(IpAddr, u16)>, String> {
+        let mut backends = HashSet::new();
+        let srv_name = format!("_moray._tcp.{}", self.domain);
+
+        match resolver.lookup_srv(srv_name.as_str()) {
+            Ok(srv_lookup) => {
+                for srv in srv_lookup.iter() {
+                    let target = srv.target().to_utf8();
+                    match resolver.lookup_ip(target.as_str()) {
+                        Ok(ips) => {
+                            for ip in ips.iter() {
+                                backends.insert((ip, srv.port()));
+                            }
+                        }
+                        Err(e) => warn!(
+                            self.log,
+                            "failed to resolve SRV target";
+                            "target" => &target,
+                            "error" => e.to_string()
+                        ),
+                    }
+                }
+            }
+            Err(e) => debug!(
+                self.log,
+                "no SRV records, falling back to A records";
+                "name" => &srv_name,
+                "error" => e.to_string()
+            ),
+        }
+
+        if backends.is_empty() {
+            let ips = resolver
+                .lookup_ip(self.domain.as_str())
+                .map_err(|e| format!("{}: {}", self.domain, e))?;
+            for ip in ips.iter() {
+                backends.insert((ip, self.default_port));
+            }
+        }
+
+        Ok(backends)
+    }
+}
+
+fn backend_key(address: &(IpAddr, u16)) -> backend::BackendKey {
+    backend::srv_key(&Backend::new(&address.0, address.1))
+}
+
+impl Resolver for DnsResolver {
+    fn run(&mut self, s: Sender<BackendMsg>) {
+        let resolver = match self.dns_resolver() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!(self.log, "{}", e);
+                return;
+            }
+        };
+
+        let mut backends: HashSet<(IpAddr, u16)> = HashSet::new();
+        let mut next_resolve = Instant::now();
+
+        loop {
+            if Instant::now() >= next_resolve {
+                match self.resolve(&resolver) {
+                    Ok(ref found) if found.is_empty() => warn!(
+                        self.log,
+                        "no moray backends found, keeping existing backends";
+                        "domain" => &self.domain
+                    ),
+                    Ok(found) => {
+                        for added in found.difference(&backends) {
+                            info!(self.log, "adding backend";
+                                "address" => added.0.to_string(),
+                                "port" => added.1);
+                            let msg = BackendMsg::AddedMsg(BackendAddedMsg {
+                                key: backend_key(added),
+                                backend: Backend::new(&added.0, added.1),
+                            });
+                            if s.send(msg).is_err() {
+                                return;
+                            }
+                        }
+
+                        for removed in backends.difference(&found) {
+                            info!(self.log, "removing backend";
+                                "address" => removed.0.to_string(),
+                                "port" => removed.1);
+                            let msg = BackendMsg::RemovedMsg(
+                                BackendRemovedMsg(backend_key(removed)),
+                            );
+                            if s.send(msg).is_err() {
+                                return;
+                            }
+                        }
+
+                        backends = found;
+                    }
+                    Err(e) => warn!(
+                        self.log,
+                        "DNS lookup failed, keeping existing backends";
+                        "error" => e
+                    ),
+                }
+                next_resolve = Instant::now() + self.resolve_interval;
+            }
+
+            // The pool hangs up on us when it is stopped.
+            if s.send(BackendMsg::HeartbeatMsg).is_err() {
+                return;
+            }
+
+            thread::sleep(std::cmp::min(
+                HEARTBEAT_INTERVAL,
+                self.resolve_interval,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Discard};
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use trust_dns_resolver::config::NameServerConfigGroup;
+
+    const TYPE_A: u16 = 1;
+    const TYPE_SRV: u16 = 33;
+
+    #[derive(Clone)]
+    enum Record {
+        A(Ipv4Addr),
+        Srv(u16, String),
+    }
+
+    type Zone = Arc<Mutex<HashMap<(String, u16), Vec<Record>>>>;
+
+    fn encode_name(name: &str, buf: &mut Vec<u8>) {
+        for label in name.trim_end_matches('.').split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.push(0);
+    }
+
+    // Answer a single DNS query from the records in `zone`.  This is just
+    // enough of DNS for trust-dns to resolve A and SRV records against.
+    fn answer(query: &[u8], zone: &Zone) -> Option<Vec<u8>> {
+        if query.len() < 12 {
+            return None;
+        }
+
+        let mut labels = vec![];
+        let mut pos = 12;
+        while pos < query.len() && query[pos] != 0 {
+            let len = query[pos] as usize;
+            let label = query.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += len + 1;
+        }
+        let question_end = pos + 5;
+        let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+        let name = labels.join(".");
+
+        let records = zone
+            .lock()
+            .unwrap()
+            .get(&(name, qtype))
+            .cloned()
+            .unwrap_or_default();
+
+        let mut resp = vec![];
+        resp.extend_from_slice(&query[0..2]);
+        // Standard response, NXDOMAIN if we have nothing
+        let rcode = if records.is_empty() { 3 } else { 0 };
+        resp.extend_from_slice(&[0x81, 0x80 | rcode]);
+        resp.extend_from_slice(&[0, 1]);
+        resp.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        resp.extend_from_slice(&[0, 0, 0, 0]);
+        resp.extend_from_slice(&query[12..question_end]);
+
+        for record in records {
+            let mut rdata = vec![];
+            let rtype = match record {
+                Record::A(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    TYPE_A
+                }
+                Record::Srv(port, target) => {
+                    rdata.extend_from_slice(&[0, 0, 0, 0]);
+                    rdata.extend_from_slice(&port.to_be_bytes());
+                    encode_name(&target, &mut rdata);
+                    TYPE_SRV
+                }
+            };
+            // Name is a pointer to the question, class IN, TTL 0
+            resp.extend_from_slice(&[0xc0, 0x0c]);
+            resp.extend_from_slice(&rtype.to_be_bytes());
+            resp.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+            resp.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            resp.extend_from_slice(&rdata);
+        }
+
+        Some(resp)
+    }
+
+    fn stub_dns_server(zone: Zone) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                if let Some(resp) = answer(&buf[..len], &zone) {
+                    let _ = socket.send_to(&resp, peer);
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn test_resolver(domain: &str, dns: SocketAddr) -> DnsResolver {
+        let config = ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[dns.ip()], dns.port()),
+        );
+        let mut opts = ResolverOpts::default();
+        opts.cache_size = 0;
+        opts.attempts = 1;
+        opts.timeout = Duration::from_millis(500);
+
+        DnsResolver::with_config(
+            domain,
+            config,
+            opts,
+            Logger::root(Discard, o!()),
+        )
+    }
+
+    fn insert(zone: &Zone, name: &str, qtype: u16, records: Vec<Record>) {
+        zone.lock()
+            .unwrap()
+            .insert((name.to_string(), qtype), records);
+    }
+
+    fn ip(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, last)
+    }
+
+    #[test]
+    fn resolve_srv_test() {
+        let zone: Zone = Arc::new(Mutex::new(HashMap::new()));
+        insert(
+            &zone,
+            "_moray._tcp.1.moray.test",
+            TYPE_SRV,
+            vec![
+                Record::Srv(2021, String::from("a.1.moray.test")),
+                Record::Srv(2022, String::from("b.1.moray.test")),
+            ],
+        );
+        insert(&zone, "a.1.moray.test", TYPE_A, vec![Record::A(ip(1))]);
+        insert(&zone, "b.1.moray.test", TYPE_A, vec![Record::A(ip(2))]);
+
+        let resolver = test_resolver("1.moray.test", stub_dns_server(zone));
+        let backends = resolver.resolve(&resolver.dns_resolver().unwrap());
+
+        let expected: HashSet<(IpAddr, u16)> =
+            vec![(ip(1).into(), 2021), (ip(2).into(), 2022)]
+                .into_iter()
+                .collect();
+        assert_eq!(backends, Ok(expected));
+    }
+
+    #[test]
+    fn resolve_a_fallback_test() {
+        let zone: Zone = Arc::new(Mutex::new(HashMap::new()));
+        insert(
+            &zone,
+            "2.moray.test",
+            TYPE_A,
+            vec![Record::A(ip(3)), Record::A(ip(4))],
+        );
+
+        let dns = stub_dns_server(zone);
+        let resolver = test_resolver("2.moray.test", dns);
+        let backends = resolver.resolve(&resolver.dns_resolver().unwrap());
+
+        let expected: HashSet<(IpAddr, u16)> = vec![
+            (ip(3).into(), DEFAULT_MORAY_PORT),
+            (ip(4).into(), DEFAULT_MORAY_PORT),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(backends, Ok(expected));
+
+        // Neither SRV nor A records
+        let resolver = test_resolver("3.moray.test", dns);
+        assert!(resolver.resolve(&resolver.dns_resolver().unwrap()).is_err());
+    }
+
+    // Wait for the next backend added or removed message, skipping heartbeats.
+    fn next_change(
+        rx: &std::sync::mpsc::Receiver<BackendMsg>,
+    ) -> (bool, (IpAddr, u16)) {
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+                BackendMsg::AddedMsg(msg) => {
+                    return (true, (msg.backend.address, msg.backend.port));
+                }
+                BackendMsg::RemovedMsg(msg) => {
+                    let removed = [(ip(5), 2021), (ip(6), 2021)]
+                        .iter()
+                        .map(|(ip, port)| (IpAddr::from(*ip), *port))
+                        .find(|b| backend_key(b) == msg.0)
+                        .expect("removed backend");
+                    return (false, removed);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_interval_test() {
+        let resolver =
+            DnsResolver::new("moray.test", Logger::root(Discard, o!()));
+        assert_eq!(resolver.resolve_interval, DEFAULT_RESOLVE_INTERVAL);
+
+        let resolver = resolver.resolve_interval(Duration::from_secs(0));
+        assert_eq!(resolver.resolve_interval, MIN_RESOLVE_INTERVAL);
+
+        let resolver = resolver.resolve_interval(Duration::from_secs(5));
+        assert_eq!(resolver.resolve_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_re_resolves_test() {
+        let zone: Zone = Arc::new(Mutex::new(HashMap::new()));
+        insert(&zone, "4.moray.test", TYPE_A, vec![Record::A(ip(5))]);
+
+        let mut resolver =
+            test_resolver("4.moray.test", stub_dns_server(zone.clone()))
+                .default_port(2021)
+                .resolve_interval(MIN_RESOLVE_INTERVAL);
+        let (tx, rx) = channel();
+        let handle = thread::spawn(move || resolver.run(tx));
+
+        assert_eq!(next_change(&rx), (true, (ip(5).into(), 2021)));
+
+        // Failover to a new moray zone
+        insert(&zone, "4.moray.test", TYPE_A, vec![Record::A(ip(6))]);
+        let mut changes = vec![next_change(&rx), next_change(&rx)];
+        changes.sort();
+        assert_eq!(
+            changes,
+            vec![(false, (ip(5).into(), 2021)), (true, (ip(6).into(), 2021))]
+        );
+
+        // An empty lookup keeps the existing backends
+        insert(&zone, "4.moray.test", TYPE_A, vec![]);
+        thread::sleep(MIN_RESOLVE_INTERVAL * 3);
+        assert!(rx.try_iter().all(|msg| match msg {
+            BackendMsg::AddedMsg(_) | BackendMsg::RemovedMsg(_) => false,
+            _ => true,
+        }));
+
+        // The resolver stops once the pool goes away
+        drop(rx);
+        handle.join().unwrap();
+    }
+}
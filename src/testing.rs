@@ -0,0 +1,1381 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! An in-process moray server for tests.
+//!
+//! `MockMoray` speaks Fast on an ephemeral port of the loopback interface and
+//! implements the bucket and object RPCs against an in-memory store, so that
+//! a `MorayClient` can be pointed at it without a moray deployment:
+//!
+//! ```
+//! use moray::buckets::{self, BucketConfig, IndexDef, IndexType};
+//! use moray::client::MorayClient;
+//! use moray::objects::MethodOptions;
+//! use moray::testing::MockMoray;
+//! use serde_json::json;
+//! use slog::{o, Discard, Logger};
+//!
+//! let mock = MockMoray::start().unwrap();
+//! let log = Logger::root(Discard, o!());
+//! let mut client = MorayClient::new(mock.address(), log, None).unwrap();
+//!
+//! let config = BucketConfig::new()
+//!     .with_index("owner", IndexDef::new(IndexType::String));
+//! let bucket_opts = buckets::MethodOptions::default();
+//! client.create_bucket("manta", &config, bucket_opts).unwrap();
+//!
+//! let opts = MethodOptions::default();
+//! let value = json!({ "owner": "abc" });
+//! client
+//!     .put_object("manta", "/a/b", value, &opts, |_| Ok(()))
+//!     .unwrap();
+//! let found: Vec<_> = client
+//!     .find_objects_iter("manta", "(owner=abc)", &opts)
+//!     .unwrap()
+//!     .collect();
+//! assert_eq!(found.len(), 1);
+//! ```
+//!
+//! The store follows moray's rules where tests are likely to depend on them:
+//! etags are checked on put and delete, a batch is applied atomically, and
+//! filters may only reference indexed fields.  Filters are evaluated against
+//! the objects' values directly, and pre and post triggers are ignored.  The
+//! `sql` RPC is not supported.
+//!
+//! Faults can be injected into the responses to particular RPCs, to test how
+//! errors, slow responses and broken connections are handled:
+//!
+//! ```ignore
+//! mock.inject_fault("findObjects", Fault::Disconnect { after: 2 });
+//! mock.inject_fault("putObject", Fault::error("NoDatabasePeersError", "x"));
+//! ```
+//!
+//! Code that takes a `dyn MorayApi` can be unit tested against `FakeMoray`,
+//! which implements the trait over the same store without a connection.
+
+use rust_fast::protocol::{FastMessage, FastMessageData};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{Error, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::api::MorayApi;
+use super::buckets::{self, now_iso, Bucket, BucketConfig};
+use super::error::{MorayError, ServerError};
+use super::fast;
+use super::filter::Filter;
+use super::meta;
+use super::objects::{
+    self, etag, now_millis, update_etag, BatchRequest, BatchResult,
+    MethodOptions, MorayObject, ReindexObjectsReturn, Sort, SortOrder,
+    UpdateObjectsReturn,
+};
+
+pub mod transcript;
+
+// The most objects findObjects returns when no limit is given, as with moray.
+const DEFAULT_FIND_LIMIT: u64 = 1000;
+
+#[derive(Clone)]
+struct StoredBucket {
+    bucket: Bucket,
+    // Objects as getObject returns them, by key.
+    objects: BTreeMap<String, Value>,
+    // The index columns set by updateObjects, which leaves the values of the
+    // objects themselves alone, by key.
+    updated: BTreeMap<String, Map<String, Value>>,
+}
+
+impl StoredBucket {
+    // An object as filters and sorts see it, with the fields set by
+    // updateObjects in place of those in its value.
+    fn index_view<'a>(&self, object: &'a Value) -> Cow<'a, Value> {
+        let updated =
+            object["key"].as_str().and_then(|key| self.updated.get(key));
+
+        match updated {
+            None => Cow::Borrowed(object),
+            Some(fields) => {
+                let mut view = object.clone();
+                for (field, value) in fields {
+                    view["value"][field] = value.clone();
+                }
+                Cow::Owned(view)
+            }
+        }
+    }
+}
+
+/// The buckets and objects of a mock moray.
+#[derive(Clone, Default)]
+pub(crate) struct Store {
+    buckets: BTreeMap<String, StoredBucket>,
+    next_id: u64,
+}
+
+impl Store {
+    /// Handle a single RPC, returning the data of each DATA message in the
+    /// response or the error to send instead.
+    pub(crate) fn dispatch(
+        &mut self,
+        method: &str,
+        args: &Value,
+    ) -> Result<Vec<Value>, ServerError> {
+        let args = args.as_array().map(Vec::as_slice).unwrap_or(&[]);
+
+        match method {
+            "createBucket" => {
+                self.create_bucket(str_arg(args, 0)?, config_arg(args, 1)?)
+            }
+            "updateBucket" => {
+                self.update_bucket(str_arg(args, 0)?, config_arg(args, 1)?)
+            }
+            "delBucket" => self.delete_bucket(str_arg(args, 0)?),
+            "getBucket" => {
+                let bucket = self.bucket(str_arg(args, 1)?)?;
+                Ok(vec![json!([wire_bucket(&bucket.bucket)?])])
+            }
+            "listBuckets" => self
+                .buckets
+                .values()
+                .map(|b| Ok(json!([wire_bucket(&b.bucket)?])))
+                .collect(),
+            "getObject" => {
+                let bucket = self.bucket(str_arg(args, 0)?)?;
+                let key = str_arg(args, 1)?;
+                match bucket.objects.get(key) {
+                    Some(object) => Ok(vec![json!([object])]),
+                    None => Err(object_not_found(&bucket.bucket, key)),
+                }
+            }
+            "findObjects" => self.find_objects(
+                str_arg(args, 0)?,
+                str_arg(args, 1)?,
+                arg(args, 2)?,
+            ),
+            "putObject" => self
+                .put_object(
+                    str_arg(args, 0)?,
+                    str_arg(args, 1)?,
+                    arg(args, 2)?,
+                    arg(args, 3)?,
+                )
+                .map(|etag| vec![json!([{ "etag": etag }])]),
+            "delObject" => self
+                .delete_object(
+                    str_arg(args, 0)?,
+                    str_arg(args, 1)?,
+                    arg(args, 2)?,
+                )
+                .map(|_| vec![]),
+            "updateObjects" => self
+                .update_objects(
+                    str_arg(args, 0)?,
+                    arg(args, 1)?,
+                    str_arg(args, 2)?,
+                )
+                .map(|(count, etag)| {
+                    vec![json!([{ "count": count, "etag": etag }])]
+                }),
+            "deleteMany" => self
+                .delete_many(
+                    str_arg(args, 0)?,
+                    str_arg(args, 1)?,
+                    arg(args, 2)?,
+                )
+                .map(|count| vec![json!([{ "count": count }])]),
+            "reindexObjects" => {
+                self.bucket(str_arg(args, 0)?)?;
+                Ok(vec![json!([{ "processed": 0, "remaining": 0 }])])
+            }
+            "batch" => self.batch(arg(args, 0)?),
+            _ => Err(ServerError::new(
+                "FastError",
+                format!("unsupported RPC method: \"{}\"", method),
+            )),
+        }
+    }
+
+    fn bucket(&self, name: &str) -> Result<&StoredBucket, ServerError> {
+        self.buckets.get(name).ok_or_else(|| bucket_not_found(name))
+    }
+
+    fn bucket_mut(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut StoredBucket, ServerError> {
+        self.buckets
+            .get_mut(name)
+            .ok_or_else(|| bucket_not_found(name))
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: BucketConfig,
+    ) -> Result<Vec<Value>, ServerError> {
+        if self.buckets.contains_key(name) {
+            return Err(ServerError::new(
+                "BucketConflictError",
+                format!("{} already exists", name),
+            ));
+        }
+
+        let bucket = Bucket::from_config(name, config, now_iso());
+        self.buckets.insert(
+            name.to_string(),
+            StoredBucket {
+                bucket,
+                objects: BTreeMap::new(),
+                updated: BTreeMap::new(),
+            },
+        );
+        Ok(vec![])
+    }
+
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: BucketConfig,
+    ) -> Result<Vec<Value>, ServerError> {
+        let stored = self.bucket_mut(name)?;
+        let current = stored.bucket.options().version();
+        let version = config.options.version();
+
+        if current != 0 && version <= current {
+            return Err(ServerError::new(
+                "BucketVersionError",
+                format!(
+                    "{} has a newer version than {} ({})",
+                    name, version, current
+                ),
+            ));
+        }
+
+        stored.bucket = Bucket::from_config(name, config, now_iso());
+        Ok(vec![])
+    }
+
+    fn delete_bucket(&mut self, name: &str) -> Result<Vec<Value>, ServerError> {
+        self.buckets
+            .remove(name)
+            .map(|_| vec![])
+            .ok_or_else(|| bucket_not_found(name))
+    }
+
+    // The objects in `bucket` that match `filter`, in the order they were
+    // created.
+    fn matching(
+        &self,
+        bucket: &str,
+        filter: &str,
+    ) -> Result<Vec<&Value>, ServerError> {
+        let stored = self.bucket(bucket)?;
+        let filter: Filter = filter
+            .parse()
+            .and_then(|f: Filter| f.validate(&stored.bucket).map(|_| f))
+            .map_err(|e| {
+                ServerError::new("InvalidQueryError", e.to_string())
+            })?;
+
+        let mut found: Vec<&Value> = stored
+            .objects
+            .values()
+            .filter(|object| filter.matches(&stored.index_view(object)))
+            .collect();
+        found.sort_by_key(|object| object["_id"].as_u64());
+        Ok(found)
+    }
+
+    fn find_objects(
+        &self,
+        bucket: &str,
+        filter: &str,
+        opts: &Value,
+    ) -> Result<Vec<Value>, ServerError> {
+        let stored = self.bucket(bucket)?;
+        let mut found = self.matching(bucket, filter)?;
+        let count = found.len();
+        let limit = opts
+            .get("limit")
+            .and_then(Value::as_u64)
+            .unwrap_or(DEFAULT_FIND_LIMIT);
+        let offset = opts.get("offset").and_then(Value::as_u64).unwrap_or(0);
+
+        if let Some(sort) = opts.get("sort") {
+            let sort: Sort =
+                serde_json::from_value(sort.clone()).map_err(|e| {
+                    ServerError::new("InvocationError", e.to_string())
+                })?;
+            found.sort_by(|a, b| {
+                let (a, b) = (stored.index_view(a), stored.index_view(b));
+                let ordering =
+                    compare(sort_value(&a, &sort), sort_value(&b, &sort));
+                match sort.order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            });
+        }
+
+        Ok(found
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|object| {
+                let mut object = object.clone();
+                object["_count"] = json!(count);
+                json!([object])
+            })
+            .collect())
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: &Value,
+        opts: &Value,
+    ) -> Result<String, ServerError> {
+        let next_id = self.next_id + 1;
+        let stored = self.bucket_mut(bucket)?;
+        let current = stored.objects.get(key);
+        check_etag(bucket, key, opts, current)?;
+
+        let id = match current {
+            Some(object) => object["_id"].clone(),
+            None => json!(next_id),
+        };
+        let etag = etag(value);
+        stored.updated.remove(key);
+        stored.objects.insert(
+            key.to_string(),
+            json!({
+                "bucket": bucket,
+                "key": key,
+                "value": value,
+                "_id": id,
+                "_etag": etag,
+                "_mtime": now_millis(),
+                "_txn_snap": null,
+            }),
+        );
+        self.next_id = next_id;
+
+        Ok(etag)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &Value,
+    ) -> Result<(), ServerError> {
+        let stored = self.bucket_mut(bucket)?;
+        let current = stored
+            .objects
+            .get(key)
+            .ok_or_else(|| object_not_found(&stored.bucket, key))?;
+        check_etag(bucket, key, opts, Some(current))?;
+
+        stored.objects.remove(key);
+        stored.updated.remove(key);
+        Ok(())
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: &Value,
+        filter: &str,
+    ) -> Result<(u64, String), ServerError> {
+        let fields =
+            objects::update_fields(&self.bucket(bucket)?.bucket, fields)?;
+        let keys: Vec<String> = self
+            .matching(bucket, filter)?
+            .iter()
+            .filter_map(|object| object["key"].as_str().map(String::from))
+            .collect();
+
+        // Every object updated by the call gets the same etag.
+        let etag = update_etag();
+        let stored = self.bucket_mut(bucket)?;
+        for key in &keys {
+            let object = stored.objects.get_mut(key).expect("matched object");
+            object["_etag"] = json!(etag);
+            stored
+                .updated
+                .entry(key.clone())
+                .or_default()
+                .extend(fields.clone());
+        }
+
+        Ok((keys.len() as u64, etag))
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &Value,
+    ) -> Result<u64, ServerError> {
+        let limit = opts.get("limit").and_then(Value::as_u64);
+        let keys: Vec<String> = self
+            .matching(bucket, filter)?
+            .iter()
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .filter_map(|object| object["key"].as_str().map(String::from))
+            .collect();
+
+        let stored = self.bucket_mut(bucket)?;
+        for key in &keys {
+            stored.objects.remove(key);
+            stored.updated.remove(key);
+        }
+
+        Ok(keys.len() as u64)
+    }
+
+    // The operations are applied to a copy of the store, which replaces this
+    // one only if all of them succeed.
+    fn batch(&mut self, requests: &Value) -> Result<Vec<Value>, ServerError> {
+        let requests = requests.as_array().ok_or_else(|| {
+            ServerError::new(
+                "InvocationError",
+                String::from("requests must be an array"),
+            )
+        })?;
+        let mut txn = self.clone();
+        let mut etags = vec![];
+
+        for req in requests {
+            let bucket = str_field(req, "bucket")?;
+            let opts = req.get("options").unwrap_or(&Value::Null);
+
+            let entry = match str_field(req, "operation")? {
+                "put" => {
+                    let key = str_field(req, "key")?;
+                    let value = req.get("value").unwrap_or(&Value::Null);
+                    let etag = txn.put_object(bucket, key, value, opts)?;
+                    json!({ "bucket": bucket, "key": key, "etag": etag })
+                }
+                "update" => {
+                    let fields = req.get("fields").unwrap_or(&Value::Null);
+                    let filter = str_field(req, "filter")?;
+                    let (count, etag) =
+                        txn.update_objects(bucket, fields, filter)?;
+                    json!({ "bucket": bucket, "count": count, "etag": etag })
+                }
+                "delete" => {
+                    let key = str_field(req, "key")?;
+                    txn.delete_object(bucket, key, opts)?;
+                    json!({ "bucket": bucket, "key": key })
+                }
+                "deleteMany" => {
+                    let filter = str_field(req, "filter")?;
+                    let count = txn.delete_many(bucket, filter, opts)?;
+                    json!({ "bucket": bucket, "count": count })
+                }
+                op => {
+                    return Err(ServerError::new(
+                        "InvocationError",
+                        format!("unsupported batch operation \"{}\"", op),
+                    ))
+                }
+            };
+            etags.push(entry);
+        }
+
+        *self = txn;
+        Ok(vec![json!([{ "etags": etags }])])
+    }
+}
+
+fn arg(args: &[Value], index: usize) -> Result<&Value, ServerError> {
+    args.get(index).ok_or_else(|| {
+        ServerError::new(
+            "InvocationError",
+            format!("missing argument {}", index),
+        )
+    })
+}
+
+fn str_arg(args: &[Value], index: usize) -> Result<&str, ServerError> {
+    arg(args, index)?.as_str().ok_or_else(|| {
+        ServerError::new(
+            "InvocationError",
+            format!("argument {} must be a string", index),
+        )
+    })
+}
+
+fn config_arg(
+    args: &[Value],
+    index: usize,
+) -> Result<BucketConfig, ServerError> {
+    serde_json::from_value(arg(args, index)?.clone()).map_err(|e| {
+        ServerError::new("InvalidBucketConfigError", e.to_string())
+    })
+}
+
+fn str_field<'a>(
+    request: &'a Value,
+    field: &str,
+) -> Result<&'a str, ServerError> {
+    request.get(field).and_then(Value::as_str).ok_or_else(|| {
+        ServerError::new(
+            "InvocationError",
+            format!("batch request is missing \"{}\"", field),
+        )
+    })
+}
+
+fn bucket_not_found(name: &str) -> ServerError {
+    ServerError::new("BucketNotFoundError", format!("{} does not exist", name))
+}
+
+fn wire_bucket(bucket: &Bucket) -> Result<Value, ServerError> {
+    bucket
+        .to_wire()
+        .map_err(|e| ServerError::new("InternalError", e.to_string()))
+}
+
+fn object_not_found(bucket: &Bucket, key: &str) -> ServerError {
+    ServerError::new(
+        "ObjectNotFoundError",
+        format!("{}::{} does not exist", bucket.name(), key),
+    )
+}
+
+// The value of a sort's attribute in a stored object, which is one of moray's
+// fields or a field of the object's value.
+fn sort_value<'a>(object: &'a Value, sort: &Sort) -> &'a Value {
+    match sort.attribute.as_str() {
+        "_key" => &object["key"],
+        attr if attr.starts_with('_') => &object[attr],
+        attr => &object["value"][attr],
+    }
+}
+
+// Numbers sort numerically, and anything else by its JSON text.
+fn compare(a: &Value, b: &Value) -> cmp::Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal),
+        _ => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+// An etag of null in the options means that the object must not exist, and
+// any other etag must match the object's.
+fn check_etag(
+    bucket: &str,
+    key: &str,
+    opts: &Value,
+    current: Option<&Value>,
+) -> Result<(), ServerError> {
+    let expected = match opts.get("etag") {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    let actual = current.map_or(&Value::Null, |object| &object["_etag"]);
+
+    if expected == actual {
+        return Ok(());
+    }
+
+    let mut err = ServerError::new(
+        "EtagConflictError",
+        format!(
+            "{}::{} has etag {}, but expected {}",
+            bucket, key, actual, expected
+        ),
+    );
+    err.context = json!({
+        "bucket": bucket,
+        "key": key,
+        "expected": expected,
+        "actual": actual,
+    });
+    Err(err)
+}
+
+/// A fault to inject into the response to an RPC, see
+/// `MockMoray::inject_fault`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Wait before handling the request.
+    Delay(Duration),
+    /// Reply with a Fast ERROR carrying this moray error name and message,
+    /// without handling the request.
+    Error { name: String, message: String },
+    /// Handle the request, but send only the first `after` messages of the
+    /// response before closing the connection.
+    Disconnect { after: usize },
+    /// Like `Disconnect`, but send part of the next message of the response
+    /// before closing the connection.
+    Truncate { after: usize },
+}
+
+impl Fault {
+    pub fn error(name: &str, message: &str) -> Self {
+        Fault::Error {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+struct Server {
+    store: Mutex<Store>,
+    // Faults that have yet to be injected, and the methods they apply to.
+    faults: Mutex<Vec<(String, Fault)>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Accepts connections to a test server on an ephemeral port on 127.0.0.1,
+// handling each on its own thread, until the server is stopped.
+struct Acceptor {
+    address: SocketAddr,
+    // A handle on each accepted connection, so they can be closed on stop.
+    connections: Mutex<Vec<TcpStream>>,
+    shutdown: AtomicBool,
+}
+
+impl Acceptor {
+    fn start<F>(name: &str, handler: F) -> Result<Arc<Acceptor>, MorayError>
+    where
+        F: Fn(TcpStream) + Clone + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let acceptor = Arc::new(Acceptor {
+            address: listener.local_addr()?,
+            connections: Mutex::new(vec![]),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let thread_acceptor = Arc::clone(&acceptor);
+        let conn_name = format!("{}-conn", name);
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                thread_acceptor.accept(listener, &conn_name, handler)
+            })?;
+
+        Ok(acceptor)
+    }
+
+    fn accept<F>(&self, listener: TcpListener, name: &str, handler: F)
+    where
+        F: Fn(TcpStream) + Clone + Send + 'static,
+    {
+        for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if let Ok(handle) = stream.try_clone() {
+                lock(&self.connections).push(handle);
+            }
+
+            let handler = handler.clone();
+            let _ = thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || handler(stream));
+        }
+    }
+
+    // Connecting wakes the accept loop so that it sees the shutdown flag.
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.address);
+
+        for conn in lock(&self.connections).drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// The messages of the response to request `id`: a DATA message for each item
+// of `data` followed by END, or an ERROR message.
+fn response_messages(
+    id: u32,
+    method: &str,
+    result: Result<Vec<Value>, Value>,
+) -> Vec<FastMessage> {
+    match result {
+        Ok(data) => {
+            let mut msgs: Vec<FastMessage> = data
+                .into_iter()
+                .map(|d| {
+                    FastMessage::data(
+                        id,
+                        FastMessageData::new(method.to_string(), d),
+                    )
+                })
+                .collect();
+            msgs.push(FastMessage::end(id, method.to_string()));
+            msgs
+        }
+        Err(err) => vec![FastMessage::error(
+            id,
+            FastMessageData::new(method.to_string(), err),
+        )],
+    }
+}
+
+fn encode_messages(msgs: &[FastMessage]) -> Result<Vec<u8>, MorayError> {
+    let mut bytes = vec![];
+    for msg in msgs {
+        bytes.extend(fast::encode_message(msg)?);
+    }
+    Ok(bytes)
+}
+
+/// A moray server for tests, listening on an ephemeral port on 127.0.0.1.  The
+/// server stops, closing any open connections, when it is dropped.
+pub struct MockMoray {
+    acceptor: Arc<Acceptor>,
+    server: Arc<Server>,
+}
+
+impl MockMoray {
+    pub fn start() -> Result<MockMoray, MorayError> {
+        let server = Arc::new(Server {
+            store: Mutex::new(Store::default()),
+            faults: Mutex::new(vec![]),
+        });
+
+        let conn_server = Arc::clone(&server);
+        let acceptor = Acceptor::start("moray-mock", move |stream| {
+            serve(stream, &conn_server)
+        })?;
+
+        Ok(MockMoray { acceptor, server })
+    }
+
+    /// The address to point a client at.
+    pub fn address(&self) -> SocketAddr {
+        self.acceptor.address
+    }
+
+    /// Inject `fault` into the response to the next call to `method`, e.g.
+    /// "findObjects".  Each fault is used once, and faults injected for the
+    /// same method are used in the order they were injected.
+    pub fn inject_fault(&self, method: &str, fault: Fault) {
+        lock(&self.server.faults).push((method.to_string(), fault));
+    }
+}
+
+impl Drop for MockMoray {
+    fn drop(&mut self) {
+        self.acceptor.stop();
+    }
+}
+
+/// An in-memory `MorayApi`, for unit tests of code that takes a
+/// `dyn MorayApi`.  It has the same store as `MockMoray`, but each request is
+/// handled directly rather than over a connection.
+#[derive(Default)]
+pub struct FakeMoray {
+    store: Store,
+}
+
+impl FakeMoray {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Handle an RPC, returning the data of each message in the response.
+    fn call(
+        &mut self,
+        (method, args): (String, Value),
+    ) -> Result<Vec<Value>, MorayError> {
+        self.store
+            .dispatch(&method, &args)
+            .map_err(|e| MorayError::from_server_error(&json!(e)))
+    }
+
+    fn call_last(&mut self, rpc: (String, Value)) -> Result<Value, MorayError> {
+        let no_response =
+            MorayError::Protocol(format!("No response from {}", rpc.0));
+        self.call(rpc)?.pop().ok_or(no_response)
+    }
+}
+
+impl MorayApi for FakeMoray {
+    fn list_buckets(
+        &mut self,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc =
+            buckets::get_list_buckets_rpc("", &opts, &buckets::Methods::List)?;
+        for d in self.call(rpc)? {
+            buckets::decode_bucket(&d, |b| Ok(bucket_handler(&b)?))?;
+        }
+        Ok(())
+    }
+
+    fn get_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+        bucket_handler: &mut dyn FnMut(&Bucket) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc =
+            buckets::get_list_buckets_rpc(name, &opts, &buckets::Methods::Get)?;
+        for d in self.call(rpc)? {
+            buckets::decode_bucket(&d, |b| Ok(bucket_handler(&b)?))?;
+        }
+        Ok(())
+    }
+
+    fn create_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.call(buckets::create_bucket_rpc(name, config, &opts)?)
+            .map(|_| ())
+    }
+
+    /// See `buckets::update_bucket`.
+    fn update_bucket(
+        &mut self,
+        name: &str,
+        config: &BucketConfig,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        let mut current = None;
+        self.get_bucket(name, opts.clone(), &mut |b| {
+            current = Some(b.clone());
+            Ok(())
+        })?;
+        buckets::check_bucket_update(name, current.as_ref(), config)?;
+
+        self.call(buckets::update_bucket_rpc(name, config, &opts)?)
+            .map(|_| ())
+    }
+
+    fn delete_bucket(
+        &mut self,
+        name: &str,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.call(buckets::delete_bucket_rpc(name, &opts))
+            .map(|_| ())
+    }
+
+    fn get_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc = objects::get_find_objects_rpc(
+            bucket,
+            key,
+            opts,
+            &objects::Methods::Get,
+        );
+        for d in self.call(rpc)? {
+            objects::decode_object(bucket, &d, |o| Ok(object_handler(&o)?))?;
+        }
+        Ok(())
+    }
+
+    fn find_objects(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&MorayObject) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc = objects::get_find_objects_rpc(
+            bucket,
+            filter,
+            opts,
+            &objects::Methods::Find,
+        );
+        for d in self.call(rpc)? {
+            objects::decode_object(bucket, &d, |o| Ok(object_handler(&o)?))?;
+        }
+        Ok(())
+    }
+
+    fn put_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+        object_handler: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc = objects::put_object_rpc(bucket, key, value, opts);
+        let etag = objects::decode_put_object(&self.call_last(rpc)?)?;
+        Ok(object_handler(&etag)?)
+    }
+
+    fn delete_object(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<(), MorayError> {
+        self.call(objects::delete_object_rpc(bucket, key, opts))
+            .map(|_| ())
+    }
+
+    fn update_objects(
+        &mut self,
+        bucket: &str,
+        fields: Value,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<UpdateObjectsReturn, MorayError> {
+        let rpc = objects::update_objects_rpc(bucket, fields, filter, opts);
+        let method = rpc.0.clone();
+        fast::decode_single(&method, &self.call_last(rpc)?)
+    }
+
+    fn delete_many(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        opts: &MethodOptions,
+    ) -> Result<u64, MorayError> {
+        let rpc = objects::delete_many_rpc(bucket, filter, opts);
+        objects::decode_delete_many(&self.call_last(rpc)?)
+    }
+
+    fn reindex_objects(
+        &mut self,
+        bucket: &str,
+        count: u64,
+        opts: &MethodOptions,
+    ) -> Result<ReindexObjectsReturn, MorayError> {
+        let rpc = objects::reindex_objects_rpc(bucket, count, opts);
+        let method = rpc.0.clone();
+        fast::decode_single(&method, &self.call_last(rpc)?)
+    }
+
+    fn batch(
+        &mut self,
+        requests: &[BatchRequest],
+        opts: &MethodOptions,
+        batch_handler: &mut dyn FnMut(Vec<BatchResult>) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        let rpc = objects::batch_rpc(requests, opts)?;
+        let results = objects::decode_batch(requests, &self.call_last(rpc)?)?;
+        Ok(batch_handler(results)?)
+    }
+
+    fn sql(
+        &mut self,
+        stmt: &str,
+        vals: Vec<&str>,
+        opts: Value,
+        query_handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), MorayError> {
+        for d in self.call(meta::sql_rpc(stmt, vals, opts)?)? {
+            query_handler(&d)?;
+        }
+        Ok(())
+    }
+}
+
+fn serve(mut stream: TcpStream, server: &Server) {
+    let mut buf = vec![];
+
+    while let Ok(req) = fast::read_message(&mut stream, &mut buf) {
+        let method = req.data.m.name.clone();
+        let fault = {
+            let mut faults = lock(&server.faults);
+            faults
+                .iter()
+                .position(|(m, _)| *m == method)
+                .map(|i| faults.remove(i).1)
+        };
+
+        if let Some(Fault::Delay(delay)) = fault {
+            thread::sleep(delay);
+        }
+        let result = match &fault {
+            Some(Fault::Error { name, message }) => {
+                Err(ServerError::new(name, message.clone()))
+            }
+            _ => lock(&server.store).dispatch(&method, &req.data.d),
+        };
+        let msgs =
+            response_messages(req.id, &method, result.map_err(|e| json!(e)));
+
+        let (count, partial) = match fault {
+            Some(Fault::Disconnect { after }) => (after, false),
+            Some(Fault::Truncate { after }) => (after, true),
+            _ => (msgs.len(), false),
+        };
+
+        let count = count.min(msgs.len());
+        let mut resp = match encode_messages(&msgs[..count]) {
+            Ok(resp) => resp,
+            Err(_) => return,
+        };
+        if partial {
+            if let Some(Ok(bytes)) = msgs.get(count).map(fast::encode_message) {
+                resp.extend_from_slice(&bytes[..bytes.len() / 2]);
+            }
+        }
+        if stream.write_all(&resp).is_err() {
+            return;
+        }
+
+        if count < msgs.len() || partial {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buckets::{self, IndexDef, IndexType};
+    use crate::client::MorayClient;
+    use crate::objects::{
+        BatchDeleteOp, BatchPutOp, BatchRequest, Etag, MethodOptions,
+    };
+    use slog::{o, Discard, Logger};
+    use std::time::Instant;
+
+    fn client(mock: &MockMoray) -> MorayClient {
+        let log = Logger::root(Discard, o!());
+        MorayClient::new(mock.address(), log, None).unwrap()
+    }
+
+    fn put(
+        client: &mut MorayClient,
+        key: &str,
+        value: Value,
+        opts: &MethodOptions,
+    ) -> Result<String, MorayError> {
+        let mut etag = String::new();
+        client.put_object("b", key, value, opts, |e| {
+            etag = e.to_string();
+            Ok(())
+        })?;
+        Ok(etag)
+    }
+
+    fn keys(client: &mut MorayClient, filter: &str) -> Vec<String> {
+        client
+            .find_objects_iter("b", filter, &MethodOptions::default())
+            .unwrap()
+            .map(|o| o.unwrap().key)
+            .collect()
+    }
+
+    #[test]
+    fn buckets_test() {
+        let mock = MockMoray::start().unwrap();
+        let mut client = client(&mock);
+        let opts = buckets::MethodOptions::default();
+        let config = BucketConfig::new()
+            .with_index("n", IndexDef::new(IndexType::Number))
+            .with_version(1);
+
+        client.create_bucket("b", &config, opts.clone()).unwrap();
+        match client.create_bucket("b", &config, opts.clone()) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "BucketConflictError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut found = vec![];
+        client
+            .get_bucket("b", opts.clone(), |b| {
+                found.push(b.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].config(), config);
+
+        client
+            .update_bucket("b", &config.clone().with_version(2), opts.clone())
+            .unwrap();
+        let names: Vec<String> = client
+            .list_buckets_iter(opts.clone())
+            .unwrap()
+            .map(|b| b.unwrap().name().to_string())
+            .collect();
+        assert_eq!(names, vec!["b"]);
+
+        client.delete_bucket("b", opts.clone()).unwrap();
+        match client.delete_bucket("b", opts) {
+            Err(MorayError::BucketNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unknown_index_type_test() {
+        let mock = MockMoray::start().unwrap();
+        let mut client = client(&mock);
+        let opts = buckets::MethodOptions::default();
+        let hstore = IndexType::Other(String::from("hstore"));
+        let config = BucketConfig::new()
+            .with_index("tags", IndexDef::new(hstore.clone()))
+            .with_version(1);
+        client.create_bucket("b", &config, opts.clone()).unwrap();
+
+        let get = |client: &mut MorayClient| {
+            let mut found = None;
+            client
+                .get_bucket("b", opts.clone(), |b| {
+                    found = Some(b.clone());
+                    Ok(())
+                })
+                .unwrap();
+            found.unwrap()
+        };
+
+        // A bucket whose index type we don't know can be updated, and the
+        // index keeps its type.
+        let config = get(&mut client)
+            .config()
+            .with_index("n", IndexDef::new(IndexType::Number))
+            .with_version(2);
+        client.update_bucket("b", &config, opts.clone()).unwrap();
+
+        let bucket = get(&mut client);
+        assert_eq!(bucket.index()["tags"].index_type, hstore);
+        assert_eq!(bucket.index()["n"].index_type, IndexType::Number);
+    }
+
+    #[test]
+    fn objects_test() {
+        let mock = MockMoray::start().unwrap();
+        let mut client = client(&mock);
+        let config = BucketConfig::new()
+            .with_index("n", IndexDef::new(IndexType::Number))
+            .with_index("s", IndexDef::new(IndexType::String));
+        client
+            .create_bucket("b", &config, buckets::MethodOptions::default())
+            .unwrap();
+
+        let mut opts = MethodOptions::default();
+        let etag = put(&mut client, "k1", json!({ "n": 1 }), &opts).unwrap();
+        put(&mut client, "k2", json!({ "n": 2, "s": "x" }), &opts).unwrap();
+        put(&mut client, "k3", json!({ "n": 3 }), &opts).unwrap();
+
+        // Etags are checked on put and delete.
+        opts.etag = Etag::Nulled;
+        match put(&mut client, "k1", json!({}), &opts) {
+            Err(MorayError::EtagConflict(e)) => {
+                assert_eq!(e.context_str("actual"), Some(etag.as_str()))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        opts.etag = Etag::Specified(etag);
+        let etag = put(&mut client, "k1", json!({ "n": 4 }), &opts).unwrap();
+        match client.delete_object("b", "k1", &opts) {
+            Err(MorayError::EtagConflict(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let opts = MethodOptions::default();
+        let obj = client.get_object_as::<Value>("b", "k1", &opts).unwrap();
+        assert_eq!(obj.value, json!({ "n": 4 }));
+        assert_eq!(obj._etag, etag);
+
+        assert_eq!(keys(&mut client, "(n>=2)"), vec!["k1", "k2", "k3"]);
+        assert_eq!(keys(&mut client, "(&(n<=3)(s=*))"), vec!["k2"]);
+        match client.find_objects_iter("b", "(unindexed=1)", &opts) {
+            Ok(mut found) => match found.next() {
+                Some(Err(MorayError::InvalidQuery(_))) => (),
+                r => panic!("unexpected result {:?}", r),
+            },
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+
+        let updated = client
+            .update_objects("b", json!({ "s": "y" }), "(n<=3)", &opts)
+            .unwrap();
+        assert_eq!(updated.count, 2);
+        assert_eq!(keys(&mut client, "(s=y)"), vec!["k2", "k3"]);
+
+        // Only the index is updated, not the values themselves, and only
+        // indexed fields can be updated.
+        let obj = client.get_object_as::<Value>("b", "k2", &opts).unwrap();
+        assert_eq!(obj.value, json!({ "n": 2, "s": "x" }));
+        assert_eq!(obj._etag, updated.etag);
+        match client.update_objects("b", json!({ "t": 1 }), "(n<=3)", &opts) {
+            Err(MorayError::Server(e)) => {
+                assert_eq!(e.name, "FieldUpdateError")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut limited = MethodOptions::default();
+        limited.set_limit(1);
+        assert_eq!(client.delete_many("b", "(s=y)", &limited).unwrap(), 1);
+        assert_eq!(client.delete_many("b", "(s=y)", &opts).unwrap(), 1);
+        client.delete_object("b", "k1", &opts).unwrap();
+        match client.get_object_as::<Value>("b", "k1", &opts) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn find_limit_test() {
+        let mut store = Store::default();
+        store
+            .dispatch("createBucket", &json!(["b", {}, {}]))
+            .unwrap();
+        for i in 0..1001 {
+            let key = format!("k{}", i);
+            store
+                .dispatch("putObject", &json!(["b", key, {}, {}]))
+                .unwrap();
+        }
+
+        // Like moray, findObjects returns at most 1000 objects by default.
+        let find = |store: &mut Store, opts: Value| {
+            store
+                .dispatch("findObjects", &json!(["b", "(_id>=0)", opts]))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(find(&mut store, json!({})), 1000);
+        assert_eq!(find(&mut store, json!({ "limit": 2000 })), 1001);
+        assert_eq!(find(&mut store, json!({ "offset": 1000 })), 1);
+    }
+
+    #[test]
+    fn batch_test() {
+        let mock = MockMoray::start().unwrap();
+        let mut client = client(&mock);
+        client
+            .create_bucket(
+                "b",
+                &BucketConfig::new(),
+                buckets::MethodOptions::default(),
+            )
+            .unwrap();
+
+        let opts = MethodOptions::default();
+        let put = |key: &str| {
+            BatchRequest::Put(BatchPutOp {
+                bucket: String::from("b"),
+                options: opts.clone(),
+                key: key.to_string(),
+                value: json!({}),
+            })
+        };
+        let delete = |key: &str| {
+            BatchRequest::Delete(BatchDeleteOp {
+                bucket: String::from("b"),
+                options: opts.clone(),
+                key: key.to_string(),
+            })
+        };
+
+        // Deleting an object that doesn't exist fails the whole batch.
+        let requests = vec![put("k1"), delete("k2")];
+        match client.batch(&requests, &opts, |_| Ok(())) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(keys(&mut client, "(_id>=0)").is_empty());
+
+        let requests = vec![put("k1"), put("k2"), delete("k1")];
+        client.batch(&requests, &opts, |_| Ok(())).unwrap();
+        assert_eq!(keys(&mut client, "(_id>=0)"), vec!["k2"]);
+    }
+
+    // A client for `mock`, with objects k1, k2 and k3 in bucket "b".
+    fn populated(mock: &MockMoray) -> MorayClient {
+        let mut client = client(mock);
+        client
+            .create_bucket(
+                "b",
+                &BucketConfig::new(),
+                buckets::MethodOptions::default(),
+            )
+            .unwrap();
+        for key in &["k1", "k2", "k3"] {
+            put(&mut client, key, json!({}), &MethodOptions::default())
+                .unwrap();
+        }
+        client
+    }
+
+    #[test]
+    fn faults_test() {
+        let mock = MockMoray::start().unwrap();
+        let mut client = populated(&mock);
+        let opts = MethodOptions::default();
+
+        // An error is returned once, without touching the store.
+        mock.inject_fault(
+            "putObject",
+            Fault::error("NoDatabasePeersError", "no peers"),
+        );
+        match put(&mut client, "k4", json!({}), &opts) {
+            Err(MorayError::NoDatabasePeers(e)) => {
+                assert_eq!(e.message, "no peers")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(keys(&mut client, "(_id>=0)"), vec!["k1", "k2", "k3"]);
+        put(&mut client, "k4", json!({}), &opts).unwrap();
+
+        let delay = Duration::from_millis(100);
+        mock.inject_fault("getObject", Fault::Delay(delay));
+        let start = Instant::now();
+        client.get_object_as::<Value>("b", "k1", &opts).unwrap();
+        assert!(start.elapsed() >= delay);
+    }
+
+    #[test]
+    fn broken_stream_test() {
+        let faults =
+            vec![Fault::Disconnect { after: 2 }, Fault::Truncate { after: 2 }];
+
+        for fault in faults {
+            let mock = MockMoray::start().unwrap();
+            let mut client = populated(&mock);
+            mock.inject_fault("findObjects", fault.clone());
+
+            let found: Vec<_> = client
+                .find_objects_iter("b", "(_id>=0)", &MethodOptions::default())
+                .unwrap()
+                .collect();
+            assert_eq!(found.len(), 3, "{:?}", fault);
+            assert_eq!(found[0].as_ref().unwrap().key, "k1");
+            assert_eq!(found[1].as_ref().unwrap().key, "k2");
+            match &found[2] {
+                Err(MorayError::Io(_)) => (),
+                r => panic!("{:?}: unexpected result {:?}", fault, r),
+            }
+        }
+    }
+}
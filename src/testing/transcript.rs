@@ -0,0 +1,447 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Record Fast exchanges with a real moray and replay them later.
+//!
+//! A `Recorder` is a proxy that forwards each connection to a moray server
+//! and appends every request and its response to a transcript file.  Point a
+//! client at the recorder to capture a transcript from a staging moray:
+//!
+//! ```ignore
+//! let recorder = Recorder::start(staging, "manta.jsonl")?;
+//! let mut client = MorayClient::new(recorder.address(), log, None)?;
+//! client.find_objects_iter("manta", "(owner=abc)", &opts)?.count();
+//! ```
+//!
+//! A `Replayer` then serves the recorded responses to the same requests,
+//! without a moray:
+//!
+//! ```ignore
+//! let replayer = Replayer::start(Transcript::load("manta.jsonl")?)?;
+//! let mut client = MorayClient::new(replayer.address(), log, None)?;
+//! ```
+//!
+//! A transcript is a JSON-lines file.  The first line is a header holding the
+//! format version, `{"version":1}`, and each following line is an `Exchange`.
+//! Connections are recorded one RPC at a time, as `MorayClient` and
+//! `AsyncMorayClient` use them.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::{encode_messages, lock, response_messages, Acceptor};
+use crate::error::MorayError;
+use crate::fast;
+use rust_fast::protocol::FastMessageStatus;
+
+/// The version of the transcript format written by `Recorder`.
+pub const TRANSCRIPT_VERSION: u32 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    version: u32,
+}
+
+/// A single RPC and moray's response to it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Exchange {
+    pub method: String,
+    pub args: Value,
+    /// The data of each DATA message in the response.
+    #[serde(default)]
+    pub data: Vec<Value>,
+    /// The data of the ERROR message, if the RPC failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transcript {
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Transcript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Transcript, MorayError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: BufRead>(reader: R) -> Result<Transcript, MorayError> {
+        let invalid = |line: usize, e: serde_json::Error| {
+            MorayError::InvalidInput(format!(
+                "transcript line {}: {}",
+                line + 1,
+                e
+            ))
+        };
+        let mut lines = reader.lines();
+
+        let header: Header = match lines.next() {
+            Some(line) => {
+                serde_json::from_str(&line?).map_err(|e| invalid(0, e))?
+            }
+            None => {
+                return Err(MorayError::InvalidInput(String::from(
+                    "transcript is empty",
+                )))
+            }
+        };
+        if header.version != TRANSCRIPT_VERSION {
+            return Err(MorayError::InvalidInput(format!(
+                "unsupported transcript version {}",
+                header.version
+            )));
+        }
+
+        let mut exchanges = vec![];
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            exchanges.push(
+                serde_json::from_str(&line).map_err(|e| invalid(i + 1, e))?,
+            );
+        }
+
+        Ok(Transcript { exchanges })
+    }
+}
+
+/// A proxy that records the exchanges between its clients and a moray
+/// server.  The proxy stops when it is dropped.
+pub struct Recorder {
+    acceptor: Arc<Acceptor>,
+}
+
+impl Recorder {
+    /// Start a proxy to the moray at `upstream`, writing a new transcript to
+    /// `path`.  Each exchange is appended to the file as soon as its response
+    /// is complete.
+    pub fn start<P: AsRef<Path>>(
+        upstream: SocketAddr,
+        path: P,
+    ) -> Result<Recorder, MorayError> {
+        let mut file = File::create(path)?;
+        writeln!(
+            file,
+            "{}",
+            json!(Header {
+                version: TRANSCRIPT_VERSION
+            })
+        )?;
+
+        let file = Arc::new(Mutex::new(file));
+        let acceptor = Acceptor::start("moray-recorder", move |stream| {
+            let _ = record(stream, upstream, &file);
+        })?;
+
+        Ok(Recorder { acceptor })
+    }
+
+    /// The address to point a client at.
+    pub fn address(&self) -> SocketAddr {
+        self.acceptor.address
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.acceptor.stop();
+    }
+}
+
+fn record(
+    mut client: TcpStream,
+    upstream: SocketAddr,
+    file: &Mutex<File>,
+) -> Result<(), MorayError> {
+    let mut server = TcpStream::connect(upstream)?;
+    let mut client_buf = vec![];
+    let mut server_buf = vec![];
+
+    loop {
+        let req = fast::read_message(&mut client, &mut client_buf)?;
+        server.write_all(&fast::encode_message(&req)?)?;
+
+        let mut exchange = Exchange {
+            method: req.data.m.name,
+            args: req.data.d,
+            data: vec![],
+            error: None,
+        };
+
+        // The exchange is written out before the end of the response is
+        // forwarded, so that it's in the transcript once the client has seen
+        // the response.
+        loop {
+            let msg = fast::read_message(&mut server, &mut server_buf)?;
+            let bytes = fast::encode_message(&msg)?;
+
+            let done = match msg.status {
+                FastMessageStatus::Data => {
+                    exchange.data.push(msg.data.d);
+                    false
+                }
+                FastMessageStatus::End => true,
+                FastMessageStatus::Error => {
+                    exchange.error = Some(msg.data.d);
+                    true
+                }
+            };
+
+            if done {
+                writeln!(lock(file), "{}", json!(exchange))?;
+            }
+            client.write_all(&bytes)?;
+            if done {
+                break;
+            }
+        }
+    }
+}
+
+/// A server that answers requests with the responses recorded in a
+/// transcript.  Each request is answered with the first exchange not yet
+/// replayed that has the same method and arguments, ignoring request ids.  A
+/// request that matches nothing gets a ReplayError.  The server stops when it
+/// is dropped.
+pub struct Replayer {
+    acceptor: Arc<Acceptor>,
+    remaining: Arc<Mutex<Vec<Exchange>>>,
+}
+
+impl Replayer {
+    pub fn start(transcript: Transcript) -> Result<Replayer, MorayError> {
+        let remaining = Arc::new(Mutex::new(transcript.exchanges));
+
+        let conn_remaining = Arc::clone(&remaining);
+        let acceptor = Acceptor::start("moray-replayer", move |stream| {
+            replay(stream, &conn_remaining)
+        })?;
+
+        Ok(Replayer {
+            acceptor,
+            remaining,
+        })
+    }
+
+    /// The address to point a client at.
+    pub fn address(&self) -> SocketAddr {
+        self.acceptor.address
+    }
+
+    /// The exchanges that have not been replayed yet.
+    pub fn remaining(&self) -> Vec<Exchange> {
+        lock(&self.remaining).clone()
+    }
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        self.acceptor.stop();
+    }
+}
+
+fn replay(mut stream: TcpStream, remaining: &Mutex<Vec<Exchange>>) {
+    let mut buf = vec![];
+
+    while let Ok(req) = fast::read_message(&mut stream, &mut buf) {
+        let method = req.data.m.name;
+        let args = without_req_ids(&req.data.d);
+
+        let exchange = {
+            let mut remaining = lock(remaining);
+            remaining
+                .iter()
+                .position(|e| {
+                    e.method == method && without_req_ids(&e.args) == args
+                })
+                .map(|i| remaining.remove(i))
+        };
+
+        let result = match exchange {
+            Some(Exchange {
+                error: Some(error), ..
+            }) => Err(error),
+            Some(exchange) => Ok(exchange.data),
+            None => Err(json!({
+                "name": "ReplayError",
+                "message": format!(
+                    "no recorded {} request matches {}",
+                    method, req.data.d
+                ),
+            })),
+        };
+
+        let msgs = response_messages(req.id, &method, result);
+        match encode_messages(&msgs) {
+            Ok(resp) if stream.write_all(&resp).is_ok() => (),
+            _ => return,
+        }
+    }
+}
+
+// Request ids are random, so they are left out when matching requests.
+fn without_req_ids(value: &Value) -> Value {
+    match value {
+        Value::Array(values) => {
+            Value::Array(values.iter().map(without_req_ids).collect())
+        }
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .filter(|(name, _)| *name != "req_id")
+                .map(|(name, v)| (name.clone(), without_req_ids(v)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buckets::{self, BucketConfig};
+    use crate::client::MorayClient;
+    use crate::objects::MethodOptions;
+    use crate::testing::MockMoray;
+    use slog::{o, Discard, Logger};
+    use std::{env, fs, process};
+
+    fn client(address: SocketAddr) -> MorayClient {
+        let log = Logger::root(Discard, o!());
+        MorayClient::new(address, log, None).unwrap()
+    }
+
+    fn fixture() -> Transcript {
+        Transcript::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/transcripts/manta.jsonl"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn replay_fixture_test() {
+        let replayer = Replayer::start(fixture()).unwrap();
+        let mut client = client(replayer.address());
+
+        let mut found = vec![];
+        client
+            .get_bucket("manta", buckets::MethodOptions::default(), |b| {
+                found.push(b.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found[0].options().version(), 2);
+        assert_eq!(found[0].index().len(), 2);
+
+        let opts = MethodOptions::default();
+        let objects: Vec<_> = client
+            .find_objects_iter("manta", "(owner=abc)", &opts)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0]._count, 2);
+        assert_eq!(objects[0]._txn_snap, None);
+        assert_eq!(objects[1]._txn_snap, Some(4_812_776));
+        assert_eq!(objects[1].value["contentMD5"], Value::Null);
+
+        // getObject doesn't include a _count.
+        let obj = client
+            .get_object_as::<Value>("manta", "/abc/stor/a", &opts)
+            .unwrap();
+        assert_eq!(obj._count, 0);
+        assert_eq!(obj._id, 17);
+
+        match client.get_object_as::<Value>("manta", "/abc/stor/x", &opts) {
+            Err(MorayError::ObjectNotFound(e)) => {
+                assert_eq!(e.context_str("key"), Some("/abc/stor/x"))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A request that isn't in the transcript.
+        match client.get_object_as::<Value>("manta", "/abc/stor/y", &opts) {
+            Err(MorayError::Server(e)) => assert_eq!(e.name, "ReplayError"),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        assert!(replayer.remaining().is_empty());
+    }
+
+    #[test]
+    fn record_test() {
+        let mock = MockMoray::start().unwrap();
+        let path = env::temp_dir().join(format!(
+            "moray-transcript-{}-{}.jsonl",
+            process::id(),
+            mock.address().port()
+        ));
+
+        let recorder = Recorder::start(mock.address(), &path).unwrap();
+        let mut client = client(recorder.address());
+        client
+            .create_bucket(
+                "b",
+                &BucketConfig::new(),
+                buckets::MethodOptions::default(),
+            )
+            .unwrap();
+        let opts = MethodOptions::default();
+        client
+            .put_object("b", "k", json!({ "n": null }), &opts, |_| Ok(()))
+            .unwrap();
+        let recorded = client.get_object_as::<Value>("b", "k", &opts).unwrap();
+        assert!(client.get_object_as::<Value>("b", "x", &opts).is_err());
+        drop(recorder);
+
+        let transcript = Transcript::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let methods: Vec<&str> = transcript
+            .exchanges
+            .iter()
+            .map(|e| e.method.as_str())
+            .collect();
+        assert_eq!(
+            methods,
+            vec!["createBucket", "putObject", "getObject", "getObject"]
+        );
+        assert!(transcript.exchanges[3].error.is_some());
+
+        // The same requests get the same responses from a replayer, with new
+        // request ids.
+        let replayer = Replayer::start(transcript).unwrap();
+        let mut client = client(replayer.address());
+        let opts = MethodOptions::default();
+        let replayed = client.get_object_as::<Value>("b", "k", &opts).unwrap();
+        assert_eq!(replayed, recorded);
+        match client.get_object_as::<Value>("b", "x", &opts) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn read_invalid_test() {
+        let inputs = vec![
+            "",
+            "{\"version\":2}\n",
+            "{\"version\":1}\n{\"method\":1}\n",
+            "not json\n",
+        ];
+        for input in inputs {
+            match Transcript::read(input.as_bytes()) {
+                Err(MorayError::InvalidInput(_)) => (),
+                r => panic!("expected {:?} to be invalid, got {:?}", input, r),
+            }
+        }
+    }
+}
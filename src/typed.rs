@@ -0,0 +1,170 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use cueball::resolver::Resolver;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::buckets::{self, BucketConfig};
+use super::client::MorayClient;
+use super::error::MorayError;
+use super::objects::MethodOptions;
+
+/// A Rust type that is stored as the value of the objects in a moray bucket.
+///
+/// This is normally derived with `#[derive(MorayBucket)]` (enable the
+/// `derive` feature), which generates the bucket name and schema from the
+/// struct and its `#[moray(...)]` attributes:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, MorayBucket)]
+/// #[moray(bucket = "manta", version = 2)]
+/// struct MantaObject {
+///     #[moray(index = "string")]
+///     owner: String,
+///     #[serde(rename = "objectId")]
+///     #[moray(index = "string", unique)]
+///     object_id: String,
+///     content_length: u64,
+/// }
+/// ```
+///
+/// The provided methods are typed wrappers around the `MorayClient` methods of
+/// the same name.
+pub trait MorayBucket: Serialize + DeserializeOwned {
+    const BUCKET_NAME: &'static str;
+
+    fn bucket_config() -> BucketConfig;
+
+    fn create_bucket<R>(
+        client: &mut MorayClient<R>,
+        opts: buckets::MethodOptions,
+    ) -> Result<(), MorayError>
+    where
+        R: Resolver,
+    {
+        client.create_bucket(Self::BUCKET_NAME, &Self::bucket_config(), opts)
+    }
+
+    fn get<R>(
+        client: &mut MorayClient<R>,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<Self, MorayError>
+    where
+        R: Resolver,
+    {
+        client
+            .get_object_as(Self::BUCKET_NAME, key, opts)
+            .map(|obj| obj.value)
+    }
+
+    fn find<R, Q>(
+        client: &mut MorayClient<R>,
+        filter: Q,
+        opts: &MethodOptions,
+    ) -> Result<Vec<Self>, MorayError>
+    where
+        R: Resolver,
+        Q: Into<String>,
+    {
+        client
+            .find_objects_as(Self::BUCKET_NAME, filter, opts)?
+            .map(|obj| obj.map(|obj| obj.value))
+            .collect()
+    }
+
+    /// Put this value at `key`, returning the object's new etag.
+    fn put<R>(
+        &self,
+        client: &mut MorayClient<R>,
+        key: &str,
+        opts: &MethodOptions,
+    ) -> Result<String, MorayError>
+    where
+        R: Resolver,
+    {
+        let value = serde_json::to_value(self).map_err(|e| {
+            MorayError::InvalidInput(format!(
+                "Could not serialize value for bucket {}: {}",
+                Self::BUCKET_NAME,
+                e
+            ))
+        })?;
+        let mut etag = String::new();
+
+        client.put_object(Self::BUCKET_NAME, key, value, opts, |e| {
+            etag = e.to_string();
+            Ok(())
+        })?;
+
+        Ok(etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buckets::{IndexDef, IndexType};
+    use crate::testing::MockMoray;
+    use serde::Deserialize;
+    use slog::{o, Discard, Logger};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Fruit {
+        name: String,
+        weight: u64,
+    }
+
+    impl MorayBucket for Fruit {
+        const BUCKET_NAME: &'static str = "fruit";
+
+        fn bucket_config() -> BucketConfig {
+            BucketConfig::new()
+                .with_index("name", IndexDef::unique(IndexType::String))
+                .with_index("weight", IndexDef::new(IndexType::Number))
+        }
+    }
+
+    fn fruit(name: &str, weight: u64) -> Fruit {
+        Fruit {
+            name: name.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mock = MockMoray::start().unwrap();
+        let log = Logger::root(Discard, o!());
+        let mut client = MorayClient::new(mock.address(), log, None).unwrap();
+        Fruit::create_bucket(&mut client, buckets::MethodOptions::default())
+            .unwrap();
+
+        let opts = MethodOptions::default();
+        let etag = fruit("apple", 5).put(&mut client, "a", &opts).unwrap();
+        assert!(!etag.is_empty());
+        fruit("banana", 12).put(&mut client, "b", &opts).unwrap();
+        fruit("cherry", 1).put(&mut client, "c", &opts).unwrap();
+
+        assert_eq!(
+            Fruit::get(&mut client, "a", &opts).unwrap(),
+            fruit("apple", 5)
+        );
+        match Fruit::get(&mut client, "d", &opts) {
+            Err(MorayError::ObjectNotFound(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let found = Fruit::find(&mut client, "(weight>=5)", &opts).unwrap();
+        assert_eq!(found, vec![fruit("apple", 5), fruit("banana", 12)]);
+
+        // A changed value replaces the old one.
+        fruit("apple", 6).put(&mut client, "a", &opts).unwrap();
+        assert_eq!(
+            Fruit::get(&mut client, "a", &opts).unwrap(),
+            fruit("apple", 6)
+        );
+    }
+}